@@ -1,4 +1,13 @@
-use clap::{arg, ArgMatches, Command};
+use std::net::SocketAddr;
+
+use clap::builder::RangedU64ValueParser;
+use clap::{arg, value_parser, ArgAction, ArgMatches, Command};
+use lib::avro;
+use lib::config;
+use lib::utils::{log_format_arg, parse_duration};
+use zeou::lag::Output;
+use zeou::loadgen::Skew;
+use zeou::retry::Tier;
 
 ///
 ///
@@ -15,6 +24,7 @@ fn process_command() -> Command {
             arg!(-d --domain <DOMAIN> "Limit processing to a specific domain (can be more than one!).\nIf not set, all are being processed.\n")
                 .next_line_help(true)
                 .env("ZEOU_DOMAINS")
+                .action(ArgAction::Append)
                 .value_delimiter(',')
                 .value_parser(["articles", "backpacks", "circles", "events", "users"])
                 .default_value("articles,backpacks,circles,events,users")
@@ -28,6 +38,277 @@ fn process_command() -> Command {
             arg!(-g --"group-id" <GROUP_ID> "consumer group id")
                 .env("ZEOU_GROUP_ID")
                 .default_value("async-std"))
+        .arg(
+            arg!(-u --unhandled <POLICY> "what to do with commands no handler is registered for")
+                .env("ZEOU_UNHANDLED")
+                .value_parser(["skip", "dead-letter", "fail"])
+                .default_value("skip")
+        )
+        .arg(
+            arg!(--"on-error" <POLICY> "what to do with undecodable messages and failing handlers")
+                .env("ZEOU_ON_ERROR")
+                .value_parser(["skip", "dead-letter", "retry", "fail"])
+                .default_value("dead-letter")
+        )
+        .arg(
+            arg!(--"retry-delays" <DELAYS> "delays of the retry topics used by '--on-error retry'")
+                .env("ZEOU_RETRY_DELAYS")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .value_parser(|delay: &str| delay.parse::<Tier>())
+                .default_value("1s,30s,5m")
+        )
+        .arg(
+            arg!(--"max-retries" <COUNT> "retries of a failing message before it is moved to the failed topic")
+                .env("ZEOU_MAX_RETRIES")
+                .value_parser(value_parser!(u32))
+                .default_value("3")
+        )
+        .arg(
+            arg!(--"retry-failed-suffix" <SUFFIX> "suffix of the topic <domain>.<SUFFIX> receiving messages whose retries are exhausted")
+                .env("ZEOU_RETRY_FAILED_SUFFIX")
+                .default_value("retry.failed")
+        )
+        .arg(
+            arg!(--"worker-id" <WORKER_ID> "identifies this worker in dead-lettered records (default: $HOSTNAME)")
+                .env("ZEOU_WORKER_ID")
+        )
+        .arg(
+            arg!(--"state-dir" <DIR> "directory of the local state stores")
+                .env("ZEOU_STATE_DIR")
+                .default_value("state")
+        )
+        .arg(
+            arg!(--"changelog-replication" <FACTOR> "replication factor of created changelog topics")
+                .env("ZEOU_CHANGELOG_REPLICATION")
+                .value_parser(value_parser!(i32))
+                .default_value("1")
+        )
+        .arg(
+            arg!(--"exactly-once" "commit output records and consumer offsets in one transaction")
+                .env("ZEOU_EXACTLY_ONCE")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            arg!(--"transactional-id" <ID> "transactional id of the producer in exactly-once mode (default: <group-id>-<worker-id>)")
+                .env("ZEOU_TRANSACTIONAL_ID")
+        )
+        .arg(
+            arg!(--"metrics-addr" <ADDR> "serve /metrics, /healthz and /readyz on this address (example: '0.0.0.0:9090')")
+                .env("ZEOU_METRICS_ADDR")
+                .value_parser(value_parser!(SocketAddr))
+        )
+        .arg(
+            arg!(--"stuck-after" <DURATION> "/healthz fails if the poll loop or a handler is stuck for longer")
+                .env("ZEOU_STUCK_AFTER")
+                .value_parser(parse_duration)
+                .default_value("1m")
+        )
+        .arg(
+            arg!(--"ready-lag" <RECORDS> "/readyz fails while a consumed partition lags behind by more records")
+                .env("ZEOU_READY_LAG")
+                .value_parser(value_parser!(i64).range(0..))
+                .default_value("1000")
+        )
+        .args(avro::args())
+        .arg(
+            arg!(--"otlp-endpoint" <URL> "export a span per handled message to this OTLP/HTTP collector (example: 'http://localhost:4318')")
+                .env("ZEOU_OTLP_ENDPOINT")
+        )
+        .arg(
+            arg!(-l --"log-conf" <LOG_CONF> "configure the logging format (example: 'rdkafka=trace')")
+        )
+}
+
+/// dump a domain topic into a local JSONL file
+fn backup_command() -> Command {
+    Command::new("backup")
+        .about("backup event stream")
+        .arg_required_else_help(true)
+        .arg(
+            arg!(-d --domain <DOMAIN> "domain to backup")
+                .required(true)
+                .value_parser(["articles", "backpacks", "circles", "events", "users"])
+        )
+        .arg(
+            arg!(-o --output <FILE> "file to write the backup to (default: <DOMAIN>.jsonl)")
+        )
+        .arg(
+            arg!(--decode "store payloads decoded by the codec of the domain instead of their raw bytes, they are encoded again on restore")
+        )
+        .arg(avro::registry_arg())
+        .arg(
+            arg!(-b --brokers <BROKERS> "broker list in kafka format")
+                .env("ZEOU_BROKER")
+                .default_value("localhost:9092"),
+        )
+        .arg(
+            arg!(-l --"log-conf" <LOG_CONF> "configure the logging format (example: 'rdkafka=trace')")
+        )
+}
+
+/// replay a backup file into the domain topic
+fn restore_command() -> Command {
+    Command::new("restore")
+        .about("restore event stream to kafka")
+        .arg_required_else_help(true)
+        .arg(
+            arg!(-d --domain <DOMAIN> "domain to restore")
+                .required(true)
+                .value_parser(["articles", "backpacks", "circles", "events", "users"])
+        )
+        .arg(
+            arg!(-i --input <FILE> "backup file to restore (default: <DOMAIN>.jsonl)")
+        )
+        .arg(avro::registry_arg())
+        .arg(
+            arg!(-b --brokers <BROKERS> "broker list in kafka format")
+                .env("ZEOU_BROKER")
+                .default_value("localhost:9092"),
+        )
+        .arg(
+            arg!(--"keep-partitions" "produce records to their original partition")
+        )
+        .arg(
+            arg!(--"keep-timestamps" "produce records with their original timestamp")
+        )
+        .arg(
+            arg!(--"batch-size" <SIZE> "number of records in flight before the progress is checkpointed")
+                .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+                .default_value("1000")
+        )
+        .arg(
+            arg!(--restart "ignore the checkpoint of a previous run and restore from the start.\nBy default a restore resumes after the last checkpointed record, which restores\nrecords delivered after a failure of their batch once more.\n")
+                .next_line_help(true)
+        )
+        .arg(
+            arg!(-l --"log-conf" <LOG_CONF> "configure the logging format (example: 'rdkafka=trace')")
+        )
+}
+
+/// move dead-lettered records back to their source topic
+fn redrive_command() -> Command {
+    Command::new("redrive")
+        .about("re-drive dead-lettered records to their source topic")
+        .arg_required_else_help(true)
+        .arg(
+            arg!(-d --domain <DOMAIN> "domain whose dead-letter topic (<DOMAIN>.dlq) is re-driven")
+                .required(true)
+                .value_parser(["articles", "backpacks", "circles", "events", "users"])
+        )
+        .arg(
+            arg!(-b --brokers <BROKERS> "broker list in kafka format")
+                .env("ZEOU_BROKER")
+                .default_value("localhost:9092"),
+        )
+        .arg(
+            arg!(-g --"group-id" <GROUP_ID> "consumer group tracking which records were re-driven")
+                .default_value("zeou-redrive"))
+        .arg(
+            arg!(-l --"log-conf" <LOG_CONF> "configure the logging format (example: 'rdkafka=trace')")
+        )
+}
+
+/// produce synthetic commands to load test the worker
+fn loadgen_command() -> Command {
+    Command::new("loadgen")
+        .about("generate load by producing random commands")
+        .arg(
+            arg!(-d --domain <DOMAIN> "domains to produce commands for (can be more than one!)")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .value_parser(["articles", "backpacks", "circles", "events", "users"])
+                .default_value("events")
+        )
+        .arg(
+            arg!(-b --brokers <BROKERS> "broker list in kafka format")
+                .env("ZEOU_BROKER")
+                .default_value("localhost:9092"),
+        )
+        .arg(
+            arg!(-r --rate <RATE> "commands produced per second")
+                .value_parser(value_parser!(u32).range(1..))
+                .default_value("100")
+        )
+        .arg(
+            arg!(-k --keys <KEYS> "number of distinct keys (aggregates)")
+                .value_parser(value_parser!(u64).range(1..))
+                .default_value("1000")
+        )
+        .arg(
+            arg!(--skew <SKEW> "distribution of the keys: uniform, zipf or zipf:<EXPONENT>")
+                .value_parser(|skew: &str| skew.parse::<Skew>())
+                .default_value("uniform")
+        )
+        .arg(
+            arg!(--"payload-size" <BYTES> "pad payloads to at least this size")
+                .value_parser(value_parser!(usize))
+                .default_value("0")
+        )
+        .arg(
+            arg!(--duration <DURATION> "how long to generate load (e.g. 30s, 5m)")
+                .value_parser(parse_duration)
+                .default_value("1m")
+        )
+        .arg(
+            arg!(-l --"log-conf" <LOG_CONF> "configure the logging format (example: 'rdkafka=trace')")
+        )
+}
+
+/// print the lag of a consumer group
+fn lag_command() -> Command {
+    Command::new("lag")
+        .about("show the lag of a consumer group and when it will have caught up")
+        .arg_required_else_help(true)
+        .arg(
+            arg!(-g --"group-id" <GROUP_ID> "consumer group to inspect")
+                .env("ZEOU_GROUP_ID")
+                .required(true)
+        )
+        .arg(
+            arg!(-d --domain <DOMAIN> "domain topics to inspect (can be more than one!)")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .value_parser(["articles", "backpacks", "circles", "events", "users"])
+                .default_value("articles,backpacks,circles,events,users")
+        )
+        .arg(
+            arg!(-b --brokers <BROKERS> "broker list in kafka format")
+                .env("ZEOU_BROKER")
+                .default_value("localhost:9092"),
+        )
+        .arg(
+            arg!(-o --output <FORMAT> "table or json (a JSON object per line)")
+                .value_parser(|output: &str| output.parse::<Output>())
+                .default_value("table")
+        )
+        .arg(
+            arg!(-w --watch "print the lag every interval until interrupted")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            arg!(--interval <DURATION> "time between the offset snapshots the drain rate is estimated from, 0s skips the estimate")
+                .value_parser(parse_duration)
+                .default_value("5s")
+        )
+        .arg(
+            arg!(-l --"log-conf" <LOG_CONF> "configure the logging format (example: 'rdkafka=trace')")
+        )
+}
+
+/// validate commands offline against their JSON Schema
+fn validate_command() -> Command {
+    Command::new("validate")
+        .about("validate a JSONL file of commands or a backup against the command schemas")
+        .arg_required_else_help(true)
+        .arg(
+            arg!(-i --input <FILE> "JSONL file with a command or a backup record per line ('-' for stdin)")
+                .required(true)
+        )
+        .arg(
+            arg!(--"allow-unknown" "accept commands without a schema")
+                .action(ArgAction::SetTrue)
+        )
         .arg(
             arg!(-l --"log-conf" <LOG_CONF> "configure the logging format (example: 'rdkafka=trace')")
         )
@@ -38,19 +319,15 @@ pub fn get_matches() -> ArgMatches {
         .version(option_env!("CARGO_PKG_VERSION").unwrap_or(""))
         .subcommand_required(true)
         .arg_required_else_help(true)
+        .args(config::args())
+        .arg(log_format_arg())
         .subcommand(process_command())
-        .subcommand(
-            Command::new("restore")
-                .about("restore event stream to kafka")
-                .arg_required_else_help(true)
-                .arg(arg!(-d --domain <DOMAIN>).help("domain to restore")),
-        )
-        .subcommand(
-            Command::new("backup")
-                .about("backup event stream")
-                .arg_required_else_help(true)
-                .arg(arg!(-d --domain <DOMAIN>).help("domain to backup")),
-        )
+        .subcommand(restore_command())
+        .subcommand(backup_command())
+        .subcommand(redrive_command())
+        .subcommand(loadgen_command())
+        .subcommand(lag_command())
+        .subcommand(validate_command())
         .get_matches()
 }
 
@@ -81,5 +358,82 @@ mod tests {
                 .unwrap_or_default().map(|v| v.as_str()).collect::<Vec<_>>(),
             vec!["backpacks", "articles"]
         );
+
+        assert_eq!(
+            process_command()
+                .get_matches_from(vec!["process", "--retry-delays", "10s,1m"])
+                .get_many::<Tier>("retry-delays")
+                .unwrap_or_default().map(|tier| tier.label.as_str()).collect::<Vec<_>>(),
+            vec!["10s", "1m"]
+        );
+        assert_eq!(
+            process_command().get_matches_from(vec!["process"]).get_one::<String>("retry-failed-suffix").unwrap(),
+            "retry.failed"
+        );
+
+        let matches = process_command().get_matches_from(vec!["process", "--metrics-addr", "0.0.0.0:9090"]);
+        assert_eq!(matches.get_one::<SocketAddr>("metrics-addr").unwrap().port(), 9090);
+        assert!(process_command().try_get_matches_from(vec!["process", "--metrics-addr", "9090"]).is_err());
+        assert_eq!(*matches.get_one::<std::time::Duration>("stuck-after").unwrap(), std::time::Duration::from_secs(60));
+        assert_eq!(*matches.get_one::<i64>("ready-lag").unwrap(), 1000);
+
+        assert!(process_command().try_get_matches_from(vec!["process", "--output-format", "avro"]).is_err());
+        let matches = process_command().get_matches_from(vec!["process", "--output-format", "avro", "--schema-registry", "http://localhost:8081"]);
+        assert_eq!(*matches.get_one::<avro::PayloadFormat>("output-format").unwrap(), avro::PayloadFormat::Avro);
+    }
+
+    #[test]
+    fn test_backup_command() {
+        let matches = backup_command().get_matches_from(vec!["backup", "-d", "events"]);
+        assert_eq!(matches.get_one::<String>("domain").unwrap(), "events");
+        assert_eq!(matches.get_one::<String>("output"), None);
+        assert!(!matches.get_flag("decode"));
+
+        assert!(backup_command()
+            .try_get_matches_from(vec!["backup", "-d", "unknown"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_loadgen_command() {
+        let matches = loadgen_command().get_matches_from(vec!["loadgen", "-d", "events,users", "--skew", "zipf:1.2", "--duration", "30s"]);
+        assert_eq!(
+            matches.get_many::<String>("domain").unwrap().map(|v| v.as_str()).collect::<Vec<_>>(),
+            vec!["events", "users"]
+        );
+        assert_eq!(*matches.get_one::<Skew>("skew").unwrap(), Skew::Zipf(1.2));
+        assert_eq!(*matches.get_one::<std::time::Duration>("duration").unwrap(), std::time::Duration::from_secs(30));
+        assert_eq!(*matches.get_one::<u32>("rate").unwrap(), 100);
+
+        assert!(loadgen_command().try_get_matches_from(vec!["loadgen", "--rate", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_lag_command() {
+        let matches = lag_command().get_matches_from(vec!["lag", "-g", "events-group", "-d", "events", "-o", "json", "-w"]);
+        assert_eq!(matches.get_one::<String>("group-id").unwrap(), "events-group");
+        assert_eq!(*matches.get_one::<Output>("output").unwrap(), Output::Json);
+        assert!(matches.get_flag("watch"));
+        assert_eq!(*matches.get_one::<std::time::Duration>("interval").unwrap(), std::time::Duration::from_secs(5));
+
+        assert!(lag_command().try_get_matches_from(vec!["lag", "-g", "events-group", "-o", "csv"]).is_err());
+    }
+
+    #[test]
+    fn test_validate_command() {
+        let matches = validate_command().get_matches_from(vec!["validate", "-i", "events.jsonl"]);
+        assert_eq!(matches.get_one::<String>("input").unwrap(), "events.jsonl");
+        assert!(!matches.get_flag("allow-unknown"));
+        assert!(validate_command().try_get_matches_from(vec!["validate", "--allow-unknown"]).is_err());
+    }
+
+    #[test]
+    fn test_restore_command() {
+        let matches = restore_command().get_matches_from(vec!["restore", "-d", "users", "--keep-partitions"]);
+        assert!(matches.get_flag("keep-partitions"));
+        assert!(!matches.get_flag("keep-timestamps"));
+        assert!(!matches.get_flag("restart"));
+        assert_eq!(*matches.get_one::<usize>("batch-size").unwrap(), 1000);
+        assert!(restore_command().try_get_matches_from(vec!["restore", "-d", "users", "--batch-size", "0"]).is_err());
     }
 }
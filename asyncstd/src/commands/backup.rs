@@ -0,0 +1,102 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::process;
+
+use clap::ArgMatches;
+
+use futures::stream::StreamExt;
+
+use rdkafka::consumer::Consumer;
+
+use lib::async_std::create_consumer;
+use lib::avro::{self, AvroCodec};
+use lib::backup::BackupRecord;
+use lib::codec::Codecs;
+use lib::config::KafkaConfig;
+use log::{error, info, warn};
+
+use super::watermarks::{Watermarks, IDLE_TIMEOUT};
+
+pub async fn backup(matches: &ArgMatches, config: &KafkaConfig) {
+    let brokers = config.brokers();
+    let domain = matches.get_one::<String>("domain").unwrap();
+    let output = matches
+        .get_one::<String>("output")
+        .cloned()
+        .unwrap_or_else(|| format!("{}.jsonl", domain));
+    let decode = matches.get_flag("decode");
+    let avro = matches
+        .get_one::<String>("schema-registry")
+        .map(|location| AvroCodec::new(avro::registry(location)));
+    let mut codecs = Codecs::from_specs(config.codecs(), avro.as_ref()).unwrap_or_else(|error| {
+        error!("{}", error);
+        process::exit(1);
+    });
+    if let Some(avro) = avro {
+        codecs = codecs.with_avro(avro);
+    }
+
+    info!(
+        "Starting backup of {} on brokers: {} to {}",
+        domain, brokers, output
+    );
+
+    let consumer = create_consumer(config, "zeou-backup");
+
+    let (mut watermarks, assignment) =
+        Watermarks::fetch(&consumer, domain, |_, low| low).expect("Failed to fetch watermarks");
+
+    let mut writer = BufWriter::new(File::create(&output).expect("Failed to create backup file"));
+    let mut written = 0_u64;
+
+    if !watermarks.is_done() {
+        consumer
+            .assign(&assignment)
+            .expect("Failed to assign partitions");
+    }
+
+    let mut stream = consumer.stream();
+
+    while !watermarks.is_done() {
+        match async_std::future::timeout(IDLE_TIMEOUT, stream.next()).await {
+            Ok(Some(Ok(message))) => {
+                if !watermarks.accepts(&message) {
+                    continue;
+                }
+
+                let mut record = BackupRecord::from_message(&message);
+                if decode {
+                    // the schema is looked up off the executor, decoding the payload hits the cache
+                    let prefetched = match message.payload() {
+                        Some(payload) => codecs.prefetch_decode(domain, payload).await,
+                        None => Ok(()),
+                    };
+                    if let Err(error) =
+                        prefetched.and_then(|()| record.decode_payload(&codecs, domain))
+                    {
+                        warn!(
+                            "Keeping raw payload at offset {} of {}: {}",
+                            record.offset, domain, error
+                        );
+                    }
+                }
+                let line = serde_json::to_string(&record).expect("Failed to serialize record");
+                writeln!(writer, "{}", line).expect("Failed to write backup file");
+                written += 1;
+
+                watermarks.complete(&message);
+            }
+            Ok(Some(Err(kafka_error))) => error!("Error receiving message: {}", kafka_error),
+            Ok(None) => {
+                warn!("Consumer unexpectedly returned no messages");
+                break;
+            }
+            Err(_) => watermarks
+                .check_position(&consumer)
+                .expect("Failed to fetch consumer position"),
+        }
+    }
+
+    writer.flush().expect("Failed to write backup file");
+    info!("Wrote {} records of {} to {}", written, domain, output);
+}
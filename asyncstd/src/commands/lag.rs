@@ -0,0 +1,140 @@
+use std::process;
+use std::time::{Duration, Instant};
+
+use clap::ArgMatches;
+
+use rdkafka::consumer::{Consumer, ConsumerContext};
+use rdkafka::error::KafkaResult;
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+
+use lib::async_std::create_consumer;
+use lib::config::KafkaConfig;
+use log::{error, info, warn};
+
+use zeou::lag::{Offsets, Output, Report, Snapshot};
+
+use super::watermarks::METADATA_TIMEOUT;
+
+/// Print the lag of a consumer group on the domain topics, once or every `--interval`
+pub async fn lag(matches: &ArgMatches, config: &KafkaConfig) {
+    let group_id = matches.get_one::<String>("group-id").unwrap();
+    let domains = matches
+        .get_many::<String>("domain")
+        .unwrap_or_default()
+        .map(|v| v.as_str())
+        .collect::<Vec<_>>();
+    let output = *matches.get_one::<Output>("output").unwrap();
+    let interval = *matches.get_one::<Duration>("interval").unwrap();
+    let watch = matches.get_flag("watch");
+
+    info!(
+        "Inspecting lag of {} on {:?} on brokers: {}",
+        group_id,
+        domains,
+        config.brokers()
+    );
+
+    // the consumer only reads the committed offsets of the group, it never joins it
+    let consumer = create_consumer(config, group_id);
+    let partitions = partitions(&consumer, &domains).unwrap_or_else(|e| {
+        error!("Unable to fetch metadata: {}", e);
+        process::exit(1);
+    });
+    if partitions.count() == 0 {
+        error!("None of the topics {:?} exist", domains);
+        process::exit(1);
+    }
+
+    let take_snapshot = || {
+        let taken_at = Instant::now();
+        let snapshot = snapshot(&consumer, &partitions).unwrap_or_else(|e| {
+            error!("Unable to fetch offsets: {}", e);
+            process::exit(1);
+        });
+        (snapshot, taken_at)
+    };
+
+    let mut previous = None;
+    if !watch && interval > Duration::ZERO {
+        // the drain rate needs a second snapshot
+        previous = Some(take_snapshot());
+        async_std::task::sleep(interval).await;
+    }
+    loop {
+        let (current, taken_at) = take_snapshot();
+        let report = Report::new(
+            group_id,
+            &current,
+            previous
+                .as_ref()
+                .map(|(snapshot, previous_at)| (snapshot, taken_at - *previous_at)),
+        );
+        match output {
+            Output::Table => println!("{}", report.table()),
+            Output::Json => println!(
+                "{}",
+                serde_json::to_string(&report).expect("Failed to serialize report")
+            ),
+        }
+
+        if !watch {
+            break;
+        }
+        previous = Some((current, taken_at));
+        async_std::task::sleep(interval).await;
+    }
+}
+
+/// All partitions of the existing `topics`
+fn partitions<C, X>(consumer: &C, topics: &[&str]) -> KafkaResult<TopicPartitionList>
+where
+    C: Consumer<X>,
+    X: ConsumerContext,
+{
+    let mut partitions = TopicPartitionList::new();
+    for topic in topics {
+        let metadata = consumer.fetch_metadata(Some(topic), METADATA_TIMEOUT)?;
+        let ids = metadata
+            .topics()
+            .iter()
+            .filter(|metadata| metadata.name() == *topic)
+            .flat_map(|metadata| metadata.partitions().iter().map(|partition| partition.id()))
+            .collect::<Vec<_>>();
+        if ids.is_empty() {
+            warn!("Skipping unknown topic {}", topic);
+        }
+        for id in ids {
+            partitions.add_partition(topic, id);
+        }
+    }
+    Ok(partitions)
+}
+
+/// The committed offsets of the group and the watermarks of `partitions`
+fn snapshot<C, X>(consumer: &C, partitions: &TopicPartitionList) -> KafkaResult<Snapshot>
+where
+    C: Consumer<X>,
+    X: ConsumerContext,
+{
+    let committed = consumer.committed_offsets(partitions.clone(), METADATA_TIMEOUT)?;
+    committed
+        .elements()
+        .iter()
+        .map(|elem| {
+            let (low, high) =
+                consumer.fetch_watermarks(elem.topic(), elem.partition(), METADATA_TIMEOUT)?;
+            let committed = match elem.offset() {
+                Offset::Offset(offset) => Some(offset),
+                _ => None,
+            };
+            Ok((
+                (elem.topic().to_owned(), elem.partition()),
+                Offsets {
+                    low,
+                    high,
+                    committed,
+                },
+            ))
+        })
+        .collect()
+}
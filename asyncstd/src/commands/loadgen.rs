@@ -0,0 +1,152 @@
+use std::process;
+use std::time::{Duration, Instant};
+
+use clap::ArgMatches;
+
+use futures::future::LocalBoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+
+use rand::Rng;
+use rdkafka::producer::FutureRecord;
+
+use lib::async_std::create_producer;
+use lib::codec::Codecs;
+use lib::config::KafkaConfig;
+use lib::headers::{self, MessageHeaders};
+use log::{error, info};
+
+use zeou::loadgen::{self, KeySampler, Skew};
+
+/// Produce random, valid commands to the domain topics at a target rate and report
+/// the achieved throughput and delivery latencies.
+pub async fn loadgen(matches: &ArgMatches, config: &KafkaConfig) {
+    let domains = matches
+        .get_many::<String>("domain")
+        .unwrap_or_default()
+        .map(|v| v.as_str())
+        .collect::<Vec<_>>();
+    let rate = *matches.get_one::<u32>("rate").unwrap();
+    let keys = *matches.get_one::<u64>("keys").unwrap();
+    let skew = *matches.get_one::<Skew>("skew").unwrap();
+    let payload_size = *matches.get_one::<usize>("payload-size").unwrap();
+    let duration = *matches.get_one::<Duration>("duration").unwrap();
+
+    info!(
+        "Generating {} commands/s for {:?} on brokers: {} for {:?} ({} keys, {:?})",
+        rate,
+        domains,
+        config.brokers(),
+        duration,
+        keys,
+        skew
+    );
+
+    let codecs = Codecs::from_specs(config.codecs(), None).unwrap_or_else(|error| {
+        error!("{}", error);
+        process::exit(1);
+    });
+    let producer = create_producer(config);
+    headers::set_producer_id("loadgen");
+    let sampler = KeySampler::new(keys, skew);
+    let mut rng = rand::thread_rng();
+
+    let mut in_flight = FuturesUnordered::<LocalBoxFuture<'_, Result<Duration, String>>>::new();
+    let mut latencies = Vec::new();
+    let mut failed = 0_u64;
+    let mut sent = 0_u64;
+    let mut record = |delivery: Result<Duration, String>| match delivery {
+        Ok(latency) => latencies.push(latency),
+        Err(e) => {
+            if failed == 0 {
+                error!("Delivery failed: {}", e);
+            }
+            failed += 1;
+        }
+    };
+
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        let due = start + Duration::from_secs(sent) / rate;
+        let now = Instant::now();
+        if now < due {
+            // collect deliveries while waiting for the next command to be due
+            if in_flight.is_empty() {
+                async_std::task::sleep(due - now).await;
+            } else if let Ok(Some(delivery)) =
+                async_std::future::timeout(due - now, in_flight.next()).await
+            {
+                record(delivery);
+            }
+            continue;
+        }
+
+        let domain = domains[rng.gen_range(0..domains.len())];
+        let key = format!("key-{}", sampler.sample(&mut rng));
+        let command = loadgen::command(domain, &key, &mut rng).expect("known domain");
+        let payload = loadgen::payload(&command, payload_size);
+        let payload = match codecs.get(domain) {
+            Some(codec) => {
+                let value = serde_json::from_str(&payload).expect("generated payloads are JSON");
+                codec.encode(domain, &value).unwrap_or_else(|error| {
+                    error!("Unable to encode {}: {}", command.name(), error);
+                    process::exit(1);
+                })
+            }
+            None => payload.into_bytes(),
+        };
+        let headers = MessageHeaders::new()
+            .with_command(command.name())
+            .fill(None);
+
+        let producer = &producer;
+        in_flight.push(
+            async move {
+                let sent_at = Instant::now();
+                producer
+                    .send(
+                        FutureRecord::to(domain)
+                            .key(&key)
+                            .payload(&payload)
+                            .headers(headers),
+                        Duration::from_secs(0),
+                    )
+                    .await
+                    .map(|_| sent_at.elapsed())
+                    .map_err(|(e, _)| e.to_string())
+            }
+            .boxed_local(),
+        );
+        sent += 1;
+        // don't let deliveries pile up when the target rate can't be met
+        while let Some(Some(delivery)) = in_flight.next().now_or_never() {
+            record(delivery);
+        }
+    }
+
+    while let Some(delivery) = in_flight.next().await {
+        record(delivery);
+    }
+    let elapsed = start.elapsed();
+
+    latencies.sort();
+    let percentile = |p| {
+        loadgen::percentile(&latencies, p)
+            .map(|latency| format!("{:.1}ms", latency.as_secs_f64() * 1000.0))
+            .unwrap_or_else(|| "-".to_owned())
+    };
+    info!(
+        "Sent {} commands in {:.1}s: {:.1} delivered/s, {} failed",
+        sent,
+        elapsed.as_secs_f64(),
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        failed
+    );
+    info!(
+        "Delivery latency p50: {}, p90: {}, p99: {}, max: {}",
+        percentile(50.0),
+        percentile(90.0),
+        percentile(99.0),
+        percentile(100.0)
+    );
+}
@@ -1,3 +1,16 @@
+mod backup;
+mod lag;
+mod loadgen;
 mod process;
+mod redrive;
+mod restore;
+mod validate;
+mod watermarks;
 
+pub use backup::backup;
+pub use lag::lag;
+pub use loadgen::loadgen;
 pub use process::process;
+pub use redrive::redrive;
+pub use restore::restore;
+pub use validate::validate;
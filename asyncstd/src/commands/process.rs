@@ -1,25 +1,54 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
 use clap::ArgMatches;
 
 use futures::stream::StreamExt;
 
+use opentelemetry::context::FutureExt;
+
 use rdkafka::message::Message;
-use rdkafka::consumer::Consumer;
+use rdkafka::consumer::{CommitMode, Consumer};
+use rdkafka::producer::{FutureProducer, Producer};
+use rdkafka::error::{KafkaError, KafkaResult};
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
 
-use lib::async_std::{create_consumer, create_producer};
+use lib::admin::create_copartitioned_topics;
+use lib::async_std::{
+    abort_transaction, begin_transaction, commit_transaction, create_consumer, create_producer,
+    create_read_committed_consumer, create_transactional_producer, AsyncStdRuntime,
+};
+use lib::avro::{self, AvroCodec, PayloadFormat};
+use lib::codec::Codecs;
+use lib::config::KafkaConfig;
+use lib::context::CustomContext;
+use lib::error::ErrorKind;
+use lib::headers;
+use lib::health::{self, Health};
+use lib::log_context::LogContext;
+use lib::metrics::{metrics, Lag};
+use lib::shutdown::Shutdown;
+use lib::telemetry;
 use log::{error, info, warn};
 
-use serde::{Deserialize, Serialize};
-use zeou::events;
+use zeou::commands::decode_async;
+use zeou::Config;
+use zeou::retry::{self, Delays, RetryConfig, Tier};
+use zeou::router::{ErrorPolicy, HandlerContext, Router};
 
-/// A Command sent to the woker
-#[derive(Debug, Deserialize, Serialize)]
-struct Command<'a> {
-    /// kind of the command
-    command: &'a str,
-}
+/// upper bound for waiting on messages, paused retry partitions are resumed and
+/// shutdown requests are noticed in between
+const POLL_TIMEOUT: Duration = Duration::from_secs(1);
 
-pub async fn process(matches: &ArgMatches) {
-    let brokers = matches.get_one::<String>("brokers").unwrap();
+/// how long to wait for outstanding deliveries on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn process(matches: &ArgMatches, kafka: &KafkaConfig) {
+    let brokers = kafka.brokers();
     let domains = matches
         .get_many::<String>("domain")
         .unwrap_or_default()
@@ -27,34 +56,296 @@ pub async fn process(matches: &ArgMatches) {
         .collect::<Vec<_>>();
 
     let group_id = matches.get_one::<String>("group-id").unwrap();
+    let unhandled = matches
+        .get_one::<String>("unhandled")
+        .unwrap()
+        .parse::<ErrorPolicy>()
+        .unwrap();
+    let on_error = matches
+        .get_one::<String>("on-error")
+        .unwrap()
+        .parse::<ErrorPolicy>()
+        .unwrap();
+    let worker_id = matches
+        .get_one::<String>("worker-id")
+        .cloned()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "zeou".to_owned());
+    headers::set_producer_id(&worker_id);
+    let exactly_once = matches.get_flag("exactly-once");
+    let avro = matches
+        .get_one::<String>("schema-registry")
+        .map(|location| AvroCodec::new(avro::registry(location)));
+    let output_format = *matches.get_one::<PayloadFormat>("output-format").unwrap();
+    let mut codecs = Codecs::from_specs(kafka.codecs(), avro.as_ref()).unwrap_or_else(|error| {
+        error!("{}", error);
+        process::exit(1);
+    });
+    if let Some(avro) = &avro {
+        codecs = codecs.with_avro(avro.clone());
+        if output_format == PayloadFormat::Avro {
+            codecs = codecs.with_output(Arc::new(avro.clone()));
+        }
+    }
+    let health = Health::new(
+        *matches.get_one::<Duration>("stuck-after").unwrap(),
+        *matches.get_one::<i64>("ready-lag").unwrap(),
+    );
+    if let Some(addr) = matches.get_one::<SocketAddr>("metrics-addr") {
+        health::serve(*addr, health.clone()).expect("Failed to serve metrics");
+    }
+    let tracer_provider = matches.get_one::<String>("otlp-endpoint").map(|endpoint| {
+        info!("Exporting traces to {}", endpoint);
+        telemetry::init(endpoint, "zeou").expect("Failed to set up trace exporter")
+    });
+
+    let config = Config {
+        kafka: kafka.clone(),
+        state_dir: PathBuf::from(matches.get_one::<String>("state-dir").unwrap()),
+        replication: *matches.get_one::<i32>("changelog-replication").unwrap(),
+        exactly_once,
+        health: health.clone(),
+    };
+
+    let retry_config = RetryConfig {
+        tiers: matches
+            .get_many::<Tier>("retry-delays")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        max_attempts: *matches.get_one::<u32>("max-retries").unwrap(),
+        failed_suffix: matches.get_one::<String>("retry-failed-suffix").unwrap().clone(),
+    };
+
+    let mut router = Router::new(unhandled, on_error).with_retry(retry_config.clone());
+    zeou::register_handlers(&mut router, &config)
+        .await
+        .expect("Failed to register handlers");
 
-    info!("Starting worker on brokers: {}, domains: {:?}, group_id: {}", brokers, domains, group_id);
+    info!("Starting worker {} on brokers: {}, domains: {:?}, group_id: {}", worker_id, brokers, domains, group_id);
 
-    let producer = create_producer(&brokers);
-    let consumer = create_consumer(&brokers, &group_id);
-    consumer.subscribe(&domains).unwrap();
+    let (producer, consumer) = if exactly_once {
+        let transactional_id = matches
+            .get_one::<String>("transactional-id")
+            .cloned()
+            .unwrap_or_else(|| format!("{}-{}", group_id, worker_id));
+        info!("Exactly-once processing with transactional id {}", transactional_id);
+        (
+            create_transactional_producer(kafka, &transactional_id),
+            create_read_committed_consumer(kafka, group_id),
+        )
+    } else {
+        (create_producer(kafka), create_consumer(kafka, group_id))
+    };
 
+    let mut topics = domains.iter().map(|domain| domain.to_string()).collect::<Vec<_>>();
+    if on_error == ErrorPolicy::Retry {
+        for domain in &domains {
+            let retry_topics = retry_config.topics(domain);
+            let failed_topic = retry_config.failed_topic(domain);
+            create_copartitioned_topics(
+                kafka,
+                domain,
+                &retry_topics
+                    .iter()
+                    .chain([&failed_topic])
+                    .map(String::as_str)
+                    .collect::<Vec<_>>(),
+                config.replication,
+                &[],
+            )
+            .await
+            .expect("Failed to create retry topics");
+            topics.extend(retry_topics);
+        }
+    }
+    consumer
+        .subscribe(&topics.iter().map(String::as_str).collect::<Vec<_>>())
+        .unwrap();
+
+    health.watch(consumer.context());
+    let shutdown = Shutdown::install().expect("Failed to install signal handlers");
     let mut stream = consumer.stream();
+    let mut delays = Delays::default();
+    let mut processed = Processed::default();
+    let mut lag = Lag::new(kafka);
+    let mut failed = false;
+
+    while !shutdown.is_requested() {
+        health.beat();
+        if let Err(error) = delays.resume_due(&consumer) {
+            error!("Error resuming retry partitions: {}", error);
+        }
+        let next = match async_std::future::timeout(delays.poll_timeout(POLL_TIMEOUT), stream.next()).await {
+            Ok(next) => next,
+            Err(_) => continue,
+        };
+        match next {
+            Some(Ok(message)) => {
+                let domain = retry::domain(message.topic());
+                metrics().consumed.with_label_values(&[domain]).inc();
+                if let Some(lag) = lag.observe(&message) {
+                    health.record_lag(message.topic(), message.partition(), lag);
+                }
+                if let Some(due) = retry::due(&message) {
+                    if due > retry::now_millis() {
+                        if let Err(error) = delays.defer(&consumer, &message, due) {
+                            error!("Error delaying retry of {}: {}", message.topic(), error);
+                        }
+                        continue;
+                    }
+                }
 
-    loop {
-        match stream.next().await {
-            Some(Ok(message)) => { 
-                match message.payload_view::<str>() {
-                    Some(Ok(string)) => match serde_json::from_str::<Command>(string) {
-                        Ok(deserialized) => {
-                            match deserialized.command {
-                                "createEvent" => events::process_message(message, &consumer, &producer).await,
-                                _ => warn!("Unhandled command: {}", deserialized.command),
+                if exactly_once {
+                    if let Err(error) = begin_transaction(&producer).await {
+                        error!("Stopping worker, unable to begin transaction at offset {} of {}: {}", message.offset(), message.topic(), error);
+                        failed = true;
+                        break;
+                    }
+                }
+
+                let ctx = HandlerContext {
+                    message: &message,
+                    domain,
+                    worker_id: &worker_id,
+                    consumer: &consumer,
+                    producer: &producer,
+                    codecs: &codecs,
+                };
+                let trace = telemetry::message(&message);
+                // one context carries the trace, the log fields and the headers of the message
+                let context = LogContext::message(&message)
+                    .with("group_id", group_id.as_str())
+                    .with("worker_id", worker_id.as_str())
+                    .attach_to(&trace);
+                let dispatched = async {
+                    match decode_async(&codecs, domain, message.payload()).await {
+                        Ok(decoded) => router.dispatch(&ctx, &decoded).await,
+                        Err(error) => router.reject(&ctx, error.kind(), error.is_transient(), &error.to_string()).await,
+                    }
+                }
+                .with_context(context.clone())
+                .await;
+                telemetry::end(&trace, dispatched.as_ref().err());
+                // the worker handles one message at a time, so the context stays attached
+                // while its transaction is committed
+                let _context = context.attach();
+                match dispatched {
+                    Ok(_) if exactly_once => match commit_transaction(&producer, &consumer, &message).await {
+                        Ok(()) => {
+                            // the transaction is committed and its changelog records are authoritative,
+                            // handlers restore their state from the changelog if applying it failed
+                            if let Err(error) = router.commit_handlers() {
+                                error!("Error applying the state of offset {} of {}: {}", message.offset(), message.topic(), error);
                             }
-                        },
-                        Err(error) => error!("Error deserializing message: {}", error),
+                        }
+                        Err(error) => {
+                            metrics().commit_failures.with_label_values(&[message.topic()]).inc();
+                            error!("Error committing transaction at offset {} of {}: {}", message.offset(), message.topic(), error);
+                            match &error {
+                                KafkaError::Transaction(error) if !error.is_fatal() && error.txn_requires_abort() => {
+                                    abort(&producer, &router).await;
+                                    // reprocess the message with the next transaction
+                                    if let Err(error) = consumer.seek(message.topic(), message.partition(), Offset::Offset(message.offset()), Duration::from_secs(1)) {
+                                        error!("Stopping worker, unable to rewind to offset {} of {}: {}", message.offset(), message.topic(), error);
+                                        failed = true;
+                                        break;
+                                    }
+                                }
+                                _ => {
+                                    // a fenced producer can't continue, neither can one whose retries failed
+                                    failed = true;
+                                    break;
+                                }
+                            }
+                        }
                     },
-                    Some(Err(utf8_error)) => error!("Error reading message: {}", utf8_error),
-                    None => warn!("Warning: no message?"),
+                    Ok(_) => {
+                        if let Err(error) = consumer.commit_message(&message, CommitMode::Async) {
+                            metrics().commit_failures.with_label_values(&[message.topic()]).inc();
+                            error!("Error committing offset {}: {}", message.offset(), error);
+                        }
+                        processed.record(&message, consumer.context().generation());
+                    }
+                    Err(error) => {
+                        if exactly_once {
+                            abort(&producer, &router).await;
+                        }
+                        error!("Stopping worker at offset {} of {}: {}", message.offset(), message.topic(), error);
+                        // the offset of the failed message is not committed
+                        failed = true;
+                        break;
+                    }
                 }
-            },
+            }
             Some(Err(kafka_error)) => error!("Error receiving message: {}", kafka_error),
             None => warn!("Consumer unexpectedly returned no messages"),
         }
     }
+
+    info!("Shutting down worker {}", worker_id);
+    drop(stream);
+    producer.flush(SHUTDOWN_TIMEOUT);
+    if !exactly_once {
+        // async commits might still be in flight, make sure the final offsets are stored
+        match processed.offsets(consumer.context().generation()) {
+            Ok(offsets) if offsets.count() > 0 => {
+                if let Err(error) = consumer.commit(&offsets, CommitMode::Sync) {
+                    warn!("Unable to commit final offsets: {}", error);
+                }
+            }
+            Ok(_) => {}
+            Err(error) => warn!("Unable to collect final offsets: {}", error),
+        }
+    }
+    // leave the group right away instead of waiting for the session to time out
+    consumer.unsubscribe();
+    if let Some(tracer_provider) = tracer_provider {
+        if let Err(error) = tracer_provider.shutdown() {
+            warn!("Unable to flush traces: {}", error);
+        }
+    }
+    info!("Worker {} stopped", worker_id);
+    if failed {
+        process::exit(1);
+    }
+}
+
+/// The next offset of every partition processed since the last partition assignment
+#[derive(Default)]
+struct Processed {
+    generation: usize,
+    offsets: HashMap<(String, i32), i64>,
+}
+
+impl Processed {
+    fn record<M: Message>(&mut self, message: &M, generation: usize) {
+        if generation != self.generation {
+            // partitions might have moved to other workers
+            self.offsets.clear();
+            self.generation = generation;
+        }
+        self.offsets.insert(
+            (message.topic().to_owned(), message.partition()),
+            message.offset() + 1,
+        );
+    }
+
+    fn offsets(&self, generation: usize) -> KafkaResult<TopicPartitionList> {
+        let mut offsets = TopicPartitionList::new();
+        if generation == self.generation {
+            for ((topic, partition), offset) in &self.offsets {
+                offsets.add_partition_offset(topic, *partition, Offset::Offset(*offset))?;
+            }
+        }
+        Ok(offsets)
+    }
+}
+
+/// Abort the current transaction and drop the state changes staged by the handlers
+async fn abort(producer: &FutureProducer<CustomContext, AsyncStdRuntime>, router: &Router) {
+    if let Err(error) = abort_transaction(producer).await {
+        error!("Error aborting transaction: {}", error);
+    }
+    router.abort_handlers();
 }
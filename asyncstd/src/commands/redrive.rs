@@ -0,0 +1,134 @@
+use std::process;
+use std::time::Duration;
+
+use clap::ArgMatches;
+
+use futures::stream::StreamExt;
+
+use rdkafka::consumer::{CommitMode, Consumer};
+use rdkafka::message::{Headers, Message, OwnedHeaders};
+use rdkafka::producer::FutureRecord;
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+
+use lib::async_std::{create_consumer, create_producer};
+use lib::config::KafkaConfig;
+use log::{error, info, warn};
+use zeou::dlq;
+
+use super::watermarks::{Watermarks, IDLE_TIMEOUT, METADATA_TIMEOUT};
+
+/// Re-drive the dead-letter topic of a domain back to the domain topics of the records.
+/// Progress is committed with the given consumer group, so records are re-driven once.
+pub async fn redrive(matches: &ArgMatches, config: &KafkaConfig) {
+    let brokers = config.brokers();
+    let domain = matches.get_one::<String>("domain").unwrap();
+    let group_id = matches.get_one::<String>("group-id").unwrap();
+    let source = dlq::topic(domain);
+
+    info!(
+        "Re-driving {} on brokers: {}, group_id: {}",
+        source, brokers, group_id
+    );
+
+    let consumer = create_consumer(config, group_id);
+    let producer = create_producer(config);
+
+    let metadata = consumer
+        .fetch_metadata(Some(&source), METADATA_TIMEOUT)
+        .expect("Failed to fetch metadata");
+    let mut partitions = TopicPartitionList::new();
+    for topic in metadata
+        .topics()
+        .iter()
+        .filter(|topic| topic.name() == source)
+    {
+        for partition in topic.partitions() {
+            partitions.add_partition(&source, partition.id());
+        }
+    }
+    let committed = consumer
+        .committed_offsets(partitions, METADATA_TIMEOUT)
+        .expect("Failed to fetch committed offsets");
+
+    let (mut watermarks, assignment) = Watermarks::fetch(&consumer, &source, |partition, low| {
+        match committed
+            .find_partition(&source, partition)
+            .map(|elem| elem.offset())
+        {
+            Some(Offset::Offset(offset)) => offset,
+            _ => low,
+        }
+    })
+    .expect("Failed to fetch watermarks");
+
+    if watermarks.is_done() {
+        info!("Nothing to re-drive in {}", source);
+        return;
+    }
+    consumer
+        .assign(&assignment)
+        .expect("Failed to assign partitions");
+
+    let mut stream = consumer.stream();
+    let mut redriven = 0_u64;
+
+    while !watermarks.is_done() {
+        match async_std::future::timeout(IDLE_TIMEOUT, stream.next()).await {
+            Ok(Some(Ok(message))) => {
+                if !watermarks.accepts(&message) {
+                    continue;
+                }
+
+                let mut topic = domain.to_owned();
+                let mut headers = OwnedHeaders::new();
+                if let Some(original) = message.headers() {
+                    for (key, value) in (0..original.count()).filter_map(|idx| original.get(idx)) {
+                        if key == dlq::HEADER_DOMAIN {
+                            topic = String::from_utf8_lossy(value).into_owned();
+                        } else if !dlq::is_dlq_header(key) {
+                            headers = headers.add(key, value);
+                        }
+                    }
+                }
+
+                let mut record = FutureRecord::<[u8], [u8]>::to(&topic).headers(headers);
+                if let Some(key) = message.key() {
+                    record = record.key(key);
+                }
+                if let Some(payload) = message.payload() {
+                    record = record.payload(payload);
+                }
+
+                if let Err((error, _)) = producer.send(record, Duration::from_secs(0)).await {
+                    error!(
+                        "Unable to re-drive offset {} of {} to {}: {}",
+                        message.offset(),
+                        source,
+                        topic,
+                        error
+                    );
+                    process::exit(1);
+                }
+                consumer
+                    .commit_message(&message, CommitMode::Async)
+                    .expect("Failed to commit offset");
+                redriven += 1;
+
+                watermarks.complete(&message);
+            }
+            Ok(Some(Err(kafka_error))) => error!("Error receiving message: {}", kafka_error),
+            Ok(None) => {
+                warn!("Consumer unexpectedly returned no messages");
+                break;
+            }
+            Err(_) => watermarks
+                .check_position(&consumer)
+                .expect("Failed to fetch consumer position"),
+        }
+    }
+
+    if let Err(error) = consumer.commit_consumer_state(CommitMode::Sync) {
+        warn!("Unable to commit final offsets: {}", error);
+    }
+    info!("Re-drove {} records of {}", redriven, source);
+}
@@ -0,0 +1,236 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::process;
+use std::time::Duration;
+
+use clap::ArgMatches;
+
+use futures::stream::{FuturesOrdered, StreamExt};
+
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::FutureRecord;
+
+use lib::async_std::create_producer;
+use lib::avro::{self, AvroCodec};
+use lib::backup::BackupRecord;
+use lib::codec::Codecs;
+use lib::config::KafkaConfig;
+use log::{error, info, warn};
+
+/// A backup line decoded into the raw bytes that are sent to kafka
+struct RestoreRecord {
+    partition: i32,
+    timestamp: Option<i64>,
+    key: Option<Vec<u8>>,
+    payload: Option<Vec<u8>>,
+    headers: Option<OwnedHeaders>,
+}
+
+impl RestoreRecord {
+    async fn parse(line: &str, codecs: &Codecs, topic: &str) -> Result<Self, String> {
+        let record = serde_json::from_str::<BackupRecord>(line).map_err(|e| e.to_string())?;
+        if record.value.is_some() {
+            // the schema is looked up off the executor, encoding the value hits the cache
+            codecs
+                .prefetch_encode(topic)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(RestoreRecord {
+            partition: record.partition,
+            timestamp: record.timestamp,
+            key: record
+                .key
+                .as_ref()
+                .map(|key| key.to_bytes())
+                .transpose()
+                .map_err(|e| e.to_string())?,
+            payload: record.payload_bytes(codecs, topic)?,
+            headers: record.owned_headers().map_err(|e| e.to_string())?,
+        })
+    }
+
+    fn to_future_record<'a>(
+        &'a self,
+        topic: &'a str,
+        keep_partitions: bool,
+        keep_timestamps: bool,
+    ) -> FutureRecord<'a, Vec<u8>, Vec<u8>> {
+        let mut record = FutureRecord::to(topic);
+        if let Some(key) = &self.key {
+            record = record.key(key);
+        }
+        if let Some(payload) = &self.payload {
+            record = record.payload(payload);
+        }
+        if let Some(headers) = &self.headers {
+            record = record.headers(headers.clone());
+        }
+        if keep_partitions {
+            record = record.partition(self.partition);
+        }
+        if let (true, Some(timestamp)) = (keep_timestamps, self.timestamp) {
+            record = record.timestamp(timestamp);
+        }
+        record
+    }
+}
+
+/// number of lines of the backup file whose records were already delivered
+fn read_checkpoint(path: &str) -> usize {
+    match fs::read_to_string(path) {
+        Ok(content) => content.trim().parse().unwrap_or_else(|_| {
+            warn!("Ignoring invalid checkpoint file {}", path);
+            0
+        }),
+        Err(_) => 0,
+    }
+}
+
+fn write_checkpoint(path: &str, restored: usize) {
+    if let Err(error) = fs::write(path, restored.to_string()) {
+        error!("Unable to write checkpoint {}: {}", path, error);
+    }
+}
+
+/// Number of records delivered before the first failure, the only ones a checkpoint may cover
+fn delivered_prefix<T, E>(results: &[Result<T, E>]) -> usize {
+    results.iter().take_while(|result| result.is_ok()).count()
+}
+
+/// Replay a backup file into the domain topic. The checkpoint only covers the records
+/// delivered before the first failure, so resuming a failed restore is at-least-once.
+pub async fn restore(matches: &ArgMatches, config: &KafkaConfig) {
+    let brokers = config.brokers();
+    let domain = matches.get_one::<String>("domain").unwrap();
+    let input = matches
+        .get_one::<String>("input")
+        .cloned()
+        .unwrap_or_else(|| format!("{}.jsonl", domain));
+    let batch_size = *matches.get_one::<usize>("batch-size").unwrap();
+    let keep_partitions = matches.get_flag("keep-partitions");
+    let keep_timestamps = matches.get_flag("keep-timestamps");
+    let avro = matches
+        .get_one::<String>("schema-registry")
+        .map(|location| AvroCodec::new(avro::registry(location)));
+    let codecs = Codecs::from_specs(config.codecs(), avro.as_ref()).unwrap_or_else(|error| {
+        error!("{}", error);
+        process::exit(1);
+    });
+
+    let checkpoint = format!("{}.checkpoint", input);
+    let skip = if matches.get_flag("restart") {
+        0
+    } else {
+        read_checkpoint(&checkpoint)
+    };
+
+    let total = BufReader::new(File::open(&input).expect("Failed to open backup file"))
+        .lines()
+        .count();
+
+    info!(
+        "Restoring {} lines from {} to {} on brokers: {}",
+        total, input, domain, brokers
+    );
+    if skip > 0 {
+        info!("Resuming after {} already restored lines", skip);
+    }
+
+    let producer = create_producer(config);
+
+    let mut lines = BufReader::new(File::open(&input).expect("Failed to open backup file"))
+        .lines()
+        .skip(skip);
+    let mut restored = skip;
+    let mut batch = Vec::with_capacity(batch_size);
+
+    loop {
+        batch.clear();
+        // lines of the batch, including blank ones which are skipped
+        let mut read = 0;
+        for line in lines.by_ref().take(batch_size) {
+            read += 1;
+            let line = line.expect("Failed to read backup file");
+            if line.trim().is_empty() {
+                continue;
+            }
+            match RestoreRecord::parse(&line, &codecs, domain).await {
+                Ok(record) => batch.push((restored + read, record)),
+                Err(parse_error) => {
+                    error!(
+                        "Cannot parse line {} of {}: {}",
+                        restored + read,
+                        input,
+                        parse_error
+                    );
+                    write_checkpoint(&checkpoint, restored);
+                    process::exit(1);
+                }
+            }
+        }
+
+        if read == 0 {
+            break;
+        }
+
+        // every send of the batch is awaited before the checkpoint is written
+        let results = batch
+            .iter()
+            .map(|(_, record)| {
+                producer.send(
+                    record.to_future_record(domain, keep_partitions, keep_timestamps),
+                    Duration::from_secs(0),
+                )
+            })
+            .collect::<FuturesOrdered<_>>()
+            .collect::<Vec<_>>()
+            .await;
+
+        let delivered = delivered_prefix(&results);
+        for ((line, _), result) in batch.iter().zip(&results) {
+            if let Err((error, _)) = result {
+                error!("Unable to restore line {}: {}", line, error);
+            }
+        }
+        if let Some((line, _)) = batch.get(delivered) {
+            // records after the first failure are sent again when the restore is resumed
+            restored = line - 1;
+            write_checkpoint(&checkpoint, restored);
+            error!(
+                "Stopping after line {}, resume to restore the remaining records",
+                restored
+            );
+            process::exit(1);
+        }
+        restored += read;
+        write_checkpoint(&checkpoint, restored);
+        info!(
+            "Restored {}/{} lines ({:.1}%)",
+            restored,
+            total,
+            restored as f64 * 100.0 / total.max(1) as f64
+        );
+    }
+
+    if restored > 0 {
+        if let Err(error) = fs::remove_file(&checkpoint) {
+            warn!("Unable to remove checkpoint {}: {}", checkpoint, error);
+        }
+    }
+    info!("Restored {} lines of {} from {}", restored, domain, input);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delivered_prefix() {
+        assert_eq!(delivered_prefix::<(), ()>(&[]), 0);
+        assert_eq!(delivered_prefix::<(), ()>(&[Ok(()), Ok(())]), 2);
+        // delivered records after a failure are not covered
+        assert_eq!(delivered_prefix(&[Ok(()), Err(()), Ok(())]), 1);
+        assert_eq!(delivered_prefix(&[Err(()), Ok(())]), 0);
+    }
+}
@@ -0,0 +1,74 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::process;
+
+use clap::ArgMatches;
+
+use lib::backup::BackupRecord;
+use lib::error::ErrorKind;
+use log::{error, info};
+
+use zeou::commands::{decode, Decoded};
+
+/// The payload of a line, which is either a command or a backup record
+fn payload(line: &str) -> Result<Option<Vec<u8>>, String> {
+    match serde_json::from_str::<BackupRecord>(line) {
+        // decoded payloads are validated as JSON
+        Ok(BackupRecord {
+            value: Some(value), ..
+        }) => Ok(Some(value.to_string().into_bytes())),
+        Ok(record) => record
+            .payload
+            .map(|payload| payload.to_bytes())
+            .transpose()
+            .map_err(|e| e.to_string()),
+        Err(_) => Ok(Some(line.as_bytes().to_vec())),
+    }
+}
+
+/// Validate the commands of a JSONL file against their schema without a broker,
+/// printing a report per invalid line. Exits with 1 if any line is invalid.
+pub fn validate(matches: &ArgMatches) {
+    let input = matches.get_one::<String>("input").unwrap();
+    let allow_unknown = matches.get_flag("allow-unknown");
+
+    let reader: Box<dyn BufRead> = if input == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(input).unwrap_or_else(|e| {
+            error!("Unable to open {}: {}", input, e);
+            process::exit(1);
+        })))
+    };
+
+    let mut valid = 0;
+    let mut invalid = 0;
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line.unwrap_or_else(|e| {
+            error!("Unable to read {}: {}", input, e);
+            process::exit(1);
+        });
+        if line.trim().is_empty() {
+            continue;
+        }
+        let checked = payload(&line).and_then(|payload| {
+            decode(payload.as_deref()).map_err(|e| format!("{}: {}", e.kind(), e))
+        });
+        match checked {
+            Ok(Decoded::Unknown(name)) if !allow_unknown => {
+                println!("line {}: unknown-command: {} has no schema", idx + 1, name);
+                invalid += 1;
+            }
+            Ok(_) => valid += 1,
+            Err(report) => {
+                println!("line {}: {}", idx + 1, report);
+                invalid += 1;
+            }
+        }
+    }
+
+    info!("Validated {}: {} valid, {} invalid", input, valid, invalid);
+    if invalid > 0 {
+        process::exit(1);
+    }
+}
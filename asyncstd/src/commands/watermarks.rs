@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use rdkafka::consumer::{Consumer, ConsumerContext};
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
+use rdkafka::message::Message;
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+
+use log::info;
+
+pub const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how long to wait for new messages before checking the consumer position.
+/// Control records (e.g. transaction markers) are never delivered, so the last
+/// offset below the high watermark might never show up.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tracks reading the partitions of a topic up to the high watermarks seen at start
+pub struct Watermarks {
+    /// partition -> high watermark we have to reach
+    remaining: HashMap<i32, i64>,
+}
+
+impl Watermarks {
+    /// Fetch the watermarks of all partitions of `topic` and the assignment to read them.
+    /// `start` picks the offset to start at, given the partition and its low watermark.
+    pub fn fetch<C, X, F>(
+        consumer: &C,
+        topic: &str,
+        start: F,
+    ) -> KafkaResult<(Self, TopicPartitionList)>
+    where
+        C: Consumer<X>,
+        X: ConsumerContext,
+        F: Fn(i32, i64) -> i64,
+    {
+        let metadata = consumer.fetch_metadata(Some(topic), METADATA_TIMEOUT)?;
+        let partitions = metadata
+            .topics()
+            .iter()
+            .filter(|metadata| metadata.name() == topic)
+            .flat_map(|metadata| metadata.partitions().iter().map(|partition| partition.id()))
+            .collect::<Vec<_>>();
+        if partitions.is_empty() {
+            return Err(KafkaError::MetadataFetch(RDKafkaErrorCode::UnknownTopic));
+        }
+
+        let mut remaining = HashMap::new();
+        let mut assignment = TopicPartitionList::new();
+        for partition in partitions {
+            let (low, high) = consumer.fetch_watermarks(topic, partition, METADATA_TIMEOUT)?;
+            let offset = start(partition, low).max(low);
+            info!("Partition {}: offsets {} to {}", partition, offset, high);
+            if high > offset {
+                assignment.add_partition_offset(topic, partition, Offset::Offset(offset))?;
+                remaining.insert(partition, high);
+            }
+        }
+
+        Ok((Watermarks { remaining }, assignment))
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.remaining.is_empty()
+    }
+
+    /// Whether `message` is below the high watermark of its partition.
+    /// Call [`Watermarks::complete`] once it has been processed.
+    pub fn accepts<M: Message>(&self, message: &M) -> bool {
+        matches!(self.remaining.get(&message.partition()), Some(high) if message.offset() < *high)
+    }
+
+    /// Mark `message` as processed
+    pub fn complete<M: Message>(&mut self, message: &M) {
+        if let Some(high) = self.remaining.get(&message.partition()) {
+            if message.offset() + 1 >= *high {
+                info!("Partition {} done", message.partition());
+                self.remaining.remove(&message.partition());
+            }
+        }
+    }
+
+    /// Mark partitions as done whose consumer position reached the high watermark
+    pub fn check_position<C, X>(&mut self, consumer: &C) -> KafkaResult<()>
+    where
+        C: Consumer<X>,
+        X: ConsumerContext,
+    {
+        for elem in consumer.position()?.elements() {
+            if let (Offset::Offset(offset), Some(high)) =
+                (elem.offset(), self.remaining.get(&elem.partition()))
+            {
+                if offset >= *high {
+                    info!("Partition {} done", elem.partition());
+                    self.remaining.remove(&elem.partition());
+                }
+            }
+        }
+        Ok(())
+    }
+}
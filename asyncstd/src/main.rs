@@ -1,4 +1,9 @@
-use lib::utils::setup_logger;
+use std::process;
+
+use clap::ArgMatches;
+use lib::config::KafkaConfig;
+use lib::utils::{setup_logger, LogFormat};
+use log::error;
 
 mod cli;
 mod commands;
@@ -11,11 +16,46 @@ async fn main() {
 
     match matches.subcommand() {
         Some(("process", sub_matches)) => {
-            setup_logger(true, sub_matches.get_one::<String>("log-conf"));
-            commands::process(sub_matches).await;
+            setup_logger(true, sub_matches.get_one::<String>("log-conf"), log_format(sub_matches));
+            commands::process(sub_matches, &kafka_config(sub_matches)).await;
+        }
+        Some(("backup", sub_matches)) => {
+            setup_logger(true, sub_matches.get_one::<String>("log-conf"), log_format(sub_matches));
+            commands::backup(sub_matches, &kafka_config(sub_matches)).await;
+        }
+        Some(("restore", sub_matches)) => {
+            setup_logger(true, sub_matches.get_one::<String>("log-conf"), log_format(sub_matches));
+            commands::restore(sub_matches, &kafka_config(sub_matches)).await;
+        }
+        Some(("loadgen", sub_matches)) => {
+            setup_logger(true, sub_matches.get_one::<String>("log-conf"), log_format(sub_matches));
+            commands::loadgen(sub_matches, &kafka_config(sub_matches)).await;
+        }
+        Some(("redrive", sub_matches)) => {
+            setup_logger(true, sub_matches.get_one::<String>("log-conf"), log_format(sub_matches));
+            commands::redrive(sub_matches, &kafka_config(sub_matches)).await;
+        }
+        Some(("lag", sub_matches)) => {
+            setup_logger(true, sub_matches.get_one::<String>("log-conf"), log_format(sub_matches));
+            commands::lag(sub_matches, &kafka_config(sub_matches)).await;
+        }
+        Some(("validate", sub_matches)) => {
+            setup_logger(true, sub_matches.get_one::<String>("log-conf"), log_format(sub_matches));
+            commands::validate(sub_matches);
         }
         _ => {
             unimplemented!();
         }
     }
 }
+
+fn log_format(matches: &ArgMatches) -> LogFormat {
+    *matches.get_one::<LogFormat>("log-format").unwrap()
+}
+
+fn kafka_config(matches: &ArgMatches) -> KafkaConfig {
+    KafkaConfig::from_matches(matches).unwrap_or_else(|error| {
+        error!("{}", error);
+        process::exit(1);
+    })
+}
@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, TopicReplication};
+use rdkafka::client::DefaultClientContext;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
+
+use log::info;
+
+use crate::config::KafkaConfig;
+
+const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of partitions of `topic`
+pub fn partition_count(config: &KafkaConfig, topic: &str) -> KafkaResult<i32> {
+    let consumer: BaseConsumer = config.client().create()?;
+    let metadata = consumer.fetch_metadata(Some(topic), METADATA_TIMEOUT)?;
+    metadata
+        .topics()
+        .iter()
+        .find(|metadata| metadata.name() == topic)
+        .map(|metadata| metadata.partitions().len() as i32)
+        .filter(|partitions| *partitions > 0)
+        .ok_or(KafkaError::MetadataFetch(RDKafkaErrorCode::UnknownTopic))
+}
+
+/// Create `topics` with as many partitions as `source` unless they exist already.
+///
+/// Records keep their partition when moved between co-partitioned topics, so state
+/// keyed by partition stays valid.
+pub async fn create_copartitioned_topics(
+    config: &KafkaConfig,
+    source: &str,
+    topics: &[&str],
+    replication: i32,
+    topic_config: &[(&str, &str)],
+) -> KafkaResult<()> {
+    let partitions = partition_count(config, source)?;
+
+    let admin: AdminClient<DefaultClientContext> = config.client().create()?;
+
+    let new_topics = topics
+        .iter()
+        .map(|topic| {
+            topic_config.iter().fold(
+                NewTopic::new(topic, partitions, TopicReplication::Fixed(replication)),
+                |new_topic, (key, value)| new_topic.set(key, value),
+            )
+        })
+        .collect::<Vec<_>>();
+    let results = admin
+        .create_topics(&new_topics, &AdminOptions::new())
+        .await?;
+
+    for result in results {
+        match result {
+            Ok(topic) => info!("Created topic {} with {} partitions", topic, partitions),
+            Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {}
+            Err((_, error)) => return Err(KafkaError::AdminOp(error)),
+        }
+    }
+    Ok(())
+}
@@ -2,13 +2,18 @@ use std::future::Future;
 use std::pin::Pin;
 use std::time::Duration;
 
-use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
 use rdkafka::consumer::stream_consumer::StreamConsumer;
-use rdkafka::producer::FutureProducer;
+use rdkafka::error::KafkaResult;
+use rdkafka::message::Message;
+use rdkafka::producer::{FutureProducer, Producer};
 use rdkafka::util::AsyncRuntime;
 
+use crate::client;
+use crate::config::KafkaConfig;
 use crate::context::CustomContext;
 
+pub use crate::client::{send, TRANSACTION_TIMEOUT};
+
 pub struct AsyncStdRuntime;
 
 impl AsyncRuntime for AsyncStdRuntime {
@@ -27,31 +32,61 @@ impl AsyncRuntime for AsyncStdRuntime {
 }
 
 pub fn create_consumer(
-    brokers: &str,
+    config: &KafkaConfig,
     group_id: &str,
 ) -> StreamConsumer<CustomContext, AsyncStdRuntime> {
-    let context = CustomContext;
-    let consumer: StreamConsumer<CustomContext, AsyncStdRuntime> = ClientConfig::new()
-        .set("bootstrap.servers", brokers)
-        .set("session.timeout.ms", "6000")
-        .set("enable.auto.commit", "false")
-        .set("auto.offset.reset", "earliest")
-        .set("group.id", group_id)
-        .set_log_level(RDKafkaLogLevel::Debug)
-        .create_with_context(context)
-        .expect("Consumer creation failed");
-    consumer
+    client::create_consumer(config, group_id)
 }
 
+/// A consumer which only sees records of committed transactions
+pub fn create_read_committed_consumer(
+    config: &KafkaConfig,
+    group_id: &str,
+) -> StreamConsumer<CustomContext, AsyncStdRuntime> {
+    client::create_read_committed_consumer(config, group_id)
+}
 
 pub fn create_producer(
-    brokers: &str,
+    config: &KafkaConfig,
+) -> FutureProducer<CustomContext, AsyncStdRuntime> {
+    client::create_producer(config)
+}
+
+/// A producer with initialized transactions, see [`client::create_transactional_producer`]
+pub fn create_transactional_producer(
+    config: &KafkaConfig,
+    transactional_id: &str,
 ) -> FutureProducer<CustomContext, AsyncStdRuntime> {
-    let context = CustomContext;
-    let producer: FutureProducer<CustomContext, AsyncStdRuntime> = ClientConfig::new()
-        .set("bootstrap.servers", brokers)
-        .set("message.timeout.ms", "5000")
-        .create_with_context(context)
-        .expect("Producer creation error");
-    producer
+    client::create_transactional_producer(config, transactional_id)
+}
+
+/// Begin a transaction on the blocking thread pool, librdkafka blocks until the
+/// transaction coordinator answered
+pub async fn begin_transaction(
+    producer: &FutureProducer<CustomContext, AsyncStdRuntime>,
+) -> KafkaResult<()> {
+    let producer = producer.clone();
+    async_std::task::spawn_blocking(move || producer.begin_transaction()).await
+}
+
+/// [`client::commit_transaction`] on the blocking thread pool
+pub async fn commit_transaction<M: Message>(
+    producer: &FutureProducer<CustomContext, AsyncStdRuntime>,
+    consumer: &StreamConsumer<CustomContext, AsyncStdRuntime>,
+    message: &M,
+) -> KafkaResult<()> {
+    let (offsets, group_metadata) = client::transaction_offsets(consumer, message)?;
+    let producer = producer.clone();
+    async_std::task::spawn_blocking(move || {
+        client::commit_offsets(&producer, &offsets, &group_metadata)
+    })
+    .await
+}
+
+/// Abort the current transaction on the blocking thread pool
+pub async fn abort_transaction(
+    producer: &FutureProducer<CustomContext, AsyncStdRuntime>,
+) -> KafkaResult<()> {
+    let producer = producer.clone();
+    async_std::task::spawn_blocking(move || producer.abort_transaction(TRANSACTION_TIMEOUT)).await
 }
@@ -0,0 +1,207 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use rdkafka::message::{Headers, Message, OwnedHeaders, Timestamp};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::codec::{CodecError, Codecs};
+
+/// Raw bytes of a record's key, payload or header value.
+///
+/// Valid UTF-8 is kept as a plain string so backups stay readable,
+/// anything else is stored base64 encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Data {
+    Text(String),
+    Binary { base64: String },
+}
+
+impl Data {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Data::Text(text.to_owned()),
+            Err(_) => Data::Binary {
+                base64: STANDARD.encode(bytes),
+            },
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        match self {
+            Data::Text(text) => Ok(text.as_bytes().to_vec()),
+            Data::Binary { base64 } => STANDARD.decode(base64),
+        }
+    }
+}
+
+/// A single header of a backed up record
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BackupHeader {
+    pub key: String,
+    pub value: Data,
+}
+
+/// One line of a backup file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BackupRecord {
+    pub partition: i32,
+    pub offset: i64,
+    /// milliseconds since the unix epoch (create or log append time)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    #[serde(default)]
+    pub key: Option<Data>,
+    #[serde(default)]
+    pub payload: Option<Data>,
+    /// the payload decoded by the codec of its topic in place of `payload`,
+    /// see [`BackupRecord::decode_payload`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<BackupHeader>,
+}
+
+impl BackupRecord {
+    pub fn from_message<M: Message>(message: &M) -> Self {
+        let timestamp = match message.timestamp() {
+            Timestamp::CreateTime(millis) | Timestamp::LogAppendTime(millis) => Some(millis),
+            Timestamp::NotAvailable => None,
+        };
+
+        let headers = message
+            .headers()
+            .map(|headers| {
+                (0..headers.count())
+                    .filter_map(|idx| headers.get(idx))
+                    .map(|(key, value)| BackupHeader {
+                        key: key.to_owned(),
+                        value: Data::from_bytes(value),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        BackupRecord {
+            partition: message.partition(),
+            offset: message.offset(),
+            timestamp,
+            key: message.key().map(Data::from_bytes),
+            payload: message.payload().map(Data::from_bytes),
+            value: None,
+            headers,
+        }
+    }
+
+    /// Replace the raw payload by its decoded value, so payloads of binary
+    /// formats are readable. The value is encoded again on restore, which only
+    /// reproduces the original bytes if the format is canonical.
+    pub fn decode_payload(&mut self, codecs: &Codecs, topic: &str) -> Result<(), CodecError> {
+        if let Some(payload) = &self.payload {
+            let bytes = payload
+                .to_bytes()
+                .expect("payloads of consumed records are valid base64");
+            self.value = Some(codecs.decode(topic, &bytes)?);
+            self.payload = None;
+        }
+        Ok(())
+    }
+
+    /// The raw payload, encoding the decoded value with the codec of `topic`
+    pub fn payload_bytes(&self, codecs: &Codecs, topic: &str) -> Result<Option<Vec<u8>>, String> {
+        match (&self.value, &self.payload) {
+            (Some(value), _) => codecs
+                .encode(topic, value)
+                .map(Some)
+                .map_err(|e| e.to_string()),
+            (None, Some(payload)) => payload.to_bytes().map(Some).map_err(|e| e.to_string()),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// headers ready to be attached to a `FutureRecord`, `None` if there are none
+    pub fn owned_headers(&self) -> Result<Option<OwnedHeaders>, base64::DecodeError> {
+        if self.headers.is_empty() {
+            return Ok(None);
+        }
+
+        let mut headers = OwnedHeaders::new_with_capacity(self.headers.len());
+        for header in &self.headers {
+            headers = headers.add(&header.key, &header.value.to_bytes()?);
+        }
+        Ok(Some(headers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::codec::CborCodec;
+
+    #[test]
+    fn test_data_roundtrip() {
+        let text = Data::from_bytes(b"{\"command\":\"createEvent\"}");
+        assert_eq!(text, Data::Text("{\"command\":\"createEvent\"}".to_owned()));
+        assert_eq!(text.to_bytes().unwrap(), b"{\"command\":\"createEvent\"}");
+
+        let binary = Data::from_bytes(&[0, 159, 146, 150]);
+        assert!(matches!(binary, Data::Binary { .. }));
+        assert_eq!(binary.to_bytes().unwrap(), vec![0, 159, 146, 150]);
+    }
+
+    #[test]
+    fn test_record_serialization() {
+        let record = BackupRecord {
+            partition: 1,
+            offset: 42,
+            timestamp: Some(1665000000000),
+            key: Some(Data::Text("user-1".to_owned())),
+            payload: Some(Data::from_bytes(&[255, 0])),
+            value: None,
+            headers: vec![BackupHeader {
+                key: "trace".to_owned(),
+                value: Data::Text("abc".to_owned()),
+            }],
+        };
+
+        let line = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            line,
+            r#"{"partition":1,"offset":42,"timestamp":1665000000000,"key":"user-1","payload":{"base64":"/wA="},"headers":[{"key":"trace","value":"abc"}]}"#
+        );
+        assert_eq!(serde_json::from_str::<BackupRecord>(&line).unwrap(), record);
+    }
+
+    #[test]
+    fn test_decoded_payload() {
+        let codecs = Codecs::default().with_topic("telemetry", Arc::new(CborCodec));
+        let value = serde_json::json!({"command": "createEvent", "kind": "add", "amount": 5});
+        let bytes = codecs.encode("telemetry", &value).unwrap();
+        let mut record = BackupRecord {
+            partition: 0,
+            offset: 7,
+            timestamp: None,
+            key: None,
+            payload: Some(Data::from_bytes(&bytes)),
+            value: None,
+            headers: vec![],
+        };
+
+        record.decode_payload(&codecs, "telemetry").unwrap();
+        assert_eq!(record.payload, None);
+        assert_eq!(record.value.as_ref(), Some(&value));
+        let line = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            line,
+            r#"{"partition":0,"offset":7,"key":null,"payload":null,"value":{"amount":5,"command":"createEvent","kind":"add"}}"#
+        );
+        let record = serde_json::from_str::<BackupRecord>(&line).unwrap();
+        assert_eq!(
+            record.payload_bytes(&codecs, "telemetry").unwrap(),
+            Some(bytes)
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use std::time::{Duration, Instant};
+
+use rdkafka::consumer::stream_consumer::StreamConsumer;
+use rdkafka::consumer::{Consumer, ConsumerGroupMetadata};
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
+use rdkafka::message::Message;
+use rdkafka::message::ToBytes;
+use rdkafka::producer::future_producer::OwnedDeliveryResult;
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+use rdkafka::types::RDKafkaRespErr;
+use rdkafka::util::AsyncRuntime;
+
+use log::warn;
+
+use crate::config::KafkaConfig;
+use crate::context::CustomContext;
+use crate::headers::MessageHeaders;
+use crate::metrics;
+use crate::telemetry;
+
+pub const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how often a step of committing a transaction is tried while it fails with retriable errors
+const COMMIT_ATTEMPTS: u32 = 5;
+
+/// Runtime-generic client construction shared by all binaries, see [`crate::async_std`]
+/// and [`crate::tokio`] for the runtime specific flavours.
+pub fn create_consumer<R: AsyncRuntime>(
+    config: &KafkaConfig,
+    group_id: &str,
+) -> StreamConsumer<CustomContext, R> {
+    config.validate().expect("Invalid security settings");
+    config
+        .consumer()
+        .set("group.id", group_id)
+        .create_with_context(CustomContext::default())
+        .expect("Consumer creation failed")
+}
+
+/// A consumer which only sees records of committed transactions
+pub fn create_read_committed_consumer<R: AsyncRuntime>(
+    config: &KafkaConfig,
+    group_id: &str,
+) -> StreamConsumer<CustomContext, R> {
+    config.validate().expect("Invalid security settings");
+    config
+        .consumer()
+        .set("group.id", group_id)
+        .set("isolation.level", "read_committed")
+        .create_with_context(CustomContext::default())
+        .expect("Consumer creation failed")
+}
+
+pub fn create_producer<R: AsyncRuntime>(config: &KafkaConfig) -> FutureProducer<CustomContext, R> {
+    config.validate().expect("Invalid security settings");
+    config
+        .producer()
+        .create_with_context(CustomContext::default())
+        .expect("Producer creation error")
+}
+
+/// A producer with initialized transactions. `transactional_id` has to be stable
+/// across restarts of the same worker, so the broker can fence zombie instances.
+pub fn create_transactional_producer<R: AsyncRuntime>(
+    config: &KafkaConfig,
+    transactional_id: &str,
+) -> FutureProducer<CustomContext, R> {
+    config.validate().expect("Invalid security settings");
+    let producer: FutureProducer<CustomContext, R> = config
+        .producer()
+        .set("enable.idempotence", "true")
+        .set("transactional.id", transactional_id)
+        .create_with_context(CustomContext::default())
+        .expect("Producer creation error");
+    producer
+        .init_transactions(TRANSACTION_TIMEOUT)
+        .expect("Initializing transactions failed");
+    producer
+}
+
+/// A producer on an in-process mock cluster of librdkafka and the config of further
+/// clients of that cluster, for tests without brokers. The cluster is gone once the
+/// producer is dropped.
+pub fn create_mock_cluster<R: AsyncRuntime>() -> (FutureProducer<CustomContext, R>, KafkaConfig) {
+    let mut mock = KafkaConfig::default();
+    mock.set("test.mock.num.brokers", "1");
+    let producer = create_producer(&mock);
+    let metadata = producer
+        .client()
+        .fetch_metadata(None, TRANSACTION_TIMEOUT)
+        .expect("Failed to fetch metadata of the mock cluster");
+    let brokers = metadata
+        .brokers()
+        .iter()
+        .map(|broker| format!("{}:{}", broker.host(), broker.port()))
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut config = KafkaConfig::default();
+    config.set("bootstrap.servers", &brokers);
+    (producer, config)
+}
+
+/// Answer the next produce requests to the mock cluster of `producer`, see
+/// [`create_mock_cluster`], with `errors` in order. `RD_KAFKA_RESP_ERR_NO_ERROR`
+/// lets a request pass.
+pub fn push_produce_errors<R: AsyncRuntime>(
+    producer: &FutureProducer<CustomContext, R>,
+    errors: &[RDKafkaRespErr],
+) {
+    const PRODUCE: i16 = 0;
+    // SAFETY: the mock cluster lives as long as the producer, which is borrowed
+    unsafe {
+        let cluster = rdkafka_sys::rd_kafka_handle_mock_cluster(producer.client().native_ptr());
+        assert!(!cluster.is_null(), "producer has no mock cluster");
+        rdkafka_sys::rd_kafka_mock_push_request_errors_array(
+            cluster,
+            PRODUCE,
+            errors.len(),
+            errors.as_ptr(),
+        );
+    }
+}
+
+/// Add the offset after `message` to the current transaction and commit it, so the
+/// records produced for `message` and its consumer offset are committed atomically.
+pub fn commit_transaction<R: AsyncRuntime, M: Message>(
+    producer: &FutureProducer<CustomContext, R>,
+    consumer: &StreamConsumer<CustomContext, R>,
+    message: &M,
+) -> KafkaResult<()> {
+    let (offsets, group_metadata) = transaction_offsets(consumer, message)?;
+    commit_offsets(producer, &offsets, &group_metadata)
+}
+
+/// The offset after `message` and the group metadata of `consumer`, which a
+/// transaction commits for `message`
+pub fn transaction_offsets<R: AsyncRuntime, M: Message>(
+    consumer: &StreamConsumer<CustomContext, R>,
+    message: &M,
+) -> KafkaResult<(TopicPartitionList, ConsumerGroupMetadata)> {
+    let mut offsets = TopicPartitionList::new();
+    offsets.add_partition_offset(
+        message.topic(),
+        message.partition(),
+        Offset::Offset(message.offset() + 1),
+    )?;
+    let group_metadata = consumer
+        .group_metadata()
+        .ok_or(KafkaError::ConsumerCommit(RDKafkaErrorCode::InvalidGroupId))?;
+    Ok((offsets, group_metadata))
+}
+
+/// Add `offsets` to the current transaction and commit it. Blocks until the brokers
+/// acknowledged both steps, up to [`TRANSACTION_TIMEOUT`] per attempt.
+pub fn commit_offsets<R: AsyncRuntime>(
+    producer: &FutureProducer<CustomContext, R>,
+    offsets: &TopicPartitionList,
+    group_metadata: &ConsumerGroupMetadata,
+) -> KafkaResult<()> {
+    retry_transaction(|| {
+        producer.send_offsets_to_transaction(offsets, group_metadata, TRANSACTION_TIMEOUT)
+    })?;
+    retry_transaction(|| producer.commit_transaction(TRANSACTION_TIMEOUT))
+}
+
+/// Run `step` of a transaction again while it fails with a retriable error, at most
+/// [`COMMIT_ATTEMPTS`] times. Other errors either require aborting the transaction or
+/// are fatal for the producer, see [`rdkafka::error::RDKafkaError`].
+fn retry_transaction<F: FnMut() -> KafkaResult<()>>(mut step: F) -> KafkaResult<()> {
+    let mut attempt = 1;
+    loop {
+        match step() {
+            Err(KafkaError::Transaction(error))
+                if error.is_retriable() && attempt < COMMIT_ATTEMPTS =>
+            {
+                warn!("Retrying to commit the transaction: {}", error);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Produce `record` and wait for its delivery, counting it and its delivery latency
+/// in the [`metrics`]. The record carries the standard headers derived from the
+/// record handled by the current task, see [`MessageHeaders::outgoing`], and its
+/// trace context, see [`telemetry::inject`].
+pub async fn send<K, P, R>(
+    producer: &FutureProducer<CustomContext, R>,
+    mut record: FutureRecord<'_, K, P>,
+) -> OwnedDeliveryResult
+where
+    K: ToBytes + ?Sized,
+    P: ToBytes + ?Sized,
+    R: AsyncRuntime,
+{
+    record.headers = Some(MessageHeaders::outgoing().fill(record.headers.take()));
+    record.headers = telemetry::inject(record.headers.take(), &opentelemetry::Context::current());
+    let topic = record.topic.to_owned();
+    let started = Instant::now();
+    let result = producer.send(record, Duration::from_secs(0)).await;
+    metrics::observe_delivery(&topic, started, &result);
+    result
+}
@@ -0,0 +1,526 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::Utf8Error;
+use std::sync::Arc;
+
+use prost_reflect::prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, SerializeOptions};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::avro::{self, AvroCodec, AvroError};
+use crate::error::ErrorKind;
+
+#[derive(Debug)]
+pub enum CodecError {
+    NotUtf8(Utf8Error),
+    Json(serde_json::Error),
+    MessagePack(String),
+    Cbor(String),
+    Protobuf(String),
+    Avro(AvroError),
+    /// a codec of the config file can't be set up
+    Config(String),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::NotUtf8(error) => write!(f, "payload is not utf-8 encoded: {}", error),
+            CodecError::Json(error) => write!(f, "payload is not valid JSON: {}", error),
+            CodecError::MessagePack(error) => write!(f, "invalid MessagePack payload: {}", error),
+            CodecError::Cbor(error) => write!(f, "invalid CBOR payload: {}", error),
+            CodecError::Protobuf(error) => write!(f, "invalid Protobuf payload: {}", error),
+            CodecError::Avro(error) => write!(f, "{}", error),
+            CodecError::Config(error) => write!(f, "invalid codec: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<AvroError> for CodecError {
+    fn from(error: AvroError) -> Self {
+        CodecError::Avro(error)
+    }
+}
+
+impl ErrorKind for CodecError {
+    fn kind(&self) -> &'static str {
+        match self {
+            CodecError::NotUtf8(_) => "not-utf8",
+            CodecError::Json(_) => "invalid-json",
+            CodecError::MessagePack(_) => "invalid-msgpack",
+            CodecError::Cbor(_) => "invalid-cbor",
+            CodecError::Protobuf(_) => "invalid-protobuf",
+            CodecError::Avro(error) => error.kind(),
+            CodecError::Config(_) => "codec-config",
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        matches!(self, CodecError::Avro(error) if error.is_transient())
+    }
+}
+
+/// Converts the payloads of a topic from and to their JSON representation, which
+/// is what handlers, schemas and backups work with
+pub trait Codec: Send + Sync {
+    /// the format of the payloads, e.g. for logs
+    fn name(&self) -> &'static str;
+
+    fn decode(&self, payload: &[u8]) -> Result<Value, CodecError>;
+
+    /// The payload of `value` for a record of `topic`
+    fn encode(&self, topic: &str, value: &Value) -> Result<Vec<u8>, CodecError>;
+
+    /// The Avro codec behind this codec when it looks up schemas in a registry
+    fn avro(&self) -> Option<&AvroCodec> {
+        None
+    }
+}
+
+/// UTF-8 encoded JSON, the format of all topics without a codec of their own
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Value, CodecError> {
+        let string = std::str::from_utf8(payload).map_err(CodecError::NotUtf8)?;
+        serde_json::from_str(string).map_err(CodecError::Json)
+    }
+
+    fn encode(&self, _topic: &str, value: &Value) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(CodecError::Json)
+    }
+}
+
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Value, CodecError> {
+        rmp_serde::from_slice(payload).map_err(|e| CodecError::MessagePack(e.to_string()))
+    }
+
+    fn encode(&self, _topic: &str, value: &Value) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec_named(value).map_err(|e| CodecError::MessagePack(e.to_string()))
+    }
+}
+
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Value, CodecError> {
+        ciborium::de::from_reader(payload).map_err(|e| CodecError::Cbor(e.to_string()))
+    }
+
+    fn encode(&self, _topic: &str, value: &Value) -> Result<Vec<u8>, CodecError> {
+        let mut payload = Vec::new();
+        ciborium::ser::into_writer(value, &mut payload)
+            .map_err(|e| CodecError::Cbor(e.to_string()))?;
+        Ok(payload)
+    }
+}
+
+/// Protobuf messages of a single type, described by a file descriptor set as
+/// written by `protoc --include_imports --descriptor_set_out`. Messages are
+/// converted with the canonical JSON mapping, except that 64 bit integers stay
+/// numbers and fields with default values are kept.
+pub struct ProtobufCodec {
+    message: MessageDescriptor,
+}
+
+impl ProtobufCodec {
+    pub fn new(pool: &DescriptorPool, message: &str) -> Result<Self, CodecError> {
+        let message = pool
+            .get_message_by_name(message)
+            .ok_or_else(|| CodecError::Config(format!("unknown Protobuf message {}", message)))?;
+        Ok(ProtobufCodec { message })
+    }
+
+    /// The codec of `message` of the file descriptor set at `descriptors`
+    pub fn load(descriptors: &Path, message: &str) -> Result<Self, CodecError> {
+        let bytes = fs::read(descriptors).map_err(|e| {
+            CodecError::Config(format!("unable to read {}: {}", descriptors.display(), e))
+        })?;
+        let pool = DescriptorPool::decode(bytes.as_slice()).map_err(|e| {
+            CodecError::Config(format!(
+                "invalid descriptors {}: {}",
+                descriptors.display(),
+                e
+            ))
+        })?;
+        ProtobufCodec::new(&pool, message)
+    }
+}
+
+impl Codec for ProtobufCodec {
+    fn name(&self) -> &'static str {
+        "protobuf"
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Value, CodecError> {
+        let message = DynamicMessage::decode(self.message.clone(), payload)
+            .map_err(|e| CodecError::Protobuf(e.to_string()))?;
+        let options = SerializeOptions::new()
+            .stringify_64_bit_integers(false)
+            .skip_default_fields(false);
+        message
+            .serialize_with_options(serde_json::value::Serializer, &options)
+            .map_err(|e| CodecError::Protobuf(e.to_string()))
+    }
+
+    fn encode(&self, _topic: &str, value: &Value) -> Result<Vec<u8>, CodecError> {
+        let message = DynamicMessage::deserialize(self.message.clone(), value).map_err(|e| {
+            CodecError::Protobuf(format!(
+                "{} is no {}: {}",
+                value,
+                self.message.full_name(),
+                e
+            ))
+        })?;
+        Ok(message.encode_to_vec())
+    }
+}
+
+/// Avro in the Confluent wire format, encoded with the latest schema of the
+/// `<topic>-value` subject
+impl Codec for AvroCodec {
+    fn name(&self) -> &'static str {
+        "avro"
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Value, CodecError> {
+        Ok(AvroCodec::decode(self, payload)?)
+    }
+
+    fn encode(&self, topic: &str, value: &Value) -> Result<Vec<u8>, CodecError> {
+        Ok(AvroCodec::encode(self, &avro::value_subject(topic), value)?)
+    }
+
+    fn avro(&self) -> Option<&AvroCodec> {
+        Some(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    #[serde(alias = "messagepack")]
+    Msgpack,
+    Cbor,
+    Avro,
+    Protobuf,
+}
+
+/// The codec of a topic in the `[codecs]` table of the config file, either the
+/// name of its format or a table with the settings the format needs
+///
+/// ```toml
+/// [codecs]
+/// telemetry = "msgpack"
+/// "events-processed" = { format = "avro", registry = "http://registry:8081" }
+/// images = { format = "protobuf", descriptors = "images.binpb", message = "zeou.Image" }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "SpecEntry")]
+pub struct CodecSpec {
+    pub format: Format,
+    /// schema registry of `avro`, `--schema-registry` if not set
+    pub registry: Option<String>,
+    /// file descriptor set of `protobuf`
+    pub descriptors: Option<PathBuf>,
+    /// fully qualified name of the `protobuf` message
+    pub message: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpecEntry {
+    Format(Format),
+    Table {
+        format: Format,
+        #[serde(default)]
+        registry: Option<String>,
+        #[serde(default)]
+        descriptors: Option<PathBuf>,
+        #[serde(default)]
+        message: Option<String>,
+    },
+}
+
+impl From<SpecEntry> for CodecSpec {
+    fn from(entry: SpecEntry) -> Self {
+        match entry {
+            SpecEntry::Format(format) => CodecSpec {
+                format,
+                registry: None,
+                descriptors: None,
+                message: None,
+            },
+            SpecEntry::Table {
+                format,
+                registry,
+                descriptors,
+                message,
+            } => CodecSpec {
+                format,
+                registry,
+                descriptors,
+                message,
+            },
+        }
+    }
+}
+
+impl CodecSpec {
+    /// Set up the codec, `avro` is used by Avro codecs without a registry of their own
+    pub fn build(&self, avro: Option<&AvroCodec>) -> Result<Arc<dyn Codec>, CodecError> {
+        match self.format {
+            Format::Json => Ok(Arc::new(JsonCodec)),
+            Format::Msgpack => Ok(Arc::new(MessagePackCodec)),
+            Format::Cbor => Ok(Arc::new(CborCodec)),
+            Format::Avro => match (&self.registry, avro) {
+                (Some(location), _) => Ok(Arc::new(AvroCodec::new(avro::registry(location)))),
+                (None, Some(codec)) => Ok(Arc::new(codec.clone())),
+                (None, None) => Err(CodecError::Config(
+                    "avro requires a registry or --schema-registry".to_owned(),
+                )),
+            },
+            Format::Protobuf => match (&self.descriptors, &self.message) {
+                (Some(descriptors), Some(message)) => {
+                    Ok(Arc::new(ProtobufCodec::load(descriptors, message)?))
+                }
+                _ => Err(CodecError::Config(
+                    "protobuf requires descriptors and message".to_owned(),
+                )),
+            },
+        }
+    }
+}
+
+/// The codecs of all topics. Topics without a codec of their own are JSON, unless
+/// their records are in the Avro wire format and a schema registry is known.
+#[derive(Clone)]
+pub struct Codecs {
+    topics: HashMap<String, Arc<dyn Codec>>,
+    /// encodes the payloads of topics without a codec of their own
+    output: Arc<dyn Codec>,
+    /// decodes Avro records of topics without a codec of their own
+    avro: Option<AvroCodec>,
+}
+
+impl Default for Codecs {
+    fn default() -> Self {
+        Codecs {
+            topics: HashMap::new(),
+            output: Arc::new(JsonCodec),
+            avro: None,
+        }
+    }
+}
+
+impl Codecs {
+    /// The codecs configured per topic, see [`CodecSpec::build`]
+    pub fn from_specs(
+        specs: &BTreeMap<String, CodecSpec>,
+        avro: Option<&AvroCodec>,
+    ) -> Result<Self, CodecError> {
+        let mut codecs = Codecs::default();
+        for (topic, spec) in specs {
+            let codec = spec
+                .build(avro)
+                .map_err(|error| CodecError::Config(format!("codec of {}: {}", topic, error)))?;
+            codecs = codecs.with_topic(topic, codec);
+        }
+        Ok(codecs)
+    }
+
+    pub fn with_topic(mut self, topic: &str, codec: Arc<dyn Codec>) -> Self {
+        self.topics.insert(topic.to_owned(), codec);
+        self
+    }
+
+    /// Encode the payloads of topics without a codec of their own with `codec`
+    pub fn with_output(mut self, codec: Arc<dyn Codec>) -> Self {
+        self.output = codec;
+        self
+    }
+
+    /// Recognize Avro records on topics without a codec of their own
+    pub fn with_avro(mut self, avro: AvroCodec) -> Self {
+        self.avro = Some(avro);
+        self
+    }
+
+    /// The codec configured for `topic`
+    pub fn get(&self, topic: &str) -> Option<&dyn Codec> {
+        self.topics.get(topic).map(|codec| codec.as_ref())
+    }
+
+    pub fn decode(&self, topic: &str, payload: &[u8]) -> Result<Value, CodecError> {
+        match (self.get(topic), &self.avro) {
+            (Some(codec), _) => codec.decode(payload),
+            (None, Some(codec)) if avro::is_avro(payload) => Ok(codec.decode(payload)?),
+            (None, _) => JsonCodec.decode(payload),
+        }
+    }
+
+    pub fn encode(&self, topic: &str, value: &Value) -> Result<Vec<u8>, CodecError> {
+        self.get(topic)
+            .unwrap_or(self.output.as_ref())
+            .encode(topic, value)
+    }
+
+    /// Fetch the schema needed to decode `payload` of `topic` on the blocking thread
+    /// pool, so a following [`Codecs::decode`] doesn't block the executor
+    pub async fn prefetch_decode(&self, topic: &str, payload: &[u8]) -> Result<(), CodecError> {
+        let avro = match self.get(topic) {
+            Some(codec) => codec.avro(),
+            None => self.avro.as_ref(),
+        };
+        if let Some(avro) = avro {
+            avro.prefetch_schema(payload).await?;
+        }
+        Ok(())
+    }
+
+    /// Fetch the schema needed to encode records of `topic` on the blocking thread
+    /// pool, so a following [`Codecs::encode`] doesn't block the executor
+    pub async fn prefetch_encode(&self, topic: &str) -> Result<(), CodecError> {
+        let codec = self.get(topic).unwrap_or(self.output.as_ref());
+        if let Some(avro) = codec.avro() {
+            avro.prefetch_latest(&avro::value_subject(topic)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_reflect::prost_types::field_descriptor_proto::Type;
+    use prost_reflect::prost_types::{
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+    };
+
+    use super::*;
+
+    fn field(name: &str, number: i32, r#type: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_owned()),
+            number: Some(number),
+            r#type: Some(r#type as i32),
+            ..FieldDescriptorProto::default()
+        }
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let value = serde_json::json!({"command": "createEvent", "kind": "add", "amount": 5});
+        let codecs: [&dyn Codec; 3] = [&JsonCodec, &MessagePackCodec, &CborCodec];
+        for codec in codecs {
+            let payload = codec.encode("events", &value).unwrap();
+            assert_eq!(codec.decode(&payload).unwrap(), value, "{}", codec.name());
+        }
+        assert!(matches!(
+            JsonCodec.decode(&[0xff]),
+            Err(CodecError::NotUtf8(_))
+        ));
+        assert_eq!(
+            MessagePackCodec.decode(&[0xc1]).unwrap_err().kind(),
+            "invalid-msgpack"
+        );
+        assert_eq!(
+            CborCodec.decode(&[0xff]).unwrap_err().kind(),
+            "invalid-cbor"
+        );
+    }
+
+    #[test]
+    fn test_protobuf() {
+        let descriptors = FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("amount.proto".to_owned()),
+                package: Some("zeou".to_owned()),
+                syntax: Some("proto3".to_owned()),
+                message_type: vec![DescriptorProto {
+                    name: Some("Amount".to_owned()),
+                    field: vec![
+                        field("amount", 1, Type::Int64),
+                        field("version", 2, Type::Uint64),
+                    ],
+                    ..DescriptorProto::default()
+                }],
+                ..FileDescriptorProto::default()
+            }],
+        };
+        let path = std::env::temp_dir().join(format!("zeou-codec-{}.binpb", std::process::id()));
+        fs::write(&path, descriptors.encode_to_vec()).unwrap();
+
+        let codec = ProtobufCodec::load(&path, "zeou.Amount").unwrap();
+        let value = serde_json::json!({"amount": -42, "version": 0});
+        let payload = codec.encode("events-processed", &value).unwrap();
+        assert_eq!(codec.decode(&payload).unwrap(), value);
+        assert_eq!(
+            codec
+                .encode("events-processed", &serde_json::json!({"amount": "x"}))
+                .unwrap_err()
+                .kind(),
+            "invalid-protobuf"
+        );
+        assert!(matches!(
+            ProtobufCodec::load(&path, "zeou.Image"),
+            Err(CodecError::Config(_))
+        ));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_codecs() {
+        let specs = toml::from_str::<BTreeMap<String, CodecSpec>>(
+            r#"
+            telemetry = "msgpack"
+            images = { format = "cbor" }
+            "#,
+        )
+        .unwrap();
+        let codecs = Codecs::from_specs(&specs, None).unwrap();
+        assert_eq!(codecs.get("telemetry").unwrap().name(), "msgpack");
+        assert_eq!(codecs.get("images").unwrap().name(), "cbor");
+        assert!(codecs.get("events").is_none());
+
+        let value = serde_json::json!({"command": "createUser", "id": "u-1"});
+        let payload = codecs.encode("telemetry", &value).unwrap();
+        assert_eq!(
+            payload,
+            MessagePackCodec.encode("telemetry", &value).unwrap()
+        );
+        assert_eq!(codecs.decode("telemetry", &payload).unwrap(), value);
+        assert!(codecs.decode("events", &payload).is_err());
+        assert_eq!(
+            codecs.encode("events", &value).unwrap(),
+            serde_json::to_vec(&value).unwrap()
+        );
+
+        let specs = toml::from_str::<BTreeMap<String, CodecSpec>>(r#"events = "avro""#).unwrap();
+        assert!(matches!(
+            Codecs::from_specs(&specs, None),
+            Err(CodecError::Config(_))
+        ));
+    }
+}
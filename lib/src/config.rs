@@ -0,0 +1,631 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches};
+use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
+use serde::Deserialize;
+use toml::Value;
+
+use crate::codec::CodecSpec;
+use crate::security::{self, SecurityError};
+use crate::utils::parse_duration;
+
+/// Environment variables with these prefixes override librdkafka properties, e.g.
+/// `ZEOU_KAFKA_SECURITY_PROTOCOL=ssl` sets `security.protocol` for all clients.
+const ENV_KAFKA: &str = "ZEOU_KAFKA_";
+const ENV_CONSUMER: &str = "ZEOU_CONSUMER_";
+const ENV_PRODUCER: &str = "ZEOU_PRODUCER_";
+
+/// Syslog level (0-7) of the librdkafka logs of all clients, defaults to the level
+/// enabled for the `librdkafka` log target
+const LOG_LEVEL: &str = "log_level";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Toml(toml::de::Error),
+    UnknownProfile(String),
+    /// a property whose value is not a string, number, boolean or list of those
+    InvalidValue(String),
+    Security(SecurityError),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "unable to read config file: {}", error),
+            ConfigError::Toml(error) => write!(f, "invalid config file: {}", error),
+            ConfigError::UnknownProfile(profile) => write!(f, "unknown profile: {}", profile),
+            ConfigError::InvalidValue(key) => write!(f, "invalid value of {}", key),
+            ConfigError::Security(error) => write!(f, "invalid security settings: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(error: io::Error) -> Self {
+        ConfigError::Io(error)
+    }
+}
+
+impl From<SecurityError> for ConfigError {
+    fn from(error: SecurityError) -> Self {
+        ConfigError::Security(error)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(error: toml::de::Error) -> Self {
+        ConfigError::Toml(error)
+    }
+}
+
+/// librdkafka properties of one layer of the config file
+#[derive(Debug, Default, Deserialize)]
+struct Layer {
+    /// applied to all clients
+    #[serde(default)]
+    kafka: BTreeMap<String, Value>,
+    #[serde(default)]
+    consumer: BTreeMap<String, Value>,
+    #[serde(default)]
+    producer: BTreeMap<String, Value>,
+    /// payload codecs by topic
+    #[serde(default)]
+    codecs: BTreeMap<String, CodecSpec>,
+}
+
+/// ```toml
+/// [kafka]
+/// "bootstrap.servers" = "localhost:9092"
+///
+/// [consumer]
+/// "session.timeout.ms" = 6000
+///
+/// [codecs]
+/// telemetry = "msgpack"
+///
+/// [profile.prod.kafka]
+/// "bootstrap.servers" = ["kafka-1:9092", "kafka-2:9092"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+struct File {
+    #[serde(flatten)]
+    base: Layer,
+    #[serde(default)]
+    profile: BTreeMap<String, Layer>,
+}
+
+/// librdkafka properties of all clients, merged from (lowest to highest precedence)
+/// built-in defaults, the config file, the selected profile, `ZEOU_*` environment
+/// variables and whatever the command line sets on top. Also holds the payload
+/// codecs of the config file, see [`crate::codec::Codecs::from_specs`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct KafkaConfig {
+    common: BTreeMap<String, String>,
+    consumer: BTreeMap<String, String>,
+    producer: BTreeMap<String, String>,
+    codecs: BTreeMap<String, CodecSpec>,
+}
+
+impl fmt::Debug for KafkaConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redacted = |properties: &BTreeMap<String, String>| {
+            properties
+                .iter()
+                .map(|(key, value)| {
+                    let value = if key.contains("password") || key.contains("secret") {
+                        "********".to_owned()
+                    } else {
+                        value.clone()
+                    };
+                    (key.clone(), value)
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+        f.debug_struct("KafkaConfig")
+            .field("common", &redacted(&self.common))
+            .field("consumer", &redacted(&self.consumer))
+            .field("producer", &redacted(&self.producer))
+            .field("codecs", &self.codecs)
+            .finish()
+    }
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        let mut config = KafkaConfig {
+            common: BTreeMap::new(),
+            consumer: BTreeMap::new(),
+            producer: BTreeMap::new(),
+            codecs: BTreeMap::new(),
+        };
+        config
+            .set("bootstrap.servers", "localhost:9092")
+            .set_consumer("session.timeout.ms", "6000")
+            .set_consumer("enable.auto.commit", "false")
+            .set_consumer("auto.offset.reset", "earliest")
+            .set_producer("message.timeout.ms", "5000");
+        config
+    }
+}
+
+impl KafkaConfig {
+    /// Merge the config file, if any, and the environment into the defaults
+    pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Self, ConfigError> {
+        let mut config = match path {
+            Some(path) => KafkaConfig::from_toml(&fs::read_to_string(path)?, profile)?,
+            None => match profile {
+                Some(profile) => return Err(ConfigError::UnknownProfile(profile.to_owned())),
+                None => KafkaConfig::default(),
+            },
+        };
+        config.apply_env(std::env::vars());
+        Ok(config)
+    }
+
+    /// Merge the contents of a config file into the defaults
+    pub fn from_toml(contents: &str, profile: Option<&str>) -> Result<Self, ConfigError> {
+        let mut file = toml::from_str::<File>(contents)?;
+        let mut config = KafkaConfig::default();
+        config.apply_layer(&file.base)?;
+        if let Some(profile) = profile {
+            let layer = file
+                .profile
+                .remove(profile)
+                .ok_or_else(|| ConfigError::UnknownProfile(profile.to_owned()))?;
+            config.apply_layer(&layer)?;
+        }
+        Ok(config)
+    }
+
+    /// Properties of a layer override all lower layers, including the client
+    /// specific defaults. Within a layer client specific sections win.
+    fn apply_layer(&mut self, layer: &Layer) -> Result<(), ConfigError> {
+        let mut common = BTreeMap::new();
+        for (key, value) in &layer.kafka {
+            flatten(&mut common, key, value)?;
+        }
+        for (key, value) in &common {
+            self.set(key, value);
+        }
+        for (properties, values) in [
+            (&mut self.consumer, &layer.consumer),
+            (&mut self.producer, &layer.producer),
+        ] {
+            for (key, value) in values {
+                flatten(properties, key, value)?;
+            }
+        }
+        self.codecs.extend(layer.codecs.clone());
+        Ok(())
+    }
+
+    /// Apply `ZEOU_KAFKA_*`, `ZEOU_CONSUMER_*` and `ZEOU_PRODUCER_*` variables,
+    /// the rest of the name is lowercased and `_` replaced by `.` (except for
+    /// `ZEOU_KAFKA_LOG_LEVEL`). `ZEOU_KAFKA_*` variables override the client
+    /// specific defaults and are overridden by the client specific variables.
+    pub fn apply_env<I: IntoIterator<Item = (String, String)>>(&mut self, vars: I) {
+        let vars = vars.into_iter().collect::<Vec<_>>();
+        for (name, value) in &vars {
+            if let Some(key) = name.strip_prefix(ENV_KAFKA) {
+                self.set(&env_key(key), value);
+            }
+        }
+        for (name, value) in &vars {
+            if let Some(key) = name.strip_prefix(ENV_CONSUMER) {
+                self.set_consumer(&env_key(key), value);
+            }
+            if let Some(key) = name.strip_prefix(ENV_PRODUCER) {
+                self.set_producer(&env_key(key), value);
+            }
+        }
+    }
+
+    /// Set a property of all clients, overriding client specific values
+    pub fn set(&mut self, key: &str, value: &str) -> &mut Self {
+        self.consumer.remove(key);
+        self.producer.remove(key);
+        self.common.insert(key.to_owned(), value.to_owned());
+        self
+    }
+
+    pub fn set_consumer(&mut self, key: &str, value: &str) -> &mut Self {
+        self.consumer.insert(key.to_owned(), value.to_owned());
+        self
+    }
+
+    pub fn set_producer(&mut self, key: &str, value: &str) -> &mut Self {
+        self.producer.insert(key.to_owned(), value.to_owned());
+        self
+    }
+
+    /// A property set for all clients
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.common.get(key).map(String::as_str)
+    }
+
+    /// The codecs of the config file by topic
+    pub fn codecs(&self) -> &BTreeMap<String, CodecSpec> {
+        &self.codecs
+    }
+
+    pub fn brokers(&self) -> &str {
+        self.get("bootstrap.servers").unwrap_or_default()
+    }
+
+    /// Check the security settings of consumers and producers
+    pub fn validate(&self) -> Result<(), SecurityError> {
+        for specific in [&self.consumer, &self.producer] {
+            let mut properties = self.common.clone();
+            properties.extend(specific.clone());
+            security::validate(&properties)?;
+        }
+        Ok(())
+    }
+
+    /// Config of clients which are neither consumers nor producers, e.g. admin clients
+    pub fn client(&self) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        for (key, value) in &self.common {
+            config.set(key, value);
+        }
+        if let Some(level) = self.get(LOG_LEVEL).and_then(log_level) {
+            config.set_log_level(level);
+        }
+        config
+    }
+
+    pub fn consumer(&self) -> ClientConfig {
+        let mut config = self.client();
+        for (key, value) in &self.consumer {
+            config.set(key, value);
+        }
+        config
+    }
+
+    pub fn producer(&self) -> ClientConfig {
+        let mut config = self.client();
+        for (key, value) in &self.producer {
+            config.set(key, value);
+        }
+        config
+    }
+}
+
+/// The property named by the rest of an environment variable
+fn env_key(name: &str) -> String {
+    match name.to_lowercase().replace('_', ".") {
+        key if key == "log.level" => LOG_LEVEL.to_owned(),
+        key => key,
+    }
+}
+
+/// The librdkafka log level of a syslog level, `None` if it is out of range
+fn log_level(level: &str) -> Option<RDKafkaLogLevel> {
+    match level.trim().parse::<u8>().ok()? {
+        0 => Some(RDKafkaLogLevel::Emerg),
+        1 => Some(RDKafkaLogLevel::Alert),
+        2 => Some(RDKafkaLogLevel::Critical),
+        3 => Some(RDKafkaLogLevel::Error),
+        4 => Some(RDKafkaLogLevel::Warning),
+        5 => Some(RDKafkaLogLevel::Notice),
+        6 => Some(RDKafkaLogLevel::Info),
+        7 => Some(RDKafkaLogLevel::Debug),
+        _ => None,
+    }
+}
+
+/// Unquoted dotted keys are nested tables in TOML, join them back into property names
+fn flatten(
+    properties: &mut BTreeMap<String, String>,
+    key: &str,
+    value: &Value,
+) -> Result<(), ConfigError> {
+    let value = match value {
+        Value::Table(table) => {
+            for (sub_key, value) in table {
+                flatten(properties, &format!("{}.{}", key, sub_key), value)?;
+            }
+            return Ok(());
+        }
+        Value::Array(values) => values
+            .iter()
+            .map(|value| scalar(value).ok_or_else(|| ConfigError::InvalidValue(key.to_owned())))
+            .collect::<Result<Vec<_>, _>>()?
+            .join(","),
+        value => scalar(value).ok_or_else(|| ConfigError::InvalidValue(key.to_owned()))?,
+    };
+    properties.insert(key.to_owned(), value);
+    Ok(())
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Integer(value) => Some(value.to_string()),
+        Value::Float(value) => Some(value.to_string()),
+        Value::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// `--config`, `--profile`, `-X` and the security arguments read by [`KafkaConfig::from_matches`]
+pub fn args() -> Vec<Arg> {
+    let mut args = vec![
+        Arg::new("config")
+            .long("config")
+            .value_name("FILE")
+            .help("TOML file with librdkafka properties")
+            .env("ZEOU_CONFIG")
+            .value_parser(clap::value_parser!(PathBuf))
+            .global(true),
+        Arg::new("profile")
+            .long("profile")
+            .value_name("PROFILE")
+            .help("profile of the config file applied on top of its base sections")
+            .env("ZEOU_PROFILE")
+            .global(true),
+        Arg::new("property")
+            .short('X')
+            .value_name("KEY=VALUE")
+            .help("set a librdkafka property of all clients (can be more than one!)")
+            .action(ArgAction::Append)
+            .value_parser(parse_property)
+            .global(true),
+        Arg::new("stats-interval")
+            .long("stats-interval")
+            .value_name("DURATION")
+            .help("emit librdkafka statistics to the logs and metrics at this interval (example: '30s')")
+            .env("ZEOU_STATS_INTERVAL")
+            .value_parser(parse_duration)
+            .global(true),
+    ];
+    args.extend(security::args());
+    args
+}
+
+impl KafkaConfig {
+    /// Load the config selected by [`args`] and apply an explicitly given `brokers`
+    /// argument, the statistics interval, the security arguments and the `-X`
+    /// properties on top
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self, ConfigError> {
+        let mut config = KafkaConfig::load(
+            matches
+                .try_get_one::<PathBuf>("config")
+                .ok()
+                .flatten()
+                .map(PathBuf::as_path),
+            matches
+                .try_get_one::<String>("profile")
+                .ok()
+                .flatten()
+                .map(String::as_str),
+        )?;
+        if let Ok(Some(brokers)) = matches.try_get_one::<String>("brokers") {
+            // the CLI default must not override the config file
+            if matches.value_source("brokers") != Some(ValueSource::DefaultValue) {
+                config.set("bootstrap.servers", brokers);
+            }
+        }
+        if let Ok(Some(interval)) = matches.try_get_one::<Duration>("stats-interval") {
+            config.set("statistics.interval.ms", &interval.as_millis().to_string());
+        }
+        for (key, value) in security::properties(matches) {
+            config.set(key, &value);
+        }
+        if let Ok(Some(properties)) = matches.try_get_many::<(String, String)>("property") {
+            for (key, value) in properties {
+                config.set(key, value);
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Parse a `key=value` librdkafka property as passed to `-X`
+pub fn parse_property(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_owned(), value.trim().to_owned()))
+        }
+        _ => Err(format!("expected key=value: {}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Format;
+
+    #[test]
+    fn test_layers() {
+        let contents = r#"
+            [kafka]
+            "bootstrap.servers" = "kafka:9092"
+            statistics.interval.ms = 30000
+
+            [consumer]
+            "session.timeout.ms" = 10000
+
+            [codecs]
+            telemetry = "msgpack"
+
+            [profile.prod.kafka]
+            "bootstrap.servers" = ["kafka-1:9092", "kafka-2:9092"]
+
+            [profile.prod.producer]
+            "enable.idempotence" = true
+
+            [profile.prod.codecs]
+            telemetry = "cbor"
+            images = { format = "protobuf", descriptors = "images.binpb", message = "zeou.Image" }
+        "#;
+
+        let config = KafkaConfig::from_toml(contents, None).unwrap();
+        assert_eq!(config.brokers(), "kafka:9092");
+        assert_eq!(config.get("statistics.interval.ms"), Some("30000"));
+        assert_eq!(config.consumer().get("session.timeout.ms"), Some("10000"));
+        assert_eq!(config.consumer().get("auto.offset.reset"), Some("earliest"));
+        assert_eq!(config.producer().get("enable.idempotence"), None);
+        assert_eq!(config.codecs()["telemetry"].format, Format::Msgpack);
+
+        let mut config = KafkaConfig::from_toml(contents, Some("prod")).unwrap();
+        assert_eq!(config.brokers(), "kafka-1:9092,kafka-2:9092");
+        assert_eq!(config.producer().get("enable.idempotence"), Some("true"));
+        assert_eq!(config.codecs()["telemetry"].format, Format::Cbor);
+        assert_eq!(
+            config.codecs()["images"].message.as_deref(),
+            Some("zeou.Image")
+        );
+
+        config.apply_env(vec![
+            ("ZEOU_KAFKA_SECURITY_PROTOCOL".to_owned(), "ssl".to_owned()),
+            (
+                "ZEOU_CONSUMER_SESSION_TIMEOUT_MS".to_owned(),
+                "20000".to_owned(),
+            ),
+            ("ZEOU_BROKER".to_owned(), "ignored:9092".to_owned()),
+        ]);
+        assert_eq!(config.get("security.protocol"), Some("ssl"));
+        assert_eq!(config.consumer().get("session.timeout.ms"), Some("20000"));
+        assert_eq!(config.brokers(), "kafka-1:9092,kafka-2:9092");
+
+        config.set("session.timeout.ms", "30000");
+        assert_eq!(config.consumer().get("session.timeout.ms"), Some("30000"));
+
+        assert!(matches!(
+            KafkaConfig::from_toml(contents, Some("staging")),
+            Err(ConfigError::UnknownProfile(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_matches() {
+        let command = clap::Command::new("test").args(args()).arg(
+            Arg::new("brokers")
+                .short('b')
+                .default_value("localhost:9092"),
+        );
+
+        let config =
+            KafkaConfig::from_matches(&command.clone().get_matches_from(vec!["test"])).unwrap();
+        assert_eq!(config, KafkaConfig::default());
+
+        let matches = command.get_matches_from(vec![
+            "test",
+            "-b",
+            "kafka:9092",
+            "-X",
+            "linger.ms=5",
+            "-X",
+            "auto.offset.reset=latest",
+            "--stats-interval",
+            "30s",
+            "-X",
+            "log_level=6",
+        ]);
+        let config = KafkaConfig::from_matches(&matches).unwrap();
+        assert_eq!(config.brokers(), "kafka:9092");
+        assert_eq!(config.get("statistics.interval.ms"), Some("30000"));
+        assert_eq!(config.producer().get("linger.ms"), Some("5"));
+        assert_eq!(config.consumer().get("auto.offset.reset"), Some("latest"));
+        assert!(matches!(config.consumer().log_level, RDKafkaLogLevel::Info));
+
+        let command = clap::Command::new("test").args(args());
+        let matches = command.clone().get_matches_from(vec![
+            "test",
+            "--security-protocol",
+            "sasl_ssl",
+            "--sasl-mechanism",
+            "SCRAM-SHA-256",
+            "--sasl-username",
+            "zeou",
+            "--sasl-password",
+            "secret",
+        ]);
+        let config = KafkaConfig::from_matches(&matches).unwrap();
+        assert_eq!(config.get("security.protocol"), Some("sasl_ssl"));
+        assert_eq!(config.get("sasl.mechanism"), Some("SCRAM-SHA-256"));
+        assert!(!format!("{:?}", config).contains("secret"));
+
+        let matches = command.get_matches_from(vec![
+            "test",
+            "--security-protocol",
+            "sasl_ssl",
+            "--sasl-mechanism",
+            "PLAIN",
+        ]);
+        assert!(matches!(
+            KafkaConfig::from_matches(&matches),
+            Err(ConfigError::Security(_))
+        ));
+    }
+
+    #[test]
+    fn test_override_client_defaults() {
+        let contents = r#"
+            [kafka]
+            "auto.offset.reset" = "latest"
+            "message.timeout.ms" = 30000
+
+            [consumer]
+            "session.timeout.ms" = 10000
+        "#;
+        let mut config = KafkaConfig::from_toml(contents, None).unwrap();
+        assert_eq!(config.consumer().get("auto.offset.reset"), Some("latest"));
+        assert_eq!(config.producer().get("message.timeout.ms"), Some("30000"));
+        assert_eq!(config.consumer().get("session.timeout.ms"), Some("10000"));
+
+        config.apply_env(vec![
+            (
+                "ZEOU_CONSUMER_SESSION_TIMEOUT_MS".to_owned(),
+                "20000".to_owned(),
+            ),
+            (
+                "ZEOU_KAFKA_SESSION_TIMEOUT_MS".to_owned(),
+                "30000".to_owned(),
+            ),
+            (
+                "ZEOU_KAFKA_ENABLE_AUTO_COMMIT".to_owned(),
+                "true".to_owned(),
+            ),
+            ("ZEOU_KAFKA_LOG_LEVEL".to_owned(), "3".to_owned()),
+        ]);
+        assert_eq!(config.consumer().get("enable.auto.commit"), Some("true"));
+        // client specific variables win over ZEOU_KAFKA_*
+        assert_eq!(config.consumer().get("session.timeout.ms"), Some("20000"));
+        assert_eq!(config.get("log_level"), Some("3"));
+        assert!(matches!(config.client().log_level, RDKafkaLogLevel::Error));
+
+        let mut config = KafkaConfig::default();
+        config.apply_env(vec![(
+            "ZEOU_KAFKA_AUTO_OFFSET_RESET".to_owned(),
+            "latest".to_owned(),
+        )]);
+        assert_eq!(config.consumer().get("auto.offset.reset"), Some("latest"));
+        assert!(log_level("8").is_none());
+    }
+
+    #[test]
+    fn test_parse_property() {
+        assert_eq!(
+            parse_property("linger.ms=5"),
+            Ok(("linger.ms".to_owned(), "5".to_owned()))
+        );
+        assert_eq!(
+            parse_property("sasl.password=a=b"),
+            Ok(("sasl.password".to_owned(), "a=b".to_owned()))
+        );
+        assert!(parse_property("linger.ms").is_err());
+        assert!(parse_property("=5").is_err());
+    }
+}
@@ -1,16 +1,54 @@
-use log::info;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use log::{debug, info, warn};
 
 use rdkafka::client::ClientContext;
 use rdkafka::consumer::{ConsumerContext, Rebalance};
 use rdkafka::error::KafkaResult;
+use rdkafka::statistics::Statistics;
 use rdkafka::topic_partition_list::TopicPartitionList;
 
+use crate::metrics::{self, metrics};
+use crate::stats;
+
 // A context can be used to change the behavior of producers and consumers by adding callbacks
 // that will be executed by librdkafka.
-// This particular context sets up custom callbacks to log rebalancing events.
-pub struct CustomContext;
+// This particular context sets up custom callbacks to log rebalancing events and
+// statistics, which are emitted every `statistics.interval.ms` if configured.
+#[derive(Clone, Default)]
+pub struct CustomContext {
+    /// incremented on every partition assignment
+    generation: Arc<AtomicUsize>,
+    /// number of currently assigned partitions
+    assigned: Arc<AtomicUsize>,
+}
+
+impl CustomContext {
+    /// Number of partition assignments seen so far. State that depends on the
+    /// assignment (e.g. restored partitions of a state store) is stale once this changes.
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
 
-impl ClientContext for CustomContext {}
+    /// Number of partitions assigned by the last rebalance
+    pub fn assigned_partitions(&self) -> usize {
+        self.assigned.load(Ordering::SeqCst)
+    }
+}
+
+impl ClientContext for CustomContext {
+    fn stats(&self, statistics: Statistics) {
+        stats::record(&statistics);
+        info!("Statistics of {}", stats::summary(&statistics));
+        let stalled = stats::stalled_partitions(&statistics);
+        if !stalled.is_empty() {
+            warn!("{} is not fetching {}", statistics.name, stalled.join(", "));
+        }
+        debug!("Client stats: {:?}", statistics);
+    }
+}
 
 impl ConsumerContext for CustomContext {
     fn pre_rebalance(&self, rebalance: &Rebalance) {
@@ -19,9 +57,31 @@ impl ConsumerContext for CustomContext {
 
     fn post_rebalance(&self, rebalance: &Rebalance) {
         info!("Post rebalance {:?}", rebalance);
+        match rebalance {
+            Rebalance::Assign(partitions) => {
+                self.assigned.store(partitions.count(), Ordering::SeqCst);
+                self.generation.fetch_add(1, Ordering::SeqCst);
+            }
+            Rebalance::Revoke(partitions) => {
+                self.assigned.store(0, Ordering::SeqCst);
+                metrics::forget_lag(partitions);
+            }
+            Rebalance::Error(_) => {}
+        }
     }
 
-    fn commit_callback(&self, _result: KafkaResult<()>, offsets: &TopicPartitionList) {
+    fn commit_callback(&self, result: KafkaResult<()>, offsets: &TopicPartitionList) {
         info!("Committing offsets: {:?}", offsets);
+        if let Err(error) = result {
+            warn!("Committing offsets failed: {}", error);
+            let topics = offsets
+                .elements()
+                .iter()
+                .map(|element| element.topic().to_owned())
+                .collect::<BTreeSet<_>>();
+            for topic in topics {
+                metrics().commit_failures.with_label_values(&[&topic]).inc();
+            }
+        }
     }
 }
@@ -0,0 +1,10 @@
+/// Classification of the errors of handling a message, shared by the decoding and
+/// the handlers so the error policies treat them alike
+pub trait ErrorKind {
+    /// short, stable description of the error used in dead-letter headers and metrics
+    fn kind(&self) -> &'static str;
+
+    /// whether the error might go away when the message is retried, e.g. once the
+    /// schema registry is back
+    fn is_transient(&self) -> bool;
+}
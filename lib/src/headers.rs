@@ -0,0 +1,303 @@
+use std::future::Future;
+
+use once_cell::sync::OnceCell;
+use opentelemetry::context::{FutureExt, WithContext};
+use opentelemetry::Context;
+use rdkafka::message::{Headers, Message, OwnedHeaders};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const HEADER_MESSAGE_ID: &str = "message.id";
+/// the message id of the record which started the chain of records
+pub const HEADER_CORRELATION_ID: &str = "correlation.id";
+/// the message id of the record whose handling produced this one
+pub const HEADER_CAUSATION_ID: &str = "causation.id";
+/// the command the record is or was produced by
+pub const HEADER_COMMAND: &str = "command.name";
+pub const HEADER_PRODUCER_ID: &str = "producer.id";
+pub const HEADER_SCHEMA_VERSION: &str = "schema.version";
+/// where the record was first consumed, kept by retried and dead-lettered copies
+pub const HEADER_ORIGIN_TOPIC: &str = "origin.topic";
+pub const HEADER_ORIGIN_PARTITION: &str = "origin.partition";
+pub const HEADER_ORIGIN_OFFSET: &str = "origin.offset";
+
+static PRODUCER_ID: OnceCell<String> = OnceCell::new();
+
+/// Identify the records produced by this process, e.g. by the worker id.
+/// Only the first call has an effect.
+pub fn set_producer_id(producer_id: &str) {
+    let _ = PRODUCER_ID.set(producer_id.to_owned());
+}
+
+/// The standard headers of a record, see the `HEADER_*` constants
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageHeaders {
+    pub message_id: String,
+    pub correlation_id: Option<String>,
+    pub causation_id: Option<String>,
+    pub command: Option<String>,
+    pub producer_id: Option<String>,
+    pub schema_version: Option<u32>,
+}
+
+impl Default for MessageHeaders {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The value of the header `name` if it is utf-8 encoded. The last one wins if
+/// there is more than one.
+pub fn header<'a, H: Headers>(headers: &'a H, name: &str) -> Option<&'a str> {
+    (0..headers.count())
+        .rev()
+        .filter_map(|idx| headers.get(idx))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| std::str::from_utf8(value).ok())
+}
+
+impl MessageHeaders {
+    /// Headers of a new record which isn't caused by another one
+    pub fn new() -> Self {
+        MessageHeaders {
+            message_id: Uuid::new_v4().to_string(),
+            correlation_id: None,
+            causation_id: None,
+            command: None,
+            producer_id: PRODUCER_ID.get().cloned(),
+            schema_version: None,
+        }
+    }
+
+    /// The headers of `message`. Records without a message id are identified by
+    /// their topic, partition and offset.
+    pub fn read<M: Message>(message: &M) -> Self {
+        let get = |name| {
+            message
+                .headers()
+                .and_then(|headers| header(headers, name))
+                .map(str::to_owned)
+        };
+        MessageHeaders {
+            message_id: get(HEADER_MESSAGE_ID).unwrap_or_else(|| {
+                format!(
+                    "{}-{}@{}",
+                    message.topic(),
+                    message.partition(),
+                    message.offset()
+                )
+            }),
+            correlation_id: get(HEADER_CORRELATION_ID),
+            causation_id: get(HEADER_CAUSATION_ID),
+            command: get(HEADER_COMMAND),
+            producer_id: get(HEADER_PRODUCER_ID),
+            schema_version: get(HEADER_SCHEMA_VERSION).and_then(|version| version.parse().ok()),
+        }
+    }
+
+    /// Headers of a new record produced while handling the record with these headers
+    pub fn caused(&self) -> Self {
+        MessageHeaders {
+            correlation_id: Some(
+                self.correlation_id
+                    .clone()
+                    .unwrap_or_else(|| self.message_id.clone()),
+            ),
+            causation_id: Some(self.message_id.clone()),
+            command: self.command.clone(),
+            ..MessageHeaders::new()
+        }
+    }
+
+    pub fn with_command(mut self, command: &str) -> Self {
+        self.command = Some(command.to_owned());
+        self
+    }
+
+    pub fn with_schema_version(mut self, schema_version: u32) -> Self {
+        self.schema_version = Some(schema_version);
+        self
+    }
+
+    /// The correlation id of the chain of records this one belongs to
+    pub fn correlation_id(&self) -> &str {
+        self.correlation_id.as_deref().unwrap_or(&self.message_id)
+    }
+
+    /// `headers` extended by the standard headers they don't carry yet. Headers
+    /// set explicitly or copied from a retried record are kept.
+    pub fn fill(&self, headers: Option<OwnedHeaders>) -> OwnedHeaders {
+        let mut headers = headers.unwrap_or_default();
+        let schema_version = self.schema_version.map(|version| version.to_string());
+        let fields = [
+            (HEADER_MESSAGE_ID, Some(self.message_id.as_str())),
+            (HEADER_CORRELATION_ID, self.correlation_id.as_deref()),
+            (HEADER_CAUSATION_ID, self.causation_id.as_deref()),
+            (HEADER_COMMAND, self.command.as_deref()),
+            (HEADER_PRODUCER_ID, self.producer_id.as_deref()),
+            (HEADER_SCHEMA_VERSION, schema_version.as_deref()),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                if header(&headers, name).is_none() {
+                    headers = headers.add(name, value);
+                }
+            }
+        }
+        headers
+    }
+
+    /// The headers of the record handled by the current task, see [`MessageHeaders::scope`]
+    pub fn current() -> Option<Self> {
+        Context::current().get::<MessageHeaders>().cloned()
+    }
+
+    /// Make these the headers of the handled record while `future` runs, so the
+    /// records it produces are derived from them
+    pub fn scope<F: Future>(self, future: F) -> WithContext<F> {
+        future.with_context(Context::current().with_value(self))
+    }
+
+    /// Headers of a record produced by the current task, caused by the handled
+    /// record if there is one
+    pub fn outgoing() -> Self {
+        match Self::current() {
+            Some(cause) => cause.caused(),
+            None => MessageHeaders::new(),
+        }
+    }
+}
+
+/// The topic, partition and offset a record was first consumed at. Copies of the
+/// record on retry, failed and dead-letter topics keep it in the `origin.*` headers.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Origin {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+impl Origin {
+    /// The origin recorded in the headers of `message`, `message` itself without them
+    pub fn read<M: Message>(message: &M) -> Self {
+        let get = |name| message.headers().and_then(|headers| header(headers, name));
+        let recorded = get(HEADER_ORIGIN_TOPIC).and_then(|topic| {
+            Some(Origin {
+                topic: topic.to_owned(),
+                partition: get(HEADER_ORIGIN_PARTITION)?.parse().ok()?,
+                offset: get(HEADER_ORIGIN_OFFSET)?.parse().ok()?,
+            })
+        });
+        recorded.unwrap_or_else(|| Origin {
+            topic: message.topic().to_owned(),
+            partition: message.partition(),
+            offset: message.offset(),
+        })
+    }
+
+    /// `headers` extended by the origin headers unless they carry them already
+    pub fn fill(&self, headers: OwnedHeaders) -> OwnedHeaders {
+        if header(&headers, HEADER_ORIGIN_TOPIC).is_some() {
+            return headers;
+        }
+        headers
+            .add(HEADER_ORIGIN_TOPIC, &self.topic)
+            .add(HEADER_ORIGIN_PARTITION, &self.partition.to_string())
+            .add(HEADER_ORIGIN_OFFSET, &self.offset.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+    use rdkafka::message::{OwnedMessage, Timestamp};
+
+    use super::*;
+
+    #[test]
+    fn test_caused() {
+        let command = OwnedMessage::new(
+            Some(b"{}".to_vec()),
+            None,
+            "events".to_owned(),
+            Timestamp::NotAvailable,
+            2,
+            42,
+            None,
+        );
+        let cause = MessageHeaders::read(&command).with_command("createEvent");
+        assert_eq!(cause.message_id, "events-2@42");
+        assert_eq!(cause.correlation_id(), "events-2@42");
+
+        let event = block_on(cause.clone().scope(async { MessageHeaders::outgoing() }));
+        assert_ne!(event.message_id, cause.message_id);
+        assert_eq!(event.correlation_id.as_deref(), Some("events-2@42"));
+        assert_eq!(event.causation_id.as_deref(), Some("events-2@42"));
+        assert_eq!(event.command.as_deref(), Some("createEvent"));
+
+        // the chain keeps the correlation id of its first record
+        let next = event.caused();
+        assert_eq!(next.correlation_id.as_deref(), Some("events-2@42"));
+        assert_eq!(next.causation_id, Some(event.message_id.clone()));
+        assert_eq!(MessageHeaders::current(), None);
+    }
+
+    #[test]
+    fn test_fill() {
+        let headers = MessageHeaders::new()
+            .with_command("createEvent")
+            .with_schema_version(2);
+        let explicit = OwnedHeaders::new().add(HEADER_SCHEMA_VERSION, "3");
+        let record = OwnedMessage::new(
+            None,
+            None,
+            "events".to_owned(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+            Some(headers.fill(Some(explicit))),
+        );
+
+        let read = MessageHeaders::read(&record);
+        assert_eq!(read.message_id, headers.message_id);
+        assert_eq!(read.command.as_deref(), Some("createEvent"));
+        assert_eq!(read.schema_version, Some(3));
+        assert_eq!(read.causation_id, None);
+    }
+
+    #[test]
+    fn test_origin() {
+        let consumed = OwnedMessage::new(
+            None,
+            None,
+            "events".to_owned(),
+            Timestamp::NotAvailable,
+            1,
+            7,
+            None,
+        );
+        let origin = Origin::read(&consumed);
+        assert_eq!(
+            origin,
+            Origin {
+                topic: "events".to_owned(),
+                partition: 1,
+                offset: 7
+            }
+        );
+
+        // a retried copy points at the record it was copied from
+        let retried = OwnedMessage::new(
+            None,
+            None,
+            "events.retry.1s".to_owned(),
+            Timestamp::NotAvailable,
+            1,
+            0,
+            Some(origin.fill(OwnedHeaders::new())),
+        );
+        assert_eq!(Origin::read(&retried), origin);
+        let refilled = Origin::read(&retried).fill(origin.fill(OwnedHeaders::new()));
+        assert_eq!(refilled.count(), 3);
+    }
+}
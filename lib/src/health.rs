@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use once_cell::sync::OnceCell;
+use tiny_http::{Header, Response, Server};
+
+use crate::context::CustomContext;
+use crate::metrics;
+
+/// Liveness and readiness of a worker, shared between its poll loop and the HTTP server.
+///
+/// A worker is live as long as its poll loop calls [`Health::beat`] at least every
+/// `stuck_after`, and ready once its consumer has partitions assigned and the lag
+/// of the consumed partitions is at most `max_lag`.
+#[derive(Clone)]
+pub struct Health {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    started: Instant,
+    /// milliseconds since `started` of the last beat, 0 before the poll loop started
+    heartbeat: AtomicU64,
+    stuck_after: Duration,
+    max_lag: i64,
+    context: OnceCell<CustomContext>,
+    lags: Mutex<Lags>,
+}
+
+/// Lags of the partitions consumed since the last partition assignment
+#[derive(Default)]
+struct Lags {
+    generation: usize,
+    partitions: HashMap<(String, i32), i64>,
+}
+
+impl fmt::Debug for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Health")
+            .field("stuck_after", &self.inner.stuck_after)
+            .field("max_lag", &self.inner.max_lag)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Health {
+    pub fn new(stuck_after: Duration, max_lag: i64) -> Self {
+        Health {
+            inner: Arc::new(Inner {
+                started: Instant::now(),
+                heartbeat: AtomicU64::new(0),
+                stuck_after,
+                max_lag,
+                context: OnceCell::new(),
+                lags: Mutex::new(Lags::default()),
+            }),
+        }
+    }
+
+    /// Track the partition assignment of the consumer with `context`
+    pub fn watch(&self, context: &CustomContext) {
+        let _ = self.inner.context.set(context.clone());
+    }
+
+    /// Signal that the poll loop is making progress
+    pub fn beat(&self) {
+        let elapsed = self.inner.started.elapsed().as_millis() as u64;
+        self.inner.heartbeat.store(elapsed.max(1), Ordering::SeqCst);
+    }
+
+    /// Record the lag of a consumed partition
+    pub fn record_lag(&self, topic: &str, partition: i32, lag: i64) {
+        let generation = self.generation();
+        let mut lags = self.inner.lags.lock().unwrap();
+        if lags.generation != generation {
+            // partitions might have moved to other workers
+            lags.partitions.clear();
+            lags.generation = generation;
+        }
+        lags.partitions.insert((topic.to_owned(), partition), lag);
+    }
+
+    fn generation(&self) -> usize {
+        self.inner
+            .context
+            .get()
+            .map(CustomContext::generation)
+            .unwrap_or_default()
+    }
+
+    /// `Err` with the reason if the poll loop didn't beat for longer than `stuck_after`.
+    /// Workers whose poll loop didn't start yet are live, state stores restoring their
+    /// changelog beat between batches, see [`ChangelogStore::heartbeat`].
+    ///
+    /// [`ChangelogStore::heartbeat`]: crate::state::ChangelogStore::heartbeat
+    pub fn live(&self) -> Result<(), String> {
+        let heartbeat = self.inner.heartbeat.load(Ordering::SeqCst);
+        if heartbeat == 0 {
+            return Ok(());
+        }
+        let since = self
+            .inner
+            .started
+            .elapsed()
+            .saturating_sub(Duration::from_millis(heartbeat));
+        if since > self.inner.stuck_after {
+            return Err(format!("poll loop stuck for {:?}", since));
+        }
+        Ok(())
+    }
+
+    /// `Err` with the reason if the worker isn't assigned partitions or is lagging behind
+    pub fn ready(&self) -> Result<(), String> {
+        let context = self.inner.context.get().ok_or("consumer not started")?;
+        if self.inner.heartbeat.load(Ordering::SeqCst) == 0 {
+            return Err("poll loop not started".to_owned());
+        }
+        if context.assigned_partitions() == 0 {
+            return Err("no partitions assigned".to_owned());
+        }
+        let lags = self.inner.lags.lock().unwrap();
+        if lags.generation == context.generation() {
+            let lagging = lags
+                .partitions
+                .iter()
+                .filter(|(_, lag)| **lag > self.inner.max_lag)
+                .map(|((topic, partition), lag)| format!("{}/{} ({})", topic, partition, lag))
+                .collect::<Vec<_>>();
+            if !lagging.is_empty() {
+                return Err(format!("lagging behind on {}", lagging.join(", ")));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The workers of a process, live if all of them are and ready if any of them is
+#[derive(Debug, Clone)]
+pub struct Workers(Vec<Health>);
+
+impl Workers {
+    pub fn new(workers: Vec<Health>) -> Self {
+        Workers(workers)
+    }
+
+    pub fn live(&self) -> Result<(), String> {
+        let stuck = self
+            .0
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, health)| {
+                health
+                    .live()
+                    .err()
+                    .map(|reason| format!("worker {}: {}", idx, reason))
+            })
+            .collect::<Vec<_>>();
+        if stuck.is_empty() {
+            Ok(())
+        } else {
+            Err(stuck.join(", "))
+        }
+    }
+
+    pub fn ready(&self) -> Result<(), String> {
+        let mut reasons = Vec::new();
+        for (idx, health) in self.0.iter().enumerate() {
+            match health.ready() {
+                Ok(()) => return Ok(()),
+                Err(reason) => reasons.push(format!("worker {}: {}", idx, reason)),
+            }
+        }
+        if reasons.is_empty() {
+            return Err("no workers".to_owned());
+        }
+        Err(reasons.join(", "))
+    }
+}
+
+impl From<Health> for Workers {
+    fn from(health: Health) -> Self {
+        Workers(vec![health])
+    }
+}
+
+fn status(result: Result<(), String>) -> Response<io::Cursor<Vec<u8>>> {
+    match result {
+        Ok(()) => Response::from_string("ok"),
+        Err(reason) => Response::from_string(reason).with_status_code(503),
+    }
+}
+
+/// Serve `GET /metrics`, `/healthz` and `/readyz` of `workers` on `addr` from a background thread
+pub fn serve<W: Into<Workers>>(addr: SocketAddr, workers: W) -> io::Result<()> {
+    let workers = workers.into();
+    let server = Server::http(addr).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    info!("Serving metrics and health checks on http://{}", addr);
+    thread::Builder::new()
+        .name("http".to_owned())
+        .spawn(move || {
+            let content_type = Header::from_bytes("Content-Type", metrics::content_type()).unwrap();
+            for request in server.incoming_requests() {
+                let url = request.url().to_owned();
+                let response = match url.as_str() {
+                    "/metrics" => {
+                        Response::from_string(metrics::render()).with_header(content_type.clone())
+                    }
+                    "/healthz" => status(workers.live()),
+                    "/readyz" => status(workers.ready()),
+                    _ => Response::from_string("not found").with_status_code(404),
+                };
+                if let Err(e) = request.respond(response) {
+                    error!("Error responding to {}: {}", url, e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rdkafka::consumer::{ConsumerContext, Rebalance};
+    use rdkafka::topic_partition_list::TopicPartitionList;
+
+    use super::*;
+
+    #[test]
+    fn test_ready() {
+        let health = Health::new(Duration::from_secs(60), 100);
+        assert!(health.live().is_ok());
+        assert!(health.ready().is_err());
+
+        let context = CustomContext::default();
+        health.watch(&context);
+        health.beat();
+        assert_eq!(health.ready(), Err("no partitions assigned".to_owned()));
+
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition("events", 0);
+        partitions.add_partition("events", 1);
+        context.post_rebalance(&Rebalance::Assign(&partitions));
+        assert_eq!(context.assigned_partitions(), 2);
+        assert_eq!(health.ready(), Ok(()));
+
+        health.record_lag("events", 0, 500);
+        assert_eq!(
+            health.ready(),
+            Err("lagging behind on events/0 (500)".to_owned())
+        );
+        health.record_lag("events", 0, 20);
+        assert_eq!(health.ready(), Ok(()));
+
+        // lags of a previous assignment don't count
+        health.record_lag("events", 1, 500);
+        context.post_rebalance(&Rebalance::Revoke(&partitions));
+        assert!(health.ready().is_err());
+        context.post_rebalance(&Rebalance::Assign(&partitions));
+        assert_eq!(health.ready(), Ok(()));
+    }
+
+    #[test]
+    fn test_live() {
+        let health = Health::new(Duration::from_millis(10), 100);
+        health.beat();
+        assert!(health.live().is_ok());
+        thread::sleep(Duration::from_millis(20));
+        assert!(health.live().is_err());
+        health.beat();
+        assert!(health.live().is_ok());
+    }
+
+    #[test]
+    fn test_workers() {
+        let idle = Health::new(Duration::from_millis(10), 100);
+        idle.watch(&CustomContext::default());
+        idle.beat();
+        let assigned = Health::new(Duration::from_secs(60), 100);
+        let context = CustomContext::default();
+        assigned.watch(&context);
+        assigned.beat();
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition("events", 0);
+        context.post_rebalance(&Rebalance::Assign(&partitions));
+
+        let workers = Workers::new(vec![idle.clone(), assigned]);
+        // a worker without partitions doesn't keep the others from being ready
+        assert_eq!(workers.ready(), Ok(()));
+        assert_eq!(
+            Workers::from(idle.clone()).ready(),
+            Err("worker 0: no partitions assigned".to_owned())
+        );
+
+        // but a stuck worker isn't hidden by live ones
+        thread::sleep(Duration::from_millis(20));
+        assert!(workers.live().unwrap_err().starts_with("worker 0: "));
+    }
+}
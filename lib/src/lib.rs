@@ -1,3 +1,20 @@
+pub mod admin;
 pub mod async_std;
+pub mod avro;
+pub mod backup;
+pub mod client;
+pub mod codec;
+pub mod config;
+pub mod error;
+pub mod headers;
+pub mod health;
+pub mod log_context;
+pub mod metrics;
+pub mod security;
+pub mod shutdown;
+pub mod state;
+pub mod stats;
+pub mod telemetry;
+pub mod tokio;
 pub mod utils;
 pub mod context;
@@ -0,0 +1,137 @@
+use std::future::Future;
+
+use opentelemetry::context::{ContextGuard, FutureExt, WithContext};
+use opentelemetry::Context;
+use rdkafka::message::Message;
+use serde_json::{Map, Value};
+
+use crate::headers::MessageHeaders;
+use crate::telemetry;
+
+/// Structured fields attached to the log records emitted while it is active,
+/// e.g. the topic, partition and offset of the message being handled.
+///
+/// The fields are kept in the OpenTelemetry [`Context`] next to the trace context
+/// and the [`MessageHeaders`] of the handled record, so a single context carries
+/// all of them. Only the JSON log format prints the fields, see
+/// [`crate::utils::LogFormat`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LogContext {
+    fields: Map<String, Value>,
+}
+
+impl LogContext {
+    /// The fields of the current context
+    pub fn current() -> Self {
+        Context::current()
+            .get::<LogContext>()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The current fields extended by `topic`, `partition`, `offset` and `key` of `message`
+    pub fn message<M: Message>(message: &M) -> Self {
+        let context = LogContext::current()
+            .with("topic", message.topic())
+            .with("partition", message.partition())
+            .with("offset", message.offset());
+        match message.key() {
+            Some(key) => context.with("key", String::from_utf8_lossy(key).into_owned()),
+            None => context,
+        }
+    }
+
+    pub fn with<V: Into<Value>>(mut self, key: &str, value: V) -> Self {
+        self.fields.insert(key.to_owned(), value.into());
+        self
+    }
+
+    pub fn fields(&self) -> &Map<String, Value> {
+        &self.fields
+    }
+
+    /// The fields logged in `context`: those of its log context, the command and
+    /// correlation id of the handled record and the trace id
+    pub fn fields_of(context: &Context) -> Map<String, Value> {
+        let mut fields = context
+            .get::<LogContext>()
+            .map(|log_context| log_context.fields.clone())
+            .unwrap_or_default();
+        if let Some(headers) = context.get::<MessageHeaders>() {
+            if let Some(command) = &headers.command {
+                fields.insert("command".to_owned(), command.as_str().into());
+            }
+            fields.insert("correlation_id".to_owned(), headers.correlation_id().into());
+        }
+        if let Some(trace_id) = telemetry::trace_id(context) {
+            fields.insert("trace_id".to_owned(), trace_id.into());
+        }
+        fields
+    }
+
+    /// `context` carrying these fields, e.g. the trace context of a message
+    pub fn attach_to(self, context: &Context) -> Context {
+        context.with_value(self)
+    }
+
+    /// Make these the fields of the current thread until the guard is dropped.
+    /// Use [`LogContext::scope`] for code which awaits in between.
+    pub fn enter(self) -> ContextGuard {
+        self.attach_to(&Context::current()).attach()
+    }
+
+    /// Make these the fields of `future` whenever it is polled, whichever thread
+    /// it runs on
+    pub fn scope<F: Future>(self, future: F) -> WithContext<F> {
+        future.with_context(self.attach_to(&Context::current()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::{block_on, yield_now};
+    use rdkafka::message::{OwnedMessage, Timestamp};
+
+    use super::*;
+
+    #[test]
+    fn test_scope() {
+        let message = OwnedMessage::new(
+            Some(b"{}".to_vec()),
+            Some(b"user-1".to_vec()),
+            "users".to_owned(),
+            Timestamp::NotAvailable,
+            3,
+            42,
+            None,
+        );
+        let worker = LogContext::default().with("worker_id", "worker-1");
+        let _guard = worker.clone().enter();
+
+        let context = LogContext::message(&message);
+        let headers = MessageHeaders::read(&message).with_command("createUser");
+        let fields = block_on(context.scope(async {
+            // the headers of the handled record are kept in the same context
+            headers
+                .scope(async {
+                    yield_now().await;
+                    LogContext::fields_of(&Context::current())
+                })
+                .await
+        }));
+        assert_eq!(
+            Value::Object(fields),
+            serde_json::json!({
+                "worker_id": "worker-1",
+                "topic": "users",
+                "partition": 3,
+                "offset": 42,
+                "key": "user-1",
+                "command": "createUser",
+                "correlation_id": "users-3@42",
+            })
+        );
+        // the scope ends with the future
+        assert_eq!(LogContext::current(), worker);
+    }
+}
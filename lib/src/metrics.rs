@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_gauge_vec, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+    Encoder, GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::message::Message;
+use rdkafka::topic_partition_list::TopicPartitionList;
+
+use crate::config::KafkaConfig;
+
+/// buckets in seconds, from 1ms to 10s
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Metrics of all workers, registered with the default prometheus registry
+pub struct Metrics {
+    pub consumed: IntCounterVec,
+    /// outcomes: handled, skipped, retried, dead_lettered and retries_exhausted
+    pub processed: IntCounterVec,
+    pub failed: IntCounterVec,
+    pub produced: IntCounterVec,
+    pub handler_latency: HistogramVec,
+    pub delivery_latency: HistogramVec,
+    pub commit_failures: IntCounterVec,
+    pub consumer_lag: IntGaugeVec,
+    /// librdkafka statistics, see [`crate::stats`]
+    pub rdkafka_messages: IntGaugeVec,
+    pub rdkafka_queue: IntGaugeVec,
+    pub rdkafka_broker_rtt: GaugeVec,
+    pub rdkafka_broker_requests: IntGaugeVec,
+    pub rdkafka_partition_queue: IntGaugeVec,
+    pub rdkafka_partition_lag: IntGaugeVec,
+    pub rdkafka_partition_fetching: IntGaugeVec,
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(|| Metrics {
+    consumed: register_int_counter_vec!(
+        "zeou_messages_consumed_total",
+        "Messages received from kafka",
+        &["domain"]
+    )
+    .unwrap(),
+    processed: register_int_counter_vec!(
+        "zeou_messages_processed_total",
+        "Messages processed, by outcome",
+        &["domain", "command", "outcome"]
+    )
+    .unwrap(),
+    failed: register_int_counter_vec!(
+        "zeou_messages_failed_total",
+        "Messages which could not be decoded or whose handler failed",
+        &["domain", "kind"]
+    )
+    .unwrap(),
+    produced: register_int_counter_vec!(
+        "zeou_messages_produced_total",
+        "Records produced, by delivery status (ok, error)",
+        &["topic", "status"]
+    )
+    .unwrap(),
+    handler_latency: register_histogram_vec!(
+        "zeou_handler_duration_seconds",
+        "Time spent in command handlers",
+        &["domain", "command"],
+        LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap(),
+    delivery_latency: register_histogram_vec!(
+        "zeou_delivery_duration_seconds",
+        "Time from producing a record until its delivery report",
+        &["topic"],
+        LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap(),
+    commit_failures: register_int_counter_vec!(
+        "zeou_commit_failures_total",
+        "Failed offset or transaction commits",
+        &["topic"]
+    )
+    .unwrap(),
+    consumer_lag: register_int_gauge_vec!(
+        "zeou_consumer_lag",
+        "Records between the last consumed offset and the high watermark",
+        &["topic", "partition"]
+    )
+    .unwrap(),
+    rdkafka_messages: register_int_gauge_vec!(
+        "zeou_rdkafka_messages",
+        "Messages transmitted (tx) to or received (rx) from the brokers by a client",
+        &["client", "direction"]
+    )
+    .unwrap(),
+    rdkafka_queue: register_int_gauge_vec!(
+        "zeou_rdkafka_queue",
+        "Messages in the producer queue (msg) and ops waiting in the reply queue (reply)",
+        &["client", "queue"]
+    )
+    .unwrap(),
+    rdkafka_broker_rtt: register_gauge_vec!(
+        "zeou_rdkafka_broker_rtt_seconds",
+        "Average round-trip time of requests to a broker",
+        &["client", "broker"]
+    )
+    .unwrap(),
+    rdkafka_broker_requests: register_int_gauge_vec!(
+        "zeou_rdkafka_broker_requests",
+        "Requests waiting to be sent to (outbuf) or answered by (waitresp) a broker",
+        &["client", "broker", "queue"]
+    )
+    .unwrap(),
+    rdkafka_partition_queue: register_int_gauge_vec!(
+        "zeou_rdkafka_partition_queue",
+        "Messages of a partition in the fetch queue (fetch) or the producer queue (msg)",
+        &["client", "topic", "partition", "queue"]
+    )
+    .unwrap(),
+    rdkafka_partition_lag: register_int_gauge_vec!(
+        "zeou_rdkafka_partition_consumer_lag",
+        "Consumer lag of a partition as reported by librdkafka",
+        &["client", "topic", "partition"]
+    )
+    .unwrap(),
+    rdkafka_partition_fetching: register_int_gauge_vec!(
+        "zeou_rdkafka_partition_fetching",
+        "1 if the partition is actively fetched, 0 otherwise",
+        &["client", "topic", "partition"]
+    )
+    .unwrap(),
+});
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Count a produced record and observe its delivery latency
+pub fn observe_delivery<T, E>(topic: &str, started: Instant, result: &Result<T, E>) {
+    let metrics = metrics();
+    let status = if result.is_ok() { "ok" } else { "error" };
+    metrics.produced.with_label_values(&[topic, status]).inc();
+    metrics
+        .delivery_latency
+        .with_label_values(&[topic])
+        .observe(started.elapsed().as_secs_f64());
+}
+
+pub fn observe_handler(domain: &str, command: &str, elapsed: Duration) {
+    metrics()
+        .handler_latency
+        .with_label_values(&[domain, command])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// how long fetched high watermarks are used to compute the consumer lag
+const LAG_REFRESH: Duration = Duration::from_secs(10);
+
+type HighWatermarks = Arc<Mutex<HashMap<(String, i32), i64>>>;
+
+/// Tracks the consumer lag of the consumed partitions. High watermarks are fetched
+/// from the broker by a background thread at most every [`LAG_REFRESH`] per partition,
+/// so observing the lag never blocks.
+pub struct Lag {
+    high_watermarks: HighWatermarks,
+    /// when the high watermark of a partition was last requested
+    requested: HashMap<(String, i32), Instant>,
+    refresh: Sender<(String, i32)>,
+}
+
+impl Lag {
+    /// Fetch high watermarks with a client of its own, which stops with the returned `Lag`
+    pub fn new(config: &KafkaConfig) -> Self {
+        let consumer: BaseConsumer = config
+            .consumer()
+            .create()
+            .expect("Consumer creation failed");
+        let high_watermarks = HighWatermarks::default();
+        let fetched = Arc::clone(&high_watermarks);
+        let (refresh, requests) = mpsc::channel::<(String, i32)>();
+        thread::Builder::new()
+            .name("lag".to_owned())
+            .spawn(move || {
+                for (topic, partition) in requests {
+                    match consumer.fetch_watermarks(&topic, partition, Duration::from_secs(1)) {
+                        Ok((_, high)) => {
+                            fetched.lock().unwrap().insert((topic, partition), high);
+                        }
+                        Err(e) => warn!("Unable to fetch watermarks of {}: {}", topic, e),
+                    }
+                }
+            })
+            .expect("Failed to spawn lag thread");
+        Lag {
+            high_watermarks,
+            requested: HashMap::new(),
+            refresh,
+        }
+    }
+
+    /// Update and return the lag of the partition of `message`, `None` until the
+    /// high watermark of the partition was fetched
+    pub fn observe<M: Message>(&mut self, message: &M) -> Option<i64> {
+        let partition = (message.topic().to_owned(), message.partition());
+        let due = self
+            .requested
+            .get(&partition)
+            .map_or(true, |requested| requested.elapsed() >= LAG_REFRESH);
+        if due {
+            self.requested.insert(partition.clone(), Instant::now());
+            // the thread only stops once `self` is dropped
+            let _ = self.refresh.send(partition.clone());
+        }
+
+        let high = *self.high_watermarks.lock().unwrap().get(&partition)?;
+        let lag = (high - message.offset() - 1).max(0);
+        metrics()
+            .consumer_lag
+            .with_label_values(&[message.topic(), &message.partition().to_string()])
+            .set(lag);
+        Some(lag)
+    }
+}
+
+/// Drop the lag series of partitions which are no longer consumed
+pub fn forget_lag(partitions: &TopicPartitionList) {
+    for elem in partitions.elements() {
+        let _ = metrics()
+            .consumer_lag
+            .remove_label_values(&[elem.topic(), &elem.partition().to_string()]);
+    }
+}
+
+/// Content type of [`render`]ed metrics
+pub fn content_type() -> &'static str {
+    prometheus::TEXT_FORMAT
+}
+
+/// The metrics of the default registry in the prometheus text format
+pub fn render() -> String {
+    // make sure the metrics are registered even if none was recorded yet
+    Lazy::force(&METRICS);
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("metrics are encodable");
+    String::from_utf8(buffer).expect("metrics are utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use rdkafka::message::{OwnedMessage, Timestamp};
+    use rdkafka::producer::FutureRecord;
+
+    use super::*;
+    use crate::async_std::{send, AsyncStdRuntime};
+    use crate::client::create_mock_cluster;
+
+    #[test]
+    fn test_render() {
+        metrics()
+            .processed
+            .with_label_values(&["events", "createEvent", "handled"])
+            .inc();
+        observe_delivery::<(), ()>("events-processed", Instant::now(), &Ok(()));
+
+        let rendered = render();
+        assert!(rendered.contains(
+            r#"zeou_messages_processed_total{command="createEvent",domain="events",outcome="handled"} 1"#
+        ));
+        assert!(rendered
+            .contains(r#"zeou_messages_produced_total{status="ok",topic="events-processed"} 1"#));
+        assert!(rendered.contains("zeou_delivery_duration_seconds_bucket"));
+    }
+
+    #[test]
+    fn test_lag() {
+        let (producer, config) = create_mock_cluster::<AsyncStdRuntime>();
+        async_std::task::block_on(async {
+            for _ in 0..3 {
+                let record = FutureRecord::<(), str>::to("lagging")
+                    .partition(0)
+                    .payload("{}");
+                send(&producer, record).await.unwrap();
+            }
+        });
+        let message = OwnedMessage::new(
+            None,
+            None,
+            "lagging".to_owned(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+            None,
+        );
+
+        let mut lag = Lag::new(&config);
+        let started = Instant::now();
+        let observed = loop {
+            match lag.observe(&message) {
+                Some(observed) => break observed,
+                None if started.elapsed() < Duration::from_secs(10) => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                None => panic!("high watermark was not fetched"),
+            }
+        };
+        assert_eq!(observed, 2);
+        let series = r#"zeou_consumer_lag{partition="0",topic="lagging"}"#;
+        assert!(render().contains(&format!("{} 2", series)));
+
+        let mut revoked = TopicPartitionList::new();
+        revoked.add_partition("lagging", 0);
+        forget_lag(&revoked);
+        assert!(!render().contains(series));
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use clap::{Arg, ArgMatches};
+
+/// `security.protocol` of librdkafka
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecurityProtocol {
+    Plaintext,
+    Ssl,
+    SaslPlaintext,
+    SaslSsl,
+}
+
+impl SecurityProtocol {
+    pub fn uses_ssl(self) -> bool {
+        matches!(self, SecurityProtocol::Ssl | SecurityProtocol::SaslSsl)
+    }
+
+    pub fn uses_sasl(self) -> bool {
+        matches!(
+            self,
+            SecurityProtocol::SaslPlaintext | SecurityProtocol::SaslSsl
+        )
+    }
+}
+
+impl FromStr for SecurityProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plaintext" => Ok(SecurityProtocol::Plaintext),
+            "ssl" => Ok(SecurityProtocol::Ssl),
+            "sasl_plaintext" => Ok(SecurityProtocol::SaslPlaintext),
+            "sasl_ssl" => Ok(SecurityProtocol::SaslSsl),
+            _ => Err(format!("unknown security protocol: {}", s)),
+        }
+    }
+}
+
+/// `sasl.mechanism` of librdkafka. GSSAPI is missing on purpose, rdkafka is built
+/// without the `gssapi` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaslMechanism {
+    Plain,
+    ScramSha256,
+    ScramSha512,
+    OAuthBearer,
+}
+
+impl SaslMechanism {
+    /// whether the mechanism authenticates with `sasl.username` and `sasl.password`
+    pub fn needs_credentials(self) -> bool {
+        !matches!(self, SaslMechanism::OAuthBearer)
+    }
+}
+
+impl FromStr for SaslMechanism {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "PLAIN" => Ok(SaslMechanism::Plain),
+            "SCRAM-SHA-256" => Ok(SaslMechanism::ScramSha256),
+            "SCRAM-SHA-512" => Ok(SaslMechanism::ScramSha512),
+            "OAUTHBEARER" => Ok(SaslMechanism::OAuthBearer),
+            _ => Err(format!("unsupported sasl mechanism: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecurityError(String);
+
+impl fmt::Display for SecurityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SecurityError {}
+
+/// CLI arguments and the librdkafka properties they set
+const ARGS: [(&str, &str, &str, &str); 8] = [
+    (
+        "security-protocol",
+        "security.protocol",
+        "ZEOU_SECURITY_PROTOCOL",
+        "plaintext, ssl, sasl_plaintext or sasl_ssl",
+    ),
+    (
+        "ssl-ca-location",
+        "ssl.ca.location",
+        "ZEOU_SSL_CA_LOCATION",
+        "CA certificate(s) to verify the brokers with",
+    ),
+    (
+        "ssl-certificate-location",
+        "ssl.certificate.location",
+        "ZEOU_SSL_CERTIFICATE_LOCATION",
+        "client certificate for TLS client authentication",
+    ),
+    (
+        "ssl-key-location",
+        "ssl.key.location",
+        "ZEOU_SSL_KEY_LOCATION",
+        "private key of the client certificate",
+    ),
+    (
+        "ssl-key-password",
+        "ssl.key.password",
+        "ZEOU_SSL_KEY_PASSWORD",
+        "password of the private key",
+    ),
+    (
+        "sasl-mechanism",
+        "sasl.mechanism",
+        "ZEOU_SASL_MECHANISM",
+        "PLAIN, SCRAM-SHA-256, SCRAM-SHA-512 or OAUTHBEARER",
+    ),
+    (
+        "sasl-username",
+        "sasl.username",
+        "ZEOU_SASL_USERNAME",
+        "SASL username",
+    ),
+    (
+        "sasl-password",
+        "sasl.password",
+        "ZEOU_SASL_PASSWORD",
+        "SASL password",
+    ),
+];
+
+/// `--security-protocol`, `--ssl-*` and `--sasl-*` arguments
+pub fn args() -> Vec<Arg> {
+    ARGS.iter()
+        .map(|(id, _, env, help)| {
+            let arg = Arg::new(*id).long(*id).help(*help).env(*env).global(true);
+            match *id {
+                "security-protocol" => {
+                    arg.value_parser(["plaintext", "ssl", "sasl_plaintext", "sasl_ssl"])
+                }
+                "sasl-mechanism" => {
+                    arg.value_parser(|s: &str| s.parse::<SaslMechanism>().map(|_| s.to_owned()))
+                }
+                "ssl-key-password" | "sasl-password" => arg.hide_env_values(true),
+                _ => arg,
+            }
+        })
+        .collect()
+}
+
+/// The properties set by the arguments of [`args`]
+pub fn properties(matches: &ArgMatches) -> Vec<(&'static str, String)> {
+    ARGS.iter()
+        .filter_map(|(id, property, _, _)| {
+            matches
+                .try_get_one::<String>(id)
+                .ok()
+                .flatten()
+                .map(|value| (*property, value.to_owned()))
+        })
+        .collect()
+}
+
+fn is_file(location: &str) -> bool {
+    Path::new(location).is_file()
+}
+
+/// Check the security properties of a client for consistency and that the files they
+/// refer to exist, so misconfigurations fail at startup instead of in librdkafka's
+/// background threads.
+pub fn validate(properties: &BTreeMap<String, String>) -> Result<(), SecurityError> {
+    let get = |key: &str| properties.get(key).map(String::as_str);
+
+    let protocol = match get("security.protocol") {
+        Some(protocol) => protocol
+            .parse::<SecurityProtocol>()
+            .map_err(SecurityError)?,
+        None => SecurityProtocol::Plaintext,
+    };
+
+    for key in properties.keys() {
+        if key.starts_with("ssl.") && !protocol.uses_ssl() {
+            return Err(SecurityError(format!(
+                "{} requires security.protocol ssl or sasl_ssl",
+                key
+            )));
+        }
+        if key.starts_with("sasl.") && !protocol.uses_sasl() {
+            return Err(SecurityError(format!(
+                "{} requires security.protocol sasl_plaintext or sasl_ssl",
+                key
+            )));
+        }
+    }
+
+    for key in [
+        "ssl.ca.location",
+        "ssl.certificate.location",
+        "ssl.key.location",
+    ] {
+        match get(key) {
+            // librdkafka's value for using the system's CA certificates
+            Some("probe") if key == "ssl.ca.location" => {}
+            Some(location) if !is_file(location) => {
+                return Err(SecurityError(format!("{} not found: {}", key, location)))
+            }
+            _ => {}
+        }
+    }
+    match (get("ssl.certificate.location"), get("ssl.key.location")) {
+        (Some(_), None) => {
+            return Err(SecurityError(
+                "ssl.certificate.location requires ssl.key.location".to_owned(),
+            ))
+        }
+        (None, Some(_)) => {
+            return Err(SecurityError(
+                "ssl.key.location requires ssl.certificate.location".to_owned(),
+            ))
+        }
+        _ => {}
+    }
+
+    if protocol.uses_sasl() {
+        let mechanism = get("sasl.mechanism")
+            .or_else(|| get("sasl.mechanisms"))
+            .ok_or_else(|| SecurityError(format!("{:?} requires sasl.mechanism", protocol)))?
+            .parse::<SaslMechanism>()
+            .map_err(SecurityError)?;
+        if mechanism.needs_credentials() {
+            for key in ["sasl.username", "sasl.password"] {
+                if get(key).map_or(true, str::is_empty) {
+                    return Err(SecurityError(format!("{:?} requires {}", mechanism, key)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&properties(&[])).is_ok());
+        assert!(validate(&properties(&[("security.protocol", "SSL")])).is_ok());
+        assert!(validate(&properties(&[
+            ("security.protocol", "ssl"),
+            ("ssl.ca.location", "probe")
+        ]))
+        .is_ok());
+        assert!(validate(&properties(&[
+            ("security.protocol", "sasl_ssl"),
+            ("sasl.mechanism", "SCRAM-SHA-512"),
+            ("sasl.username", "zeou"),
+            ("sasl.password", "secret")
+        ]))
+        .is_ok());
+
+        assert!(validate(&properties(&[("security.protocol", "tls")])).is_err());
+        assert!(validate(&properties(&[("ssl.ca.location", "probe")])).is_err());
+        assert!(validate(&properties(&[
+            ("security.protocol", "ssl"),
+            ("ssl.ca.location", "/does/not/exist.pem")
+        ]))
+        .is_err());
+        assert!(validate(&properties(&[
+            ("security.protocol", "sasl_plaintext"),
+            ("sasl.mechanism", "GSSAPI")
+        ]))
+        .is_err());
+        assert!(validate(&properties(&[
+            ("security.protocol", "sasl_ssl"),
+            ("sasl.mechanism", "PLAIN"),
+            ("sasl.username", "zeou")
+        ]))
+        .is_err());
+        assert!(validate(&properties(&[
+            ("security.protocol", "ssl"),
+            ("sasl.username", "zeou")
+        ]))
+        .is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use std::future::{self, Future};
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use std::thread;
+
+use signal_hook::consts::TERM_SIGNALS;
+use signal_hook::flag;
+use signal_hook::iterator::Signals;
+
+/// Set once SIGTERM or SIGINT is received, so a worker can stop fetching and drain.
+/// A second signal terminates the process immediately.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    /// tasks waiting for the shutdown request
+    waiting: Arc<Mutex<Vec<Waker>>>,
+}
+
+impl Shutdown {
+    /// Register the signal handlers
+    pub fn install() -> io::Result<Self> {
+        let shutdown = Shutdown::default();
+        for signal in TERM_SIGNALS {
+            // exits if the flag is already set, i.e. on the second signal
+            flag::register_conditional_shutdown(*signal, 1, Arc::clone(&shutdown.requested))?;
+            flag::register(*signal, Arc::clone(&shutdown.requested))?;
+        }
+        // waking tasks isn't async-signal-safe, that happens on a thread instead
+        let mut signals = Signals::new(TERM_SIGNALS)?;
+        let waiting = shutdown.clone();
+        thread::Builder::new()
+            .name("shutdown".to_owned())
+            .spawn(move || {
+                if signals.forever().next().is_some() {
+                    waiting.wake();
+                }
+            })?;
+        Ok(shutdown)
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Request a shutdown without a signal
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Resolves once a shutdown is requested, on any runtime
+    pub fn wait(&self) -> impl Future<Output = ()> + '_ {
+        future::poll_fn(move |cx| {
+            if self.is_requested() {
+                return Poll::Ready(());
+            }
+            let mut waiting = self.waiting.lock().unwrap();
+            // polled again by the same task, e.g. in a select loop
+            if !waiting.iter().any(|waker| waker.will_wake(cx.waker())) {
+                waiting.push(cx.waker().clone());
+            }
+            drop(waiting);
+            // the request might have happened while registering
+            if self.is_requested() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+    }
+
+    fn wake(&self) {
+        let waiting = mem::take(&mut *self.waiting.lock().unwrap());
+        waiting.into_iter().for_each(Waker::wake);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wait() {
+        let shutdown = Shutdown::default();
+        let requested = shutdown.clone();
+        let requester = thread::spawn(move || requested.request());
+        async_std::task::block_on(shutdown.wait());
+        assert!(shutdown.is_requested());
+        requester.join().unwrap();
+    }
+}
@@ -0,0 +1,600 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
+use rdkafka::message::{Message, OwnedMessage};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+
+use log::{info, warn};
+
+use crate::admin::create_copartitioned_topics;
+use crate::async_std::AsyncStdRuntime;
+use crate::client::send;
+use crate::config::KafkaConfig;
+use crate::context::CustomContext;
+use crate::health::Health;
+
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+/// how long restoring a single partition may take by default
+const RESTORE_DEADLINE: Duration = Duration::from_secs(300);
+/// upper bound of changelog records fetched by a single blocking call
+const RESTORE_BATCH: usize = 1000;
+
+#[derive(Debug)]
+pub enum StateError {
+    Store(sled::Error),
+    Kafka(KafkaError),
+    /// the changelog partition was not restored before the deadline, the records
+    /// applied so far are kept
+    RestoreTimeout {
+        topic: String,
+        partition: i32,
+        position: i64,
+        high: i64,
+    },
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Store(error) => write!(f, "state store error: {}", error),
+            StateError::Kafka(error) => write!(f, "changelog error: {}", error),
+            StateError::RestoreTimeout {
+                topic,
+                partition,
+                position,
+                high,
+            } => write!(
+                f,
+                "restoring {} partition {} timed out at offset {} of {}",
+                topic, partition, position, high
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<sled::Error> for StateError {
+    fn from(error: sled::Error) -> Self {
+        StateError::Store(error)
+    }
+}
+
+impl From<KafkaError> for StateError {
+    fn from(error: KafkaError) -> Self {
+        StateError::Kafka(error)
+    }
+}
+
+pub type StateResult<T> = Result<T, StateError>;
+
+/// A local key-value store which also remembers up to which changelog offset
+/// it has been restored
+pub trait KeyValueStore {
+    fn get(&self, key: &[u8]) -> StateResult<Option<Vec<u8>>>;
+    fn put(&self, key: &[u8], value: &[u8]) -> StateResult<()>;
+    fn delete(&self, key: &[u8]) -> StateResult<()>;
+    /// next changelog offset to apply for `partition`
+    fn changelog_offset(&self, partition: i32) -> StateResult<Option<i64>>;
+    fn set_changelog_offset(&self, partition: i32, offset: i64) -> StateResult<()>;
+    fn flush(&self) -> StateResult<()>;
+}
+
+/// Embedded on-disk store
+pub struct SledStore {
+    data: sled::Tree,
+    offsets: sled::Tree,
+}
+
+impl SledStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> StateResult<Self> {
+        Self::from_db(sled::open(path)?)
+    }
+
+    /// A store which is removed when dropped
+    pub fn temporary() -> StateResult<Self> {
+        Self::from_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn from_db(db: sled::Db) -> StateResult<Self> {
+        Ok(SledStore {
+            data: db.open_tree("data")?,
+            offsets: db.open_tree("changelog_offsets")?,
+        })
+    }
+}
+
+impl KeyValueStore for SledStore {
+    fn get(&self, key: &[u8]) -> StateResult<Option<Vec<u8>>> {
+        Ok(self.data.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> StateResult<()> {
+        self.data.insert(key, value)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> StateResult<()> {
+        self.data.remove(key)?;
+        Ok(())
+    }
+
+    fn changelog_offset(&self, partition: i32) -> StateResult<Option<i64>> {
+        Ok(self.offsets.get(partition.to_be_bytes())?.map(|value| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&value);
+            i64::from_be_bytes(bytes)
+        }))
+    }
+
+    fn set_changelog_offset(&self, partition: i32, offset: i64) -> StateResult<()> {
+        self.offsets
+            .insert(partition.to_be_bytes(), &offset.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn flush(&self) -> StateResult<()> {
+        self.data.flush()?;
+        self.offsets.flush()?;
+        Ok(())
+    }
+}
+
+/// In-memory store, state is only kept in the changelog
+#[derive(Default)]
+pub struct MemoryStore {
+    data: RefCell<BTreeMap<Vec<u8>, Vec<u8>>>,
+    offsets: RefCell<BTreeMap<i32, i64>>,
+}
+
+impl KeyValueStore for MemoryStore {
+    fn get(&self, key: &[u8]) -> StateResult<Option<Vec<u8>>> {
+        Ok(self.data.borrow().get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> StateResult<()> {
+        self.data.borrow_mut().insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> StateResult<()> {
+        self.data.borrow_mut().remove(key);
+        Ok(())
+    }
+
+    fn changelog_offset(&self, partition: i32) -> StateResult<Option<i64>> {
+        Ok(self.offsets.borrow().get(&partition).copied())
+    }
+
+    fn set_changelog_offset(&self, partition: i32, offset: i64) -> StateResult<()> {
+        self.offsets.borrow_mut().insert(partition, offset);
+        Ok(())
+    }
+
+    fn flush(&self) -> StateResult<()> {
+        Ok(())
+    }
+}
+
+/// A [`KeyValueStore`] mirrored to a compacted changelog topic.
+///
+/// Every write is produced to the changelog partition matching the partition of the
+/// input message before it is applied locally. A partition is restored from the
+/// changelog the first time it is accessed after an assignment, starting at the last
+/// changelog offset the local store has seen.
+pub struct ChangelogStore<S> {
+    store: S,
+    config: KafkaConfig,
+    topic: String,
+    restored: RefCell<HashSet<i32>>,
+    generation: Cell<usize>,
+    /// stage writes until the producer's transaction is committed
+    transactional: bool,
+    pending: RefCell<Vec<PendingWrite>>,
+    restore_deadline: Duration,
+    /// beaten between restored batches, restores run inside the poll loop
+    health: Option<Health>,
+}
+
+/// A changelog write not yet applied to the local store
+struct PendingWrite {
+    partition: i32,
+    key: Vec<u8>,
+    /// `None` for deletes
+    value: Option<Vec<u8>>,
+    offset: i64,
+}
+
+impl<S: KeyValueStore> ChangelogStore<S> {
+    pub fn new(store: S, config: &KafkaConfig, topic: &str) -> Self {
+        ChangelogStore {
+            store,
+            config: config.clone(),
+            topic: topic.to_owned(),
+            restored: RefCell::new(HashSet::new()),
+            generation: Cell::new(0),
+            transactional: false,
+            pending: RefCell::new(Vec::new()),
+            restore_deadline: RESTORE_DEADLINE,
+            health: None,
+        }
+    }
+
+    /// How long restoring a single partition may take before it fails
+    pub fn restore_deadline(mut self, deadline: Duration) -> Self {
+        self.restore_deadline = deadline;
+        self
+    }
+
+    /// Keep the worker of `health` live while a restore takes longer than its `stuck_after`
+    pub fn heartbeat(mut self, health: Health) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Writes are produced within the producer's current transaction and only applied
+    /// locally on [`ChangelogStore::commit`], so an aborted transaction leaves no trace.
+    pub fn transactional(mut self) -> Self {
+        self.transactional = true;
+        self
+    }
+
+    /// Apply the writes of the committed transaction locally. If that fails the
+    /// changelog is ahead of the local store, which is then restored from the
+    /// changelog on the next access.
+    pub fn commit(&self) -> StateResult<()> {
+        for write in self.pending.take() {
+            if let Err(error) = self.apply(write) {
+                self.invalidate();
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Restore every partition from the changelog on its next access
+    pub fn invalidate(&self) {
+        self.restored.borrow_mut().clear();
+    }
+
+    /// Forget the writes of the aborted transaction
+    pub fn abort(&self) {
+        self.pending.borrow_mut().clear();
+    }
+
+    fn stage(&self, write: PendingWrite) -> StateResult<()> {
+        if self.transactional {
+            self.pending.borrow_mut().push(write);
+            Ok(())
+        } else {
+            self.apply(write)
+        }
+    }
+
+    fn apply(&self, write: PendingWrite) -> StateResult<()> {
+        match write.value {
+            Some(value) => self.store.put(&write.key, &value)?,
+            None => self.store.delete(&write.key)?,
+        }
+        self.store
+            .set_changelog_offset(write.partition, write.offset + 1)
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn get(&self, key: &[u8]) -> StateResult<Option<Vec<u8>>> {
+        self.store.get(key)
+    }
+
+    pub async fn put(
+        &self,
+        producer: &FutureProducer<CustomContext, AsyncStdRuntime>,
+        partition: i32,
+        key: &[u8],
+        value: &[u8],
+    ) -> StateResult<()> {
+        let record = FutureRecord::to(&self.topic)
+            .partition(partition)
+            .key(key)
+            .payload(value);
+        let (_, offset) = send(producer, record).await.map_err(|(error, _)| error)?;
+        self.stage(PendingWrite {
+            partition,
+            key: key.to_vec(),
+            value: Some(value.to_vec()),
+            offset,
+        })
+    }
+
+    pub async fn delete(
+        &self,
+        producer: &FutureProducer<CustomContext, AsyncStdRuntime>,
+        partition: i32,
+        key: &[u8],
+    ) -> StateResult<()> {
+        // a record without payload is a tombstone for the compacted changelog
+        let record = FutureRecord::<[u8], [u8]>::to(&self.topic)
+            .partition(partition)
+            .key(key);
+        let (_, offset) = send(producer, record).await.map_err(|(error, _)| error)?;
+        self.stage(PendingWrite {
+            partition,
+            key: key.to_vec(),
+            value: None,
+            offset,
+        })
+    }
+
+    /// Restore `partition` unless it already was since the consumer's last assignment
+    pub async fn ensure_restored(&self, partition: i32, generation: usize) -> StateResult<()> {
+        if self.generation.replace(generation) != generation {
+            self.restored.borrow_mut().clear();
+        }
+        if self.restored.borrow().contains(&partition) {
+            return Ok(());
+        }
+        self.restore(partition).await?;
+        self.restored.borrow_mut().insert(partition);
+        Ok(())
+    }
+
+    /// Apply the changelog of `partition` from the last seen offset up to its high watermark.
+    /// The consumer blocks, so it only runs on the blocking thread pool.
+    pub async fn restore(&self, partition: i32) -> StateResult<usize> {
+        let deadline = Instant::now() + self.restore_deadline;
+        let config = self.config.clone();
+        let topic = self.topic.clone();
+        let (mut consumer, watermarks) = async_std::task::spawn_blocking(move || {
+            // librdkafka only assigns partitions to consumers with a group, which is never joined
+            let consumer: BaseConsumer = config
+                .consumer()
+                .set("group.id", format!("{}-restore", topic))
+                .set("enable.auto.commit", "false")
+                .set("enable.partition.eof", "false")
+                .set("isolation.level", "read_committed")
+                .create()?;
+            let watermarks = consumer.fetch_watermarks(&topic, partition, RESTORE_TIMEOUT);
+            Ok::<_, KafkaError>((consumer, watermarks))
+        })
+        .await?;
+
+        let (low, high) = match watermarks {
+            Ok(watermarks) => watermarks,
+            Err(KafkaError::MetadataFetch(RDKafkaErrorCode::UnknownPartition)) => {
+                warn!("Changelog {} has no partition {}", self.topic, partition);
+                return Ok(0);
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let start = self
+            .store
+            .changelog_offset(partition)?
+            .unwrap_or(low)
+            .max(low);
+        if start >= high {
+            return Ok(0);
+        }
+
+        info!(
+            "Restoring {} partition {} from offset {} to {}",
+            self.topic, partition, start, high
+        );
+
+        let mut assignment = TopicPartitionList::new();
+        assignment.add_partition_offset(&self.topic, partition, Offset::Offset(start))?;
+        consumer.assign(&assignment)?;
+
+        let mut applied = 0;
+        let mut position = start;
+        while position < high {
+            if Instant::now() >= deadline {
+                self.store.flush()?;
+                return Err(StateError::RestoreTimeout {
+                    topic: self.topic.clone(),
+                    partition,
+                    position,
+                    high,
+                });
+            }
+            let (returned, batch) = async_std::task::spawn_blocking(move || {
+                let batch = fetch(&consumer);
+                (consumer, batch)
+            })
+            .await;
+            consumer = returned;
+
+            let (messages, consumed) = batch?;
+            for message in messages {
+                if let Some(key) = message.key() {
+                    match message.payload() {
+                        Some(value) => self.store.put(key, value)?,
+                        None => self.store.delete(key)?,
+                    }
+                    applied += 1;
+                }
+                position = message.offset() + 1;
+                self.store.set_changelog_offset(partition, position)?;
+            }
+            position = position.max(consumed);
+            if let Some(health) = &self.health {
+                health.beat();
+            }
+        }
+
+        self.store.set_changelog_offset(partition, position)?;
+        self.store.flush()?;
+        info!(
+            "Restored {} records of {} partition {}",
+            applied, self.topic, partition
+        );
+        Ok(applied)
+    }
+}
+
+/// Poll up to [`RESTORE_BATCH`] records which are already fetched, waiting only for the first.
+/// Also returns the position of the consumer since compaction and transaction markers leave gaps.
+fn fetch(consumer: &BaseConsumer) -> KafkaResult<(Vec<OwnedMessage>, i64)> {
+    let mut messages = Vec::new();
+    while messages.len() < RESTORE_BATCH {
+        let timeout = if messages.is_empty() {
+            POLL_TIMEOUT
+        } else {
+            Duration::ZERO
+        };
+        match consumer.poll(timeout) {
+            Some(Ok(message)) => messages.push(message.detach()),
+            Some(Err(error)) => return Err(error),
+            None => break,
+        }
+    }
+    let mut position = 0;
+    for elem in consumer.position()?.elements() {
+        if let Offset::Offset(offset) = elem.offset() {
+            position = position.max(offset);
+        }
+    }
+    Ok((messages, position))
+}
+
+/// Create a compacted changelog topic with as many partitions as `source` unless it exists already
+pub async fn create_changelog_topic(
+    config: &KafkaConfig,
+    source: &str,
+    topic: &str,
+    replication: i32,
+) -> StateResult<()> {
+    create_copartitioned_topics(
+        config,
+        source,
+        &[topic],
+        replication,
+        &[("cleanup.policy", "compact")],
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::create_mock_cluster;
+
+    fn test_store<S: KeyValueStore>(store: S) {
+        assert_eq!(store.get(b"a").unwrap(), None);
+        store.put(b"a", b"1").unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        store.delete(b"a").unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+
+        assert_eq!(store.changelog_offset(3).unwrap(), None);
+        store.set_changelog_offset(3, 42).unwrap();
+        assert_eq!(store.changelog_offset(3).unwrap(), Some(42));
+        assert_eq!(store.changelog_offset(2).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sled_store() {
+        test_store(SledStore::temporary().unwrap());
+    }
+
+    #[test]
+    fn test_memory_store() {
+        test_store(MemoryStore::default());
+    }
+
+    /// A store which is full
+    struct FullStore;
+
+    impl KeyValueStore for FullStore {
+        fn get(&self, _key: &[u8]) -> StateResult<Option<Vec<u8>>> {
+            Ok(None)
+        }
+
+        fn put(&self, _key: &[u8], _value: &[u8]) -> StateResult<()> {
+            Err(sled::Error::Unsupported("full".to_owned()).into())
+        }
+
+        fn delete(&self, _key: &[u8]) -> StateResult<()> {
+            Ok(())
+        }
+
+        fn changelog_offset(&self, _partition: i32) -> StateResult<Option<i64>> {
+            Ok(None)
+        }
+
+        fn set_changelog_offset(&self, _partition: i32, _offset: i64) -> StateResult<()> {
+            Ok(())
+        }
+
+        fn flush(&self) -> StateResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failed_commit() {
+        let store =
+            ChangelogStore::new(FullStore, &KafkaConfig::default(), "events-state").transactional();
+        store.restored.borrow_mut().insert(1);
+        store
+            .stage(PendingWrite {
+                partition: 1,
+                key: b"a".to_vec(),
+                value: Some(b"1".to_vec()),
+                offset: 7,
+            })
+            .unwrap();
+
+        assert!(store.commit().is_err());
+        // the committed write is restored from the changelog instead
+        assert!(store.restored.borrow().is_empty());
+        assert!(store.pending.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_restore() {
+        let (producer, kafka) = create_mock_cluster::<AsyncStdRuntime>();
+
+        async_std::task::block_on(async {
+            let written = ChangelogStore::new(MemoryStore::default(), &kafka, "events-state");
+            written.put(&producer, 0, b"a", b"1").await.unwrap();
+            written.put(&producer, 0, b"b", b"2").await.unwrap();
+            written.delete(&producer, 0, b"a").await.unwrap();
+
+            let store = ChangelogStore::new(MemoryStore::default(), &kafka, "events-state")
+                .restore_deadline(Duration::ZERO);
+            assert!(matches!(
+                store.restore(0).await,
+                Err(StateError::RestoreTimeout {
+                    position: 0,
+                    high: 3,
+                    ..
+                })
+            ));
+
+            // a long restore keeps the worker live
+            let health = Health::new(Duration::from_millis(100), 0);
+            health.beat();
+            std::thread::sleep(Duration::from_millis(150));
+            assert!(health.live().is_err());
+            let store = ChangelogStore::new(MemoryStore::default(), &kafka, "events-state")
+                .heartbeat(health.clone());
+            assert_eq!(store.restore(0).await.unwrap(), 3);
+            assert!(health.live().is_ok());
+            assert_eq!(store.get(b"a").unwrap(), None);
+            assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(store.store.changelog_offset(0).unwrap(), Some(3));
+            // nothing left to restore
+            assert_eq!(store.restore(0).await.unwrap(), 0);
+        });
+    }
+}
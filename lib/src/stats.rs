@@ -0,0 +1,231 @@
+use std::fmt::Write;
+
+use rdkafka::statistics::{Partition, Statistics};
+
+use crate::metrics::metrics;
+
+fn is_consumer(statistics: &Statistics) -> bool {
+    statistics.client_type == "consumer"
+}
+
+/// The partitions a producer produces to or a consumer is assigned. librdkafka also
+/// reports the internal unassigned partition `-1` and, for consumers, partitions of
+/// topics it only knows the metadata of.
+fn partitions(statistics: &Statistics) -> impl Iterator<Item = (&str, &Partition)> {
+    let consumer = is_consumer(statistics);
+    statistics.topics.values().flat_map(move |topic| {
+        topic
+            .partitions
+            .values()
+            .filter(move |partition| partition.partition >= 0 && (partition.desired || !consumer))
+            .map(move |partition| (topic.topic.as_str(), partition))
+    })
+}
+
+/// Summed consumer lag of the assigned partitions, partitions with an unknown lag
+/// (`-1`) are ignored
+pub fn consumer_lag(statistics: &Statistics) -> i64 {
+    partitions(statistics)
+        .map(|(_, partition)| partition.consumer_lag.max(0))
+        .sum()
+}
+
+/// Update the `zeou_rdkafka_*` metrics of the client the statistics were emitted by
+pub fn record(statistics: &Statistics) {
+    let metrics = metrics();
+    let client = statistics.name.as_str();
+
+    metrics
+        .rdkafka_messages
+        .with_label_values(&[client, "tx"])
+        .set(statistics.txmsgs);
+    metrics
+        .rdkafka_messages
+        .with_label_values(&[client, "rx"])
+        .set(statistics.rxmsgs);
+    metrics
+        .rdkafka_queue
+        .with_label_values(&[client, "msg"])
+        .set(statistics.msg_cnt as i64);
+    metrics
+        .rdkafka_queue
+        .with_label_values(&[client, "reply"])
+        .set(statistics.replyq);
+
+    for broker in statistics.brokers.values() {
+        let name = broker.name.as_str();
+        if let Some(rtt) = &broker.rtt {
+            // librdkafka reports latencies in microseconds
+            metrics
+                .rdkafka_broker_rtt
+                .with_label_values(&[client, name])
+                .set(rtt.avg as f64 / 1_000_000.0);
+        }
+        metrics
+            .rdkafka_broker_requests
+            .with_label_values(&[client, name, "outbuf"])
+            .set(broker.outbuf_cnt);
+        metrics
+            .rdkafka_broker_requests
+            .with_label_values(&[client, name, "waitresp"])
+            .set(broker.waitresp_cnt);
+    }
+
+    for (topic, partition) in partitions(statistics) {
+        let id = partition.partition.to_string();
+        metrics
+            .rdkafka_partition_queue
+            .with_label_values(&[client, topic, &id, "fetch"])
+            .set(partition.fetchq_cnt);
+        metrics
+            .rdkafka_partition_queue
+            .with_label_values(&[client, topic, &id, "msg"])
+            .set(partition.msgq_cnt);
+        if !is_consumer(statistics) {
+            continue;
+        }
+        metrics
+            .rdkafka_partition_lag
+            .with_label_values(&[client, topic, &id])
+            .set(partition.consumer_lag);
+        metrics
+            .rdkafka_partition_fetching
+            .with_label_values(&[client, topic, &id])
+            .set((partition.fetch_state == "active") as i64);
+    }
+}
+
+/// One line describing the client, e.g.
+/// `rdkafka#consumer-1: rx 120 tx 0 msgs, queued 0 msgs 0 replies, rtt kafka:9092/1 1.2ms, lag 37`
+pub fn summary(statistics: &Statistics) -> String {
+    let mut summary = format!(
+        "{}: rx {} tx {} msgs, queued {} msgs {} replies",
+        statistics.name,
+        statistics.rxmsgs,
+        statistics.txmsgs,
+        statistics.msg_cnt,
+        statistics.replyq
+    );
+    let mut brokers = statistics
+        .brokers
+        .values()
+        .filter_map(|broker| broker.rtt.as_ref().map(|rtt| (&broker.name, rtt.avg)))
+        .filter(|(_, avg)| *avg > 0)
+        .collect::<Vec<_>>();
+    brokers.sort();
+    for (name, avg) in brokers {
+        let _ = write!(summary, ", rtt {} {:.1}ms", name, avg as f64 / 1000.0);
+    }
+    if is_consumer(statistics) {
+        let _ = write!(summary, ", lag {}", consumer_lag(statistics));
+    }
+    summary
+}
+
+/// Describe the partitions which are not fetched, e.g. because their leader is unavailable
+pub fn stalled_partitions(statistics: &Statistics) -> Vec<String> {
+    if !is_consumer(statistics) {
+        return Vec::new();
+    }
+    let mut stalled = partitions(statistics)
+        .filter(|(_, partition)| partition.fetch_state != "active")
+        .map(|(topic, partition)| {
+            format!(
+                "{}/{} ({})",
+                topic, partition.partition, partition.fetch_state
+            )
+        })
+        .collect::<Vec<_>>();
+    stalled.sort();
+    stalled
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rdkafka::statistics::{Broker, Topic, Window};
+
+    use super::*;
+
+    fn partition(id: i32, fetch_state: &str, consumer_lag: i64) -> (i32, Partition) {
+        let partition = Partition {
+            partition: id,
+            desired: id >= 0,
+            fetch_state: fetch_state.to_owned(),
+            consumer_lag,
+            ..Partition::default()
+        };
+        (id, partition)
+    }
+
+    fn statistics() -> Statistics {
+        let topic = Topic {
+            topic: "events".to_owned(),
+            partitions: HashMap::from([
+                partition(-1, "none", -1),
+                partition(0, "active", 30),
+                partition(1, "active", 7),
+                partition(2, "offset-query", -1),
+            ]),
+            ..Topic::default()
+        };
+        let broker = Broker {
+            name: "kafka:9092/1".to_owned(),
+            rtt: Some(Window {
+                avg: 1200,
+                ..Window::default()
+            }),
+            ..Broker::default()
+        };
+        Statistics {
+            name: "rdkafka#consumer-1".to_owned(),
+            client_type: "consumer".to_owned(),
+            rxmsgs: 120,
+            topics: HashMap::from([("events".to_owned(), topic)]),
+            brokers: HashMap::from([("kafka:9092/1".to_owned(), broker)]),
+            ..Statistics::default()
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let statistics = statistics();
+        assert_eq!(consumer_lag(&statistics), 37);
+        assert_eq!(
+            summary(&statistics),
+            "rdkafka#consumer-1: rx 120 tx 0 msgs, queued 0 msgs 0 replies, rtt kafka:9092/1 1.2ms, lag 37"
+        );
+        assert_eq!(
+            stalled_partitions(&statistics),
+            vec!["events/2 (offset-query)"]
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        record(&statistics());
+        let metrics = metrics();
+        assert_eq!(
+            metrics
+                .rdkafka_partition_lag
+                .with_label_values(&["rdkafka#consumer-1", "events", "0"])
+                .get(),
+            30
+        );
+        assert_eq!(
+            metrics
+                .rdkafka_partition_fetching
+                .with_label_values(&["rdkafka#consumer-1", "events", "2"])
+                .get(),
+            0
+        );
+        assert_eq!(
+            metrics
+                .rdkafka_broker_rtt
+                .with_label_values(&["rdkafka#consumer-1", "kafka:9092/1"])
+                .get(),
+            0.0012
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer, TracerProvider};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use rdkafka::message::{Headers, Message, OwnedHeaders};
+
+/// Reads the W3C trace context from the headers of a record
+struct HeaderExtractor<'a, H>(&'a H);
+
+impl<H: Headers> Extractor for HeaderExtractor<'_, H> {
+    fn get(&self, key: &str) -> Option<&str> {
+        // the last header wins, records copied by the retry and dead letter
+        // topics might carry more than one
+        (0..self.0.count())
+            .rev()
+            .filter_map(|idx| self.0.get(idx))
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .and_then(|(_, value)| std::str::from_utf8(value).ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        (0..self.0.count())
+            .filter_map(|idx| self.0.get(idx))
+            .map(|(name, _)| name)
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct HeaderInjector(Vec<(String, String)>);
+
+impl Injector for HeaderInjector {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.push((key.to_owned(), value));
+    }
+}
+
+/// Export the spans of handled messages to the OTLP/HTTP collector at `endpoint`
+/// (example: 'http://localhost:4318'). Keep the provider until shutdown and shut
+/// it down to flush the pending spans.
+pub fn init(endpoint: &str, service_name: &str) -> Result<SdkTracerProvider, String> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(service_name.to_owned())
+                .build(),
+        )
+        .build();
+    global::set_tracer_provider(provider.clone());
+    Ok(provider)
+}
+
+/// The trace context of the producer of `message`, if its headers carry a `traceparent`
+pub fn extract<M: Message>(message: &M) -> Context {
+    match message.headers() {
+        Some(headers) => TraceContextPropagator::new()
+            .extract_with_context(&Context::new(), &HeaderExtractor(headers)),
+        None => Context::new(),
+    }
+}
+
+/// A context with a consumer span for handling `message`, child of the trace
+/// context in its headers. Without [`init`] the span isn't recorded but the
+/// incoming trace context is still passed on.
+pub fn message<M: Message>(message: &M) -> Context {
+    let parent = extract(message);
+    let tracer = global::tracer_provider().tracer("zeou");
+    let span = tracer
+        .span_builder(format!("{} process", message.topic()))
+        .with_kind(SpanKind::Consumer)
+        .with_attributes([
+            KeyValue::new("messaging.system", "kafka"),
+            KeyValue::new("messaging.operation.type", "process"),
+            KeyValue::new("messaging.destination.name", message.topic().to_owned()),
+            KeyValue::new(
+                "messaging.destination.partition.id",
+                message.partition().to_string(),
+            ),
+            KeyValue::new("messaging.kafka.offset", message.offset()),
+        ])
+        .start_with_context(&tracer, &parent);
+    parent.with_span(span)
+}
+
+/// End the span of `context`, marking it failed with `error`
+pub fn end<E: ToString>(context: &Context, error: Option<E>) {
+    let span = context.span();
+    if let Some(error) = error {
+        span.set_status(Status::error(error.to_string()));
+    }
+    span.end();
+}
+
+/// The hex trace id of `context`, `None` outside of a trace
+pub fn trace_id(context: &Context) -> Option<String> {
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
+
+/// `headers` with the trace context of `context`, replacing the one they carry
+pub fn inject(headers: Option<OwnedHeaders>, context: &Context) -> Option<OwnedHeaders> {
+    let propagator = TraceContextPropagator::new();
+    let mut injector = HeaderInjector::default();
+    propagator.inject_context(context, &mut injector);
+    if injector.0.is_empty() {
+        return headers;
+    }
+
+    let fields = propagator.fields().collect::<Vec<_>>();
+    let mut injected = OwnedHeaders::new();
+    if let Some(headers) = &headers {
+        for (name, value) in (0..headers.count()).filter_map(|idx| headers.get(idx)) {
+            if !fields.iter().any(|field| field.eq_ignore_ascii_case(name)) {
+                injected = injected.add(name, value);
+            }
+        }
+    }
+    for (name, value) in &injector.0 {
+        injected = injected.add(name.as_str(), value.as_str());
+    }
+    Some(injected)
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry_sdk::trace::InMemorySpanExporter;
+    use rdkafka::message::{OwnedMessage, Timestamp};
+
+    use super::*;
+    use crate::headers::header;
+
+    const TRACE_ID: &str = "0af7651916cd43dd8448eb211c80319c";
+    const SPAN_ID: &str = "b7ad6b7169203331";
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn test_propagation() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        global::set_tracer_provider(provider);
+
+        let headers = OwnedHeaders::new()
+            .add("retry-attempt", "1")
+            .add("traceparent", TRACEPARENT);
+        let record = OwnedMessage::new(
+            Some(b"{}".to_vec()),
+            None,
+            "events".to_owned(),
+            Timestamp::NotAvailable,
+            0,
+            42,
+            Some(headers.clone()),
+        );
+
+        let context = message(&record);
+        assert_eq!(trace_id(&context).as_deref(), Some(TRACE_ID));
+
+        // the produced record replaces the copied traceparent and keeps the other headers
+        let injected = inject(Some(headers), &context).unwrap();
+        let extractor = HeaderExtractor(&injected);
+        let keys = extractor.keys();
+        assert_eq!(keys.iter().filter(|key| **key == "traceparent").count(), 1);
+        assert_eq!(header(&injected, "retry-attempt"), Some("1"));
+
+        // the span of the message continues the trace with a new span id
+        end(&context, None::<String>);
+        let spans = exporter.get_finished_spans().unwrap();
+        assert_eq!(spans.len(), 1);
+        let span_context = &spans[0].span_context;
+        assert_eq!(span_context.trace_id().to_string(), TRACE_ID);
+        assert_ne!(span_context.span_id().to_string(), SPAN_ID);
+        assert_eq!(spans[0].parent_span_id.to_string(), SPAN_ID);
+        assert_eq!(
+            header(&injected, "traceparent"),
+            Some(format!("00-{}-{}-01", TRACE_ID, span_context.span_id()).as_str())
+        );
+
+        // records produced outside of a trace are left alone
+        assert_eq!(
+            trace_id(&extract(&OwnedMessage::new(
+                None,
+                None,
+                "events".to_owned(),
+                Timestamp::NotAvailable,
+                0,
+                0,
+                None,
+            ))),
+            None
+        );
+        assert!(inject(None, &Context::new()).is_none());
+    }
+}
@@ -0,0 +1,36 @@
+use rdkafka::consumer::stream_consumer::StreamConsumer;
+use rdkafka::producer::FutureProducer;
+use rdkafka::util::TokioRuntime;
+
+use crate::client;
+use crate::config::KafkaConfig;
+use crate::context::CustomContext;
+
+pub use crate::client::{commit_transaction, send, TRANSACTION_TIMEOUT};
+
+pub fn create_consumer(
+    config: &KafkaConfig,
+    group_id: &str,
+) -> StreamConsumer<CustomContext, TokioRuntime> {
+    client::create_consumer(config, group_id)
+}
+
+/// A consumer which only sees records of committed transactions
+pub fn create_read_committed_consumer(
+    config: &KafkaConfig,
+    group_id: &str,
+) -> StreamConsumer<CustomContext, TokioRuntime> {
+    client::create_read_committed_consumer(config, group_id)
+}
+
+pub fn create_producer(config: &KafkaConfig) -> FutureProducer<CustomContext, TokioRuntime> {
+    client::create_producer(config)
+}
+
+/// A producer with initialized transactions, see [`client::create_transactional_producer`]
+pub fn create_transactional_producer(
+    config: &KafkaConfig,
+    transactional_id: &str,
+) -> FutureProducer<CustomContext, TokioRuntime> {
+    client::create_transactional_producer(config, transactional_id)
+}
@@ -1,27 +1,102 @@
 use std::io::Write;
+use std::str::FromStr;
 use std::thread;
+use std::time::Duration;
 
 use chrono::prelude::*;
+use clap::Arg;
 use env_logger::fmt::Formatter;
 use env_logger::Builder;
 use log::{LevelFilter, Record};
+use opentelemetry::Context;
+use serde_json::{Map, Value};
 
-pub fn setup_logger(log_thread: bool, rust_log: Option<&String>) {
+use crate::log_context::LogContext;
+
+/// How log records are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// a human readable line per record
+    Text,
+    /// a JSON object per line carrying the fields of the [`LogContext`]
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+/// The `--log-format` argument selecting the [`LogFormat`] of [`setup_logger`]
+pub fn log_format_arg() -> Arg {
+    Arg::new("log-format")
+        .long("log-format")
+        .value_name("FORMAT")
+        .help("text or json (a JSON object per line including the message being handled)")
+        .env("ZEOU_LOG_FORMAT")
+        .value_parser(|format: &str| format.parse::<LogFormat>())
+        .default_value("text")
+        .global(true)
+}
+
+fn thread_name() -> String {
+    thread::current().name().unwrap_or("unknown").to_owned()
+}
+
+/// A log record as JSON object, fields of the context don't override the record's own
+fn json_record(record: &Record, thread: Option<String>, fields: &Map<String, Value>) -> Value {
+    let mut object = Map::new();
+    object.insert(
+        "timestamp".to_owned(),
+        Value::String(Local::now().to_rfc3339_opts(SecondsFormat::Millis, false)),
+    );
+    object.insert(
+        "level".to_owned(),
+        Value::String(record.level().to_string()),
+    );
+    object.insert(
+        "target".to_owned(),
+        Value::String(record.target().to_owned()),
+    );
+    if let Some(thread) = thread {
+        object.insert("thread".to_owned(), Value::String(thread));
+    }
+    object.insert(
+        "message".to_owned(),
+        Value::String(record.args().to_string()),
+    );
+    for (key, value) in fields {
+        object.entry(key.clone()).or_insert_with(|| value.clone());
+    }
+    Value::Object(object)
+}
+
+pub fn setup_logger(log_thread: bool, rust_log: Option<&String>, format: LogFormat) {
     let output_format = move |formatter: &mut Formatter, record: &Record| {
+        if format == LogFormat::Json {
+            let thread = log_thread.then(thread_name);
+            let line = json_record(record, thread, &LogContext::fields_of(&Context::current()));
+            return writeln!(formatter, "{}", line);
+        }
+
         let thread_name = if log_thread {
-            format!(
-                "(thread: {}) ",
-                thread::current().name().unwrap_or("unknown")
-            )
+            format!("(thread: {}) ", thread_name())
         } else {
             "".to_string()
         };
 
         let local_time: DateTime<Local> = Local::now();
         let time_str = local_time.format("%H:%M:%S%.3f").to_string();
-        write!(
+        writeln!(
             formatter,
-            "{} {}{} - {} - {}\n",
+            "{} {}{} - {} - {}",
             time_str,
             thread_name,
             record.level(),
@@ -39,3 +114,48 @@ pub fn setup_logger(log_thread: bool, rust_log: Option<&String>) {
 
     builder.init();
 }
+
+/// Parse a duration like `250ms`, `30s`, `5m` or `1h`
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("missing unit in duration: {}", s))?;
+    let (value, unit) = s.split_at(split);
+    let value = value
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 60 * 60)),
+        _ => Err(format!("unknown unit in duration: {}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_record() {
+        let context = LogContext::default()
+            .with("offset", 42)
+            .with("message", "ignored");
+        let line = json_record(
+            &Record::builder()
+                .args(format_args!("Handled {}", "createEvent"))
+                .level(log::Level::Info)
+                .target("zeou::events")
+                .build(),
+            None,
+            context.fields(),
+        );
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], "zeou::events");
+        assert_eq!(line["message"], "Handled createEvent");
+        assert_eq!(line["offset"], 42);
+        assert!(line.get("thread").is_none());
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+    }
+}
@@ -1,62 +1,78 @@
 use clap::{Command, Arg};
 use log::{info, warn};
 
-use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
 use rdkafka::consumer::stream_consumer::StreamConsumer;
 use rdkafka::consumer::{CommitMode, Consumer};
-use rdkafka::message::Message;
+use rdkafka::message::{Headers, Message};
 use rdkafka::util::get_rdkafka_version;
 
-use lib::utils::setup_logger;
+use lib::avro::{self, AvroCodec};
+use lib::codec::{CodecError, Codecs};
+use lib::config::{self, KafkaConfig};
+use lib::tokio::create_consumer;
+use lib::utils::{log_format_arg, setup_logger, LogFormat};
 use lib::context::CustomContext;
+use lib::headers::MessageHeaders;
+use lib::log_context::LogContext;
+use lib::shutdown::Shutdown;
 
 /// https://github.com/fede1024/rust-rdkafka/blob/master/examples/simple_consumer.rs
-
 // A type alias with your custom consumer can be created for convenience.
 type LoggingConsumer = StreamConsumer<CustomContext>;
 
-async fn consume(brokers: &str, group_id: &str, topics: &str) {
-    let context = CustomContext;
-
-    let consumer: LoggingConsumer = ClientConfig::new()
-        .set("group.id", group_id)
-        .set("bootstrap.servers", brokers)
-        .set("enable.partition.eof", "false")
-        .set("session.timeout.ms", "6000")
-        .set("enable.auto.commit", "true")
-        //.set("statistics.interval.ms", "30000")
-        //.set("auto.offset.reset", "smallest")
-        .set_log_level(RDKafkaLogLevel::Debug)
-        .create_with_context(context)
-        .expect("Consumer creation failed");
+async fn consume(config: &KafkaConfig, group_id: &str, topics: &str, codecs: Codecs) {
+    let consumer: LoggingConsumer = create_consumer(config, group_id);
 
     consumer
-        .subscribe(&[&topics])
+        .subscribe(&[topics])
         .expect("Can't subscribe to specified topics");
 
+    let shutdown = Shutdown::install().expect("Failed to install signal handlers");
+    let requested = shutdown.wait();
+    tokio::pin!(requested);
+
     loop {
-        match consumer.recv().await {
+        let received = tokio::select! {
+            _ = &mut requested => break,
+            received = consumer.recv() => received,
+        };
+        match received {
             Err(e) => warn!("Kafka error: {}", e),
             Ok(m) => {
-                let payload = match m.payload_view::<str>() {
-                    None => "",
-                    Some(Ok(s)) => s,
-                    Some(Err(e)) => {
-                        warn!("Error while deserializing message payload: {:?}", e);
-                        ""
-                    }
+                let _log_context = LogContext::message(&m).with("group_id", group_id).enter();
+                let payload = match m.payload() {
+                    None => String::new(),
+                    // resolving an Avro schema might block on the schema registry
+                    Some(bytes) => match tokio::task::block_in_place(|| codecs.decode(m.topic(), bytes)) {
+                        Ok(value) => value.to_string(),
+                        // text which isn't JSON
+                        Err(CodecError::Json(_)) => String::from_utf8_lossy(bytes).into_owned(),
+                        Err(e) => {
+                            warn!("Error while decoding message payload: {}", e);
+                            String::new()
+                        }
+                    },
                 };
                 info!("key: '{:?}', payload: '{}', topic: {}, partition: {}, offset: {}, timestamp: {:?}",
                       m.key(), payload, m.topic(), m.partition(), m.offset(), m.timestamp());
-                // if let Some(headers) = m.headers() {
-                //     for header in headers.iter() {
-                //         info!("  Header {:#?}: {:?}", header.key, header.value);
-                //     }
-                // }
+                let headers = MessageHeaders::read(&m);
+                info!("message id: {}, correlation id: {}, causation id: {:?}, command: {:?}, producer: {:?}, schema version: {:?}",
+                      headers.message_id, headers.correlation_id(), headers.causation_id, headers.command, headers.producer_id, headers.schema_version);
+                if let Some(headers) = m.headers() {
+                    for (key, value) in (0..headers.count()).filter_map(|idx| headers.get(idx)) {
+                        info!("  Header {}: {}", key, String::from_utf8_lossy(value));
+                    }
+                }
                 consumer.commit_message(&m, CommitMode::Async).unwrap();
             }
         };
     }
+
+    info!("Shutting down");
+    if let Err(e) = consumer.commit_consumer_state(CommitMode::Sync) {
+        warn!("Unable to commit final offsets: {}", e);
+    }
+    consumer.unsubscribe();
 }
 
 #[tokio::main]
@@ -90,16 +106,31 @@ async fn main() {
                 .help("Topic list")
                 .required(true),
         )
+        .arg(avro::registry_arg())
+        .args(config::args())
+        .arg(log_format_arg())
         .get_matches();
 
-    setup_logger(true, matches.get_one::<String>("log-conf"));
+    setup_logger(
+        true,
+        matches.get_one::<String>("log-conf"),
+        *matches.get_one::<LogFormat>("log-format").unwrap(),
+    );
 
     let (version_n, version_s) = get_rdkafka_version();
     info!("rd_kafka_version: 0x{:08x}, {}", version_n, version_s);
 
     let topics = matches.get_one::<String>("topics").unwrap();
-    let brokers = matches.get_one::<String>("brokers").unwrap();
     let group_id = matches.get_one::<String>("group-id").unwrap();
+    let config = KafkaConfig::from_matches(&matches).expect("Invalid configuration");
+
+    let avro = matches
+        .get_one::<String>("schema-registry")
+        .map(|location| AvroCodec::new(avro::registry(location)));
+    let mut codecs = Codecs::from_specs(config.codecs(), avro.as_ref()).expect("Invalid codecs");
+    if let Some(avro) = avro {
+        codecs = codecs.with_avro(avro);
+    }
 
-    consume(brokers, group_id, &topics).await
+    consume(&config, group_id, topics, codecs).await
 }
@@ -0,0 +1,303 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use lib::backup::{BackupHeader, Data};
+use lib::codec::{Codec, CodecError};
+
+/// How the lines of the input are turned into records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// every line is the payload of a record without key
+    Raw,
+    /// every line is a JSON object with `key`, `headers`, `payload` (or its decoded
+    /// `value`) and `partition`, backup files of `zeou backup` are valid input.
+    /// The partition is only used with `--keep-partitions`.
+    Jsonl,
+    /// every line is a JSON object with a `request_id`, which becomes the key.
+    /// The line is the payload.
+    Requests,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Format::Raw),
+            "jsonl" => Ok(Format::Jsonl),
+            "requests" => Ok(Format::Requests),
+            _ => Err(format!("unknown format: {}", s)),
+        }
+    }
+}
+
+/// Where the key of a record comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyMode {
+    None,
+    /// the key given by the input format
+    Record,
+    /// a random key, spreading records over all partitions
+    Random,
+    /// a top-level field of the JSON payload
+    Field(String),
+}
+
+impl FromStr for KeyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(KeyMode::None),
+            "record" => Ok(KeyMode::Record),
+            "random" => Ok(KeyMode::Random),
+            _ => match s.strip_prefix("field:") {
+                Some(field) if !field.is_empty() => Ok(KeyMode::Field(field.to_owned())),
+                _ => Err(format!("unknown key mode: {}", s)),
+            },
+        }
+    }
+}
+
+/// A record ready to be produced
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Record {
+    pub key: Option<Vec<u8>>,
+    pub payload: Option<Vec<u8>>,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub partition: Option<i32>,
+}
+
+#[derive(Debug)]
+pub enum InputError {
+    Json(serde_json::Error),
+    Base64(base64::DecodeError),
+    MissingRequestId,
+    /// the payload has no such field or it is not a string or number
+    MissingKeyField(String),
+    Codec(CodecError),
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::Json(error) => write!(f, "invalid JSON: {}", error),
+            InputError::Base64(error) => write!(f, "invalid base64: {}", error),
+            InputError::MissingRequestId => write!(f, "no request_id"),
+            InputError::MissingKeyField(field) => write!(f, "no key field {}", field),
+            InputError::Codec(error) => write!(f, "unable to encode payload: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for InputError {}
+
+impl From<serde_json::Error> for InputError {
+    fn from(error: serde_json::Error) -> Self {
+        InputError::Json(error)
+    }
+}
+
+impl From<base64::DecodeError> for InputError {
+    fn from(error: base64::DecodeError) -> Self {
+        InputError::Base64(error)
+    }
+}
+
+/// The payload of a JSONL record: text or base64 encoded bytes like in backups,
+/// any other JSON is produced serialized
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Data(Data),
+    Json(Value),
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonlRecord {
+    #[serde(default)]
+    key: Option<Data>,
+    #[serde(default)]
+    payload: Option<Payload>,
+    /// a payload decoded by `zeou backup --decode`
+    #[serde(default)]
+    value: Option<Value>,
+    #[serde(default)]
+    headers: Vec<BackupHeader>,
+    #[serde(default)]
+    partition: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    request_id: Option<String>,
+}
+
+/// Parse one line of the input
+pub fn parse(format: Format, line: &str) -> Result<Record, InputError> {
+    match format {
+        Format::Raw => Ok(Record {
+            payload: Some(line.as_bytes().to_vec()),
+            ..Record::default()
+        }),
+        Format::Jsonl => {
+            let record = serde_json::from_str::<JsonlRecord>(line)?;
+            let payload = match (record.value, record.payload) {
+                (Some(value), _) | (None, Some(Payload::Json(value))) => {
+                    Some(value.to_string().into_bytes())
+                }
+                (None, Some(Payload::Data(data))) => Some(data.to_bytes()?),
+                (None, None) => None,
+            };
+            let headers = record
+                .headers
+                .iter()
+                .map(|header| Ok((header.key.clone(), header.value.to_bytes()?)))
+                .collect::<Result<Vec<_>, InputError>>()?;
+            Ok(Record {
+                key: record.key.map(|key| key.to_bytes()).transpose()?,
+                payload,
+                headers,
+                partition: record.partition,
+            })
+        }
+        Format::Requests => {
+            let request = serde_json::from_str::<Request>(line)?;
+            let id = request.request_id.ok_or(InputError::MissingRequestId)?;
+            Ok(Record {
+                key: Some(id.into_bytes()),
+                payload: Some(line.as_bytes().to_vec()),
+                ..Record::default()
+            })
+        }
+    }
+}
+
+/// Replace the key of `record` according to `mode`
+pub fn apply_key(mode: &KeyMode, record: &mut Record) -> Result<(), InputError> {
+    match mode {
+        KeyMode::None => record.key = None,
+        KeyMode::Record => {}
+        KeyMode::Random => {
+            record.key = Some(format!("{:016x}", rand::random::<u64>()).into_bytes())
+        }
+        KeyMode::Field(field) => {
+            let value = record
+                .payload
+                .as_deref()
+                .and_then(|payload| serde_json::from_slice::<Value>(payload).ok());
+            let key = match value.as_ref().and_then(|value| value.get(field)) {
+                Some(Value::String(key)) => key.clone(),
+                Some(Value::Number(key)) => key.to_string(),
+                _ => return Err(InputError::MissingKeyField(field.clone())),
+            };
+            record.key = Some(key.into_bytes());
+        }
+    }
+    Ok(())
+}
+
+/// Encode the JSON payload of `record` with the codec of its topic
+pub fn encode(codec: &dyn Codec, topic: &str, record: &mut Record) -> Result<(), InputError> {
+    if let Some(payload) = &record.payload {
+        let value = serde_json::from_slice::<Value>(payload)?;
+        record.payload = Some(codec.encode(topic, &value).map_err(InputError::Codec)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::codec::MessagePackCodec;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_jsonl() {
+        let record = parse(
+            Format::Jsonl,
+            r#"{"key": "a", "payload": {"command": "createEvent"}, "headers": [{"key": "h", "value": {"base64": "AJ8="}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(record.key, Some(b"a".to_vec()));
+        assert_eq!(
+            record.payload,
+            Some(br#"{"command":"createEvent"}"#.to_vec())
+        );
+        assert_eq!(record.headers, vec![("h".to_owned(), vec![0, 159])]);
+        assert_eq!(record.partition, None);
+
+        // lines of a backup file
+        let record = parse(
+            Format::Jsonl,
+            r#"{"partition": 2, "offset": 7, "payload": "{\"command\":\"createEvent\"}"}"#,
+        )
+        .unwrap();
+        assert_eq!(record.key, None);
+        assert_eq!(
+            record.payload,
+            Some(br#"{"command":"createEvent"}"#.to_vec())
+        );
+        assert_eq!(record.partition, Some(2));
+
+        assert!(parse(Format::Jsonl, "not json").is_err());
+    }
+
+    #[test]
+    fn test_parse_requests() {
+        let line = r#"{"request_id": "user-001", "title": "t", "body": "b"}"#;
+        let record = parse(Format::Requests, line).unwrap();
+        assert_eq!(record.key, Some(b"user-001".to_vec()));
+        assert_eq!(record.payload, Some(line.as_bytes().to_vec()));
+
+        assert!(matches!(
+            parse(Format::Requests, r#"{"title": "t"}"#),
+            Err(InputError::MissingRequestId)
+        ));
+    }
+
+    #[test]
+    fn test_apply_key() {
+        let mut record = parse(Format::Raw, r#"{"id": 42, "name": "x"}"#).unwrap();
+        assert_eq!(record.key, None);
+
+        apply_key(&"field:id".parse().unwrap(), &mut record).unwrap();
+        assert_eq!(record.key, Some(b"42".to_vec()));
+        apply_key(&"field:name".parse().unwrap(), &mut record).unwrap();
+        assert_eq!(record.key, Some(b"x".to_vec()));
+        assert!(apply_key(&"field:missing".parse().unwrap(), &mut record).is_err());
+
+        apply_key(&KeyMode::None, &mut record).unwrap();
+        assert_eq!(record.key, None);
+        apply_key(&KeyMode::Random, &mut record).unwrap();
+        assert_eq!(record.key.map(|key| key.len()), Some(16));
+
+        assert!("field:".parse::<KeyMode>().is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut record = parse(
+            Format::Jsonl,
+            r#"{"partition": 0, "offset": 7, "value": {"command": "createEvent"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            record.payload,
+            Some(br#"{"command":"createEvent"}"#.to_vec())
+        );
+        encode(&MessagePackCodec, "events", &mut record).unwrap();
+        assert_eq!(
+            MessagePackCodec
+                .decode(record.payload.as_deref().unwrap())
+                .unwrap(),
+            serde_json::json!({"command": "createEvent"})
+        );
+
+        let mut record = parse(Format::Raw, "not json").unwrap();
+        assert!(encode(&MessagePackCodec, "events", &mut record).is_err());
+    }
+}
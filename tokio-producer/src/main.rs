@@ -0,0 +1,357 @@
+use std::process;
+use std::time::Duration;
+
+use clap::builder::RangedU64ValueParser;
+use clap::{Arg, ArgAction, Command};
+use futures::future::LocalBoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use log::{error, info, warn};
+use serde_json::json;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::time::{self, MissedTickBehavior};
+
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+
+use lib::avro::{self, AvroCodec};
+use lib::codec::Codecs;
+use lib::config::{self, KafkaConfig};
+use lib::context::CustomContext;
+use lib::tokio::create_producer;
+use lib::utils::{log_format_arg, setup_logger, LogFormat};
+
+mod input;
+
+use input::{Format, KeyMode, Record};
+
+/// How deliveries are reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Report {
+    None,
+    /// log the number of delivered and failed records at the end
+    Summary,
+    /// print a JSON line with partition and offset or the error of every record
+    Each,
+}
+
+struct Options {
+    topic: String,
+    format: Format,
+    key: KeyMode,
+    partition: Option<i32>,
+    /// produce records to the partition given by the input format
+    keep_partitions: bool,
+    /// records per second
+    rate: Option<u32>,
+    report: Report,
+    max_in_flight: usize,
+    /// encode the JSON payloads of topics with a codec of their own
+    codecs: Codecs,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    delivered: u64,
+    failed: u64,
+    invalid: u64,
+}
+
+/// line number and partition/offset or error of a produced record
+type Delivery = (usize, Result<(i32, i64), String>);
+
+impl Stats {
+    fn record(&mut self, report: Report, (line, result): Delivery) {
+        match &result {
+            Ok(_) => self.delivered += 1,
+            Err(error) => {
+                error!("Delivery of line {} failed: {}", line, error);
+                self.failed += 1;
+            }
+        }
+        if report == Report::Each {
+            let report = match result {
+                Ok((partition, offset)) => {
+                    json!({"line": line, "partition": partition, "offset": offset})
+                }
+                Err(error) => json!({"line": line, "error": error}),
+            };
+            println!("{}", report);
+        }
+    }
+}
+
+fn to_future_record<'a>(topic: &'a str, record: &'a Record) -> FutureRecord<'a, [u8], [u8]> {
+    let mut future_record = FutureRecord::to(topic);
+    if let Some(key) = &record.key {
+        future_record = future_record.key(key.as_slice());
+    }
+    if let Some(payload) = &record.payload {
+        future_record = future_record.payload(payload.as_slice());
+    }
+    if let Some(partition) = record.partition {
+        future_record = future_record.partition(partition);
+    }
+    if !record.headers.is_empty() {
+        let headers = record
+            .headers
+            .iter()
+            .fold(OwnedHeaders::new(), |headers, (key, value)| {
+                headers.add(key, value)
+            });
+        future_record = future_record.headers(headers);
+    }
+    future_record
+}
+
+async fn produce<I: AsyncBufRead + Unpin>(
+    producer: &FutureProducer<CustomContext>,
+    input: I,
+    options: &Options,
+) -> Stats {
+    let mut stats = Stats::default();
+    let mut in_flight = FuturesUnordered::<LocalBoxFuture<'static, Delivery>>::new();
+    let mut interval = options.rate.map(|rate| {
+        let mut interval = time::interval(Duration::from_secs(1) / rate);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+
+    // the schema is looked up once off the executor, encoding the records hits the cache
+    let avro_codec = options
+        .codecs
+        .get(&options.topic)
+        .and_then(|codec| codec.avro());
+    if let Some(codec) = avro_codec {
+        let subject = avro::value_subject(&options.topic);
+        if let Err(e) = codec.prefetch_latest(&subject).await {
+            warn!("Unable to fetch the schema of {}: {}", options.topic, e);
+        }
+    }
+
+    let mut lines = input.lines();
+    let mut line_number = 0;
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                error!("Error reading input: {}", e);
+                stats.invalid += 1;
+                break;
+            }
+        };
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut record = match input::parse(options.format, &line)
+            .and_then(|mut record| input::apply_key(&options.key, &mut record).map(|()| record))
+            .and_then(|mut record| match options.codecs.get(&options.topic) {
+                Some(codec) => input::encode(codec, &options.topic, &mut record).map(|()| record),
+                None => Ok(record),
+            }) {
+            Ok(record) => record,
+            Err(e) => {
+                error!("Skipping line {}: {}", line_number, e);
+                stats.invalid += 1;
+                continue;
+            }
+        };
+        if options.partition.is_some() || !options.keep_partitions {
+            record.partition = options.partition;
+        }
+
+        if let Some(interval) = interval.as_mut() {
+            interval.tick().await;
+        }
+        while in_flight.len() >= options.max_in_flight {
+            if let Some(delivery) = in_flight.next().await {
+                stats.record(options.report, delivery);
+            }
+        }
+
+        let mut future_record = to_future_record(&options.topic, &record);
+        loop {
+            match producer.send_result(future_record) {
+                Ok(delivery) => {
+                    let line = line_number;
+                    in_flight.push(
+                        delivery
+                            .map(move |result| {
+                                let result = match result {
+                                    Ok(Ok(delivered)) => Ok(delivered),
+                                    Ok(Err((e, _))) => Err(e.to_string()),
+                                    Err(_) => Err("delivery canceled".to_owned()),
+                                };
+                                (line, result)
+                            })
+                            .boxed_local(),
+                    );
+                    break;
+                }
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), returned)) => {
+                    // wait for deliveries to make room in the producer queue
+                    future_record = returned;
+                    match in_flight.next().await {
+                        Some(delivery) => stats.record(options.report, delivery),
+                        None => time::sleep(Duration::from_millis(10)).await,
+                    }
+                }
+                Err((e, _)) => {
+                    stats.record(options.report, (line_number, Err(e.to_string())));
+                    break;
+                }
+            }
+        }
+    }
+
+    while let Some(delivery) = in_flight.next().await {
+        stats.record(options.report, delivery);
+    }
+    stats
+}
+
+#[tokio::main]
+async fn main() {
+    let matches = Command::new("tokio-producer")
+        .version(option_env!("CARGO_PKG_VERSION").unwrap_or(""))
+        .about("Command line producer reading records from stdin or a file")
+        .arg(
+            Arg::new("brokers")
+                .short('b')
+                .long("brokers")
+                .help("Broker list in kafka format")
+                .env("ZEOU_BROKER")
+                .default_value("localhost:9092"),
+        )
+        .arg(
+            Arg::new("topic")
+                .short('t')
+                .long("topic")
+                .help("Topic to produce to")
+                .required(true),
+        )
+        .arg(
+            Arg::new("input")
+                .short('i')
+                .long("input")
+                .help("File to read records from, one per line (default: stdin)"),
+        )
+        .arg(
+            Arg::new("format")
+                .short('f')
+                .long("format")
+                .help("raw: a payload per line\njsonl: JSON objects with key, headers, payload and partition (e.g. backups)\nrequests: JSON objects keyed by their request_id")
+                .value_parser(["raw", "jsonl", "requests"])
+                .default_value("raw"),
+        )
+        .arg(
+            Arg::new("key")
+                .short('k')
+                .long("key")
+                .help("none, record (the key of the input format), random or field:<NAME> of the JSON payload")
+                .value_parser(|key: &str| key.parse::<KeyMode>())
+                .default_value("record"),
+        )
+        .arg(
+            Arg::new("partition")
+                .short('p')
+                .long("partition")
+                .help("Produce all records to this partition instead of using the partitioner")
+                .value_parser(clap::value_parser!(i32)),
+        )
+        .arg(
+            Arg::new("keep-partitions")
+                .long("keep-partitions")
+                .help("Produce jsonl records to the partition of their line, e.g. the original partition of a backup")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("partition"),
+        )
+        .arg(
+            Arg::new("rate")
+                .long("rate")
+                .help("Maximum number of records produced per second")
+                .value_parser(clap::value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .help("none, summary or each (a JSON line per delivered record on stdout)")
+                .value_parser(["none", "summary", "each"])
+                .default_value("summary"),
+        )
+        .arg(
+            Arg::new("max-in-flight")
+                .long("max-in-flight")
+                .help("Maximum number of records waiting for their delivery")
+                .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::new("log-conf")
+                .long("log-conf")
+                .help("Configure the logging format (example: 'rdkafka=trace')"),
+        )
+        .arg(avro::registry_arg())
+        .args(config::args())
+        .arg(log_format_arg())
+        .get_matches();
+
+    setup_logger(
+        true,
+        matches.get_one::<String>("log-conf"),
+        *matches.get_one::<LogFormat>("log-format").unwrap(),
+    );
+
+    let config = KafkaConfig::from_matches(&matches).expect("Invalid configuration");
+    let avro = matches
+        .get_one::<String>("schema-registry")
+        .map(|location| AvroCodec::new(avro::registry(location)));
+    let options = Options {
+        topic: matches.get_one::<String>("topic").unwrap().to_owned(),
+        format: matches
+            .get_one::<String>("format")
+            .unwrap()
+            .parse()
+            .unwrap(),
+        key: matches.get_one::<KeyMode>("key").unwrap().clone(),
+        partition: matches.get_one::<i32>("partition").copied(),
+        keep_partitions: matches.get_flag("keep-partitions"),
+        rate: matches.get_one::<u32>("rate").copied(),
+        report: match matches.get_one::<String>("report").unwrap().as_str() {
+            "none" => Report::None,
+            "each" => Report::Each,
+            _ => Report::Summary,
+        },
+        max_in_flight: *matches.get_one::<usize>("max-in-flight").unwrap(),
+        codecs: Codecs::from_specs(config.codecs(), avro.as_ref()).unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(1);
+        }),
+    };
+
+    let producer: FutureProducer<CustomContext> = create_producer(&config);
+    let stats = match matches.get_one::<String>("input") {
+        Some(path) if path != "-" => {
+            let file = tokio::fs::File::open(path).await.unwrap_or_else(|e| {
+                error!("Unable to open {}: {}", path, e);
+                process::exit(1);
+            });
+            produce(&producer, BufReader::new(file), &options).await
+        }
+        _ => produce(&producer, BufReader::new(tokio::io::stdin()), &options).await,
+    };
+
+    if options.report != Report::None {
+        info!(
+            "Produced {} records to {}, {} failed, {} invalid lines",
+            stats.delivered, options.topic, stats.failed, stats.invalid
+        );
+    }
+    if stats.failed > 0 || stats.invalid > 0 {
+        process::exit(1);
+    }
+}
@@ -1,22 +1,39 @@
+use std::net::SocketAddr;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use clap::{Command, Arg};
 use futures::stream::FuturesUnordered;
-use futures::{StreamExt, TryStreamExt};
-use log::info;
+use futures::StreamExt;
+use log::{info, warn};
+use opentelemetry::context::FutureExt;
+use opentelemetry::Context;
 
-use rdkafka::config::ClientConfig;
 use rdkafka::consumer::stream_consumer::StreamConsumer;
-use rdkafka::consumer::Consumer;
+use rdkafka::consumer::{CommitMode, Consumer};
 use rdkafka::message::{BorrowedMessage, OwnedMessage};
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
 use rdkafka::Message;
+use tokio::task::JoinSet;
+use tokio::time::{self, MissedTickBehavior};
 
-use lib::utils::setup_logger;
+use lib::config::{self, KafkaConfig};
+use lib::context::CustomContext;
+use lib::headers::MessageHeaders;
+use lib::health::{self, Health, Workers};
+use lib::log_context::LogContext;
+use lib::metrics::{self, metrics, Lag};
+use lib::shutdown::Shutdown;
+use lib::tokio::{create_consumer, create_producer, send};
+use lib::utils::{log_format_arg, parse_duration, setup_logger, LogFormat};
 
-/// https://github.com/fede1024/rust-rdkafka/blob/master/examples/asynchronous_processing.rs
+/// how long to wait for outstanding deliveries on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how often an idle event loop signals that it is alive
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
 
+/// https://github.com/fede1024/rust-rdkafka/blob/master/examples/asynchronous_processing.rs
 async fn record_borrowed_message_receipt(msg: &BorrowedMessage<'_>) {
     // Simulate some work that must be done in the same order as messages are
     // received; i.e., before truly parallel processing can begin.
@@ -30,7 +47,7 @@ async fn record_owned_message_receipt(_msg: &OwnedMessage) {
 }
 
 // Emulates an expensive, synchronous computation.
-fn expensive_computation<'a>(msg: OwnedMessage) -> String {
+fn expensive_computation(msg: OwnedMessage) -> String {
     info!("Starting expensive computation on message {}", msg.offset());
     thread::sleep(Duration::from_millis(rand::random::<u64>() % 5000));
     info!(
@@ -53,68 +70,117 @@ fn expensive_computation<'a>(msg: OwnedMessage) -> String {
 // the messages), while `tokio::task::spawn_blocking` is used to handle the
 // simulated CPU-bound task.
 async fn run_async_processor(
-    brokers: String,
+    config: KafkaConfig,
     group_id: String,
     input_topic: String,
     output_topic: String,
+    health: Health,
+    shutdown: Shutdown,
 ) {
     // Create the `StreamConsumer`, to receive the messages from the topic in form of a `Stream`.
-    let consumer: StreamConsumer = ClientConfig::new()
-        .set("group.id", &group_id)
-        .set("bootstrap.servers", &brokers)
-        .set("enable.partition.eof", "false")
-        .set("session.timeout.ms", "6000")
-        .set("enable.auto.commit", "false")
-        .create()
-        .expect("Consumer creation failed");
+    let consumer: StreamConsumer<CustomContext> = create_consumer(&config, &group_id);
 
     consumer
         .subscribe(&[&input_topic])
         .expect("Can't subscribe to specified topic");
 
     // Create the `FutureProducer` to produce asynchronously.
-    let producer: FutureProducer = ClientConfig::new()
-        .set("bootstrap.servers", &brokers)
-        .set("message.timeout.ms", "5000")
-        .create()
-        .expect("Producer creation error");
-
-    // Create the outer pipeline on the message stream.
-    let stream_processor = consumer.stream().try_for_each(|borrowed_message| {
+    let producer: FutureProducer<CustomContext> = create_producer(&config);
+
+    health.watch(consumer.context());
+    let mut heartbeat = time::interval(HEARTBEAT_INTERVAL);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let requested = shutdown.wait();
+    tokio::pin!(requested);
+    let mut stream = consumer.stream();
+    // spawned computations, awaited before shutting down
+    let mut tasks = JoinSet::new();
+    let mut lag = Lag::new(&config);
+
+    info!("Starting event loop");
+    loop {
+        health.beat();
+        let borrowed_message = tokio::select! {
+            _ = &mut requested => break,
+            _ = heartbeat.tick() => continue,
+            next = stream.next() => match next {
+                Some(Ok(borrowed_message)) => borrowed_message,
+                Some(Err(e)) => panic!("stream processing failed: {}", e),
+                None => break,
+            },
+        };
+        // forget about finished computations
+        while tasks.try_join_next().is_some() {}
+
         let producer = producer.clone();
+        let input_topic = input_topic.to_string();
         let output_topic = output_topic.to_string();
-        async move {
-            // Process each message
-            record_borrowed_message_receipt(&borrowed_message).await;
-            // Borrowed messages can't outlive the consumer they are received from, so they need to
-            // be owned in order to be sent to a separate thread.
-            let owned_message = borrowed_message.detach();
-            record_owned_message_receipt(&owned_message).await;
-            tokio::spawn(async move {
-                // The body of this block will be executed on the main thread pool,
-                // but we perform `expensive_computation` on a separate thread pool
-                // for CPU-intensive tasks via `tokio::task::spawn_blocking`.
-                let computation_result =
-                    tokio::task::spawn_blocking(|| expensive_computation(owned_message))
-                        .await
-                        .expect("failed to wait for expensive computation");
-                let produce_future = producer.send(
-                    FutureRecord::to(&output_topic)
-                        .key("some key")
-                        .payload(&computation_result),
-                    Duration::from_secs(0),
-                );
-                match produce_future.await {
-                    Ok(delivery) => println!("Sent: {:?}", delivery),
-                    Err((e, _)) => println!("Error: {:?}", e),
-                }
-            });
-            Ok(())
+        metrics().consumed.with_label_values(&[&input_topic]).inc();
+        if let Some(lag) = lag.observe(&borrowed_message) {
+            health.record_lag(&input_topic, borrowed_message.partition(), lag);
         }
-    });
+        let log_context = LogContext::message(&borrowed_message).with("group_id", group_id.as_str());
+        // Process each message
+        log_context
+            .clone()
+            .scope(record_borrowed_message_receipt(&borrowed_message))
+            .await;
+        // Borrowed messages can't outlive the consumer they are received from, so they need to
+        // be owned in order to be sent to a separate thread.
+        let owned_message = borrowed_message.detach();
+        record_owned_message_receipt(&owned_message).await;
+        // the output record is caused by the input record, one context carries its
+        // headers and the log fields
+        let headers = MessageHeaders::read(&owned_message).with_command("expensive_computation");
+        let context = log_context.attach_to(&Context::current()).with_value(headers);
+        let task = async move {
+            // The body of this block will be executed on the main thread pool,
+            // but we perform `expensive_computation` on a separate thread pool
+            // for CPU-intensive tasks via `tokio::task::spawn_blocking`.
+            let started = Instant::now();
+            let context = Context::current();
+            let computation_result = tokio::task::spawn_blocking(move || {
+                let _context = context.attach();
+                expensive_computation(owned_message)
+            })
+            .await
+            .expect("failed to wait for expensive computation");
+            metrics::observe_handler(&input_topic, "expensive_computation", started.elapsed());
+            let produce_future = send(
+                &producer,
+                FutureRecord::to(&output_topic)
+                    .key("some key")
+                    .payload(&computation_result),
+            );
+            match produce_future.await {
+                Ok(delivery) => {
+                    metrics()
+                        .processed
+                        .with_label_values(&[&input_topic, "expensive_computation", "handled"])
+                        .inc();
+                    println!("Sent: {:?}", delivery)
+                }
+                Err((e, _)) => {
+                    metrics()
+                        .failed
+                        .with_label_values(&[&input_topic, "produce"])
+                        .inc();
+                    println!("Error: {:?}", e)
+                }
+            }
+        };
+        tasks.spawn(task.with_context(context));
+    }
+    drop(stream);
 
-    info!("Starting event loop");
-    stream_processor.await.expect("stream processing failed");
+    info!("Draining {} in-flight messages", tasks.len());
+    while tasks.join_next().await.is_some() {}
+    producer.flush(SHUTDOWN_TIMEOUT);
+    if let Err(e) = consumer.commit_consumer_state(CommitMode::Sync) {
+        warn!("Unable to commit final offsets: {}", e);
+    }
+    consumer.unsubscribe();
     info!("Stream processing terminated");
 }
 
@@ -160,28 +226,74 @@ async fn main() {
             Arg::new("num-workers")
                 .long("num-workers")
                 .help("Number of workers")
+                .value_parser(clap::value_parser!(usize))
                 .default_value("1"),
         )
+        .arg(
+            Arg::new("metrics-addr")
+                .long("metrics-addr")
+                .help("Serve /metrics, /healthz and /readyz on this address (example: '0.0.0.0:9090')")
+                .env("ZEOU_METRICS_ADDR")
+                .value_parser(clap::value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("stuck-after")
+                .long("stuck-after")
+                .help("/healthz fails if the event loop is stuck for longer")
+                .env("ZEOU_STUCK_AFTER")
+                .value_parser(parse_duration)
+                .default_value("1m"),
+        )
+        .arg(
+            Arg::new("ready-lag")
+                .long("ready-lag")
+                .help("/readyz fails while a consumed partition lags behind by more records")
+                .env("ZEOU_READY_LAG")
+                .value_parser(clap::value_parser!(i64).range(0..))
+                .default_value("1000"),
+        )
+        .args(config::args())
+        .arg(log_format_arg())
         .get_matches();
 
-    setup_logger(true, matches.get_one::<String>("log-conf"));
+    setup_logger(
+        true,
+        matches.get_one::<String>("log-conf"),
+        *matches.get_one::<LogFormat>("log-format").unwrap(),
+    );
 
-    let brokers = matches.get_one::<String>("brokers").unwrap();
+    let config = KafkaConfig::from_matches(&matches).expect("Invalid configuration");
     let group_id = matches.get_one::<String>("group-id").unwrap();
     let input_topic = matches.get_one::<String>("input-topic").unwrap();
     let output_topic = matches.get_one::<String>("output-topic").unwrap();
     let num_workers = matches.get_one::<usize>("num-workers").unwrap();
-
-    (0..*num_workers)
+    // every worker has its own consumer, the process is ready as soon as one of them is
+    let healths = (0..*num_workers)
         .map(|_| {
+            Health::new(
+                *matches.get_one::<Duration>("stuck-after").unwrap(),
+                *matches.get_one::<i64>("ready-lag").unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    if let Some(addr) = matches.get_one::<SocketAddr>("metrics-addr") {
+        health::serve(*addr, Workers::new(healths.clone())).expect("Failed to serve metrics");
+    }
+
+    let shutdown = Shutdown::install().expect("Failed to install signal handlers");
+    healths
+        .into_iter()
+        .map(|health| {
             tokio::spawn(run_async_processor(
-                brokers.to_owned(),
+                config.clone(),
                 group_id.to_owned(),
                 input_topic.to_owned(),
                 output_topic.to_owned(),
+                health,
+                shutdown.clone(),
             ))
         })
         .collect::<FuturesUnordered<_>>()
-        .for_each(|_| async { () })
+        .for_each(|_| async {})
         .await
 }
@@ -0,0 +1,276 @@
+use std::fmt;
+
+use lib::codec::{Codec, CodecError, Codecs, JsonCodec};
+use lib::error::ErrorKind;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::schema::{self, Violation};
+use crate::{articles, backpacks, circles, events, users};
+
+/// A Command sent to the worker, tagged by its `command` field
+///
+/// ```json
+/// {"command": "createEvent", "kind": "add", "amount": 5}
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "camelCase")]
+pub enum Command {
+    CreateArticle(articles::CreateArticle),
+    UpdateArticle(articles::UpdateArticle),
+    DeleteArticle(articles::DeleteArticle),
+    CreateBackpack(backpacks::CreateBackpack),
+    AddArticleToBackpack(backpacks::AddArticleToBackpack),
+    RemoveArticleFromBackpack(backpacks::RemoveArticleFromBackpack),
+    CreateCircle(circles::CreateCircle),
+    JoinCircle(circles::JoinCircle),
+    LeaveCircle(circles::LeaveCircle),
+    CreateEvent(events::CreateEvent),
+    CreateUser(users::CreateUser),
+    UpdateUser(users::UpdateUser),
+    DeleteUser(users::DeleteUser),
+}
+
+impl Command {
+    /// all command names known to the worker
+    pub const NAMES: [&'static str; 13] = [
+        "createArticle",
+        "updateArticle",
+        "deleteArticle",
+        "createBackpack",
+        "addArticleToBackpack",
+        "removeArticleFromBackpack",
+        "createCircle",
+        "joinCircle",
+        "leaveCircle",
+        "createEvent",
+        "createUser",
+        "updateUser",
+        "deleteUser",
+    ];
+
+    /// the value of the `command` tag
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::CreateArticle(_) => "createArticle",
+            Command::UpdateArticle(_) => "updateArticle",
+            Command::DeleteArticle(_) => "deleteArticle",
+            Command::CreateBackpack(_) => "createBackpack",
+            Command::AddArticleToBackpack(_) => "addArticleToBackpack",
+            Command::RemoveArticleFromBackpack(_) => "removeArticleFromBackpack",
+            Command::CreateCircle(_) => "createCircle",
+            Command::JoinCircle(_) => "joinCircle",
+            Command::LeaveCircle(_) => "leaveCircle",
+            Command::CreateEvent(_) => "createEvent",
+            Command::CreateUser(_) => "createUser",
+            Command::UpdateUser(_) => "updateUser",
+            Command::DeleteUser(_) => "deleteUser",
+        }
+    }
+}
+
+/// Outcome of decoding a payload which is valid JSON with a `command` tag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decoded {
+    Command(Command),
+    /// a command the worker does not know about
+    Unknown(String),
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    NoPayload,
+    /// the payload can't be decoded by the codec of its topic
+    Codec(CodecError),
+    /// the payload is JSON but has no `command` string
+    MissingCommand,
+    /// a known command whose payload doesn't match its JSON Schema
+    SchemaViolation(&'static str, Vec<Violation>),
+    /// a known command with an invalid payload
+    InvalidPayload(&'static str, serde_json::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::NoPayload => write!(f, "message has no payload"),
+            DecodeError::Codec(error) => write!(f, "{}", error),
+            DecodeError::MissingCommand => write!(f, "payload has no command"),
+            DecodeError::SchemaViolation(command, violations) => {
+                let report = violations
+                    .iter()
+                    .map(Violation::to_string)
+                    .collect::<Vec<_>>();
+                write!(
+                    f,
+                    "payload of {} violates its schema: {}",
+                    command,
+                    report.join("; ")
+                )
+            }
+            DecodeError::InvalidPayload(command, error) => {
+                write!(f, "invalid payload for {}: {}", command, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl ErrorKind for DecodeError {
+    fn kind(&self) -> &'static str {
+        match self {
+            DecodeError::NoPayload => "no-payload",
+            DecodeError::Codec(error) => error.kind(),
+            DecodeError::MissingCommand => "missing-command",
+            DecodeError::SchemaViolation(_, _) => "schema-violation",
+            DecodeError::InvalidPayload(_, _) => "invalid-payload",
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        matches!(self, DecodeError::Codec(error) if error.is_transient())
+    }
+}
+
+/// Decode the JSON payload of a kafka message into a [`Command`], validating the
+/// payloads of known commands against their schema, see [`crate::schema`]
+pub fn decode(payload: Option<&[u8]>) -> Result<Decoded, DecodeError> {
+    let payload = payload.ok_or(DecodeError::NoPayload)?;
+    decode_value(JsonCodec.decode(payload).map_err(DecodeError::Codec)?)
+}
+
+/// Decode a payload of `topic` with its codec, see [`decode`]
+pub fn decode_with(
+    codecs: &Codecs,
+    topic: &str,
+    payload: Option<&[u8]>,
+) -> Result<Decoded, DecodeError> {
+    let payload = payload.ok_or(DecodeError::NoPayload)?;
+    decode_value(codecs.decode(topic, payload).map_err(DecodeError::Codec)?)
+}
+
+/// [`decode_with`] for async callers, schemas are looked up on the blocking thread pool
+pub async fn decode_async(
+    codecs: &Codecs,
+    topic: &str,
+    payload: Option<&[u8]>,
+) -> Result<Decoded, DecodeError> {
+    if let Some(payload) = payload {
+        codecs
+            .prefetch_decode(topic, payload)
+            .await
+            .map_err(DecodeError::Codec)?;
+    }
+    decode_with(codecs, topic, payload)
+}
+
+/// Decode the JSON representation of a payload, see [`decode`]
+pub fn decode_value(value: Value) -> Result<Decoded, DecodeError> {
+    let name = match value.get("command").and_then(Value::as_str) {
+        Some(name) => name,
+        None => return Err(DecodeError::MissingCommand),
+    };
+
+    match Command::NAMES.iter().find(|known| **known == name) {
+        Some(known) => {
+            schema::validate(known, &value)
+                .map_err(|violations| DecodeError::SchemaViolation(known, violations))?;
+            serde_json::from_value::<Command>(value)
+                .map(Decoded::Command)
+                .map_err(|error| DecodeError::InvalidPayload(known, error))
+        }
+        None => Ok(Decoded::Unknown(name.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use lib::codec::MessagePackCodec;
+
+    use super::*;
+    use crate::events::{CreateEvent, EventKind};
+
+    #[test]
+    fn test_decode_command() {
+        let decoded = decode(Some(
+            br#"{"command": "createEvent", "kind": "add", "amount": 5}"#,
+        ));
+        assert_eq!(
+            decoded.unwrap(),
+            Decoded::Command(Command::CreateEvent(CreateEvent {
+                kind: EventKind::Add,
+                amount: 5
+            }))
+        );
+    }
+
+    #[test]
+    fn test_decode_unknown_command() {
+        let decoded = decode(Some(br#"{"command": "launchRocket"}"#));
+        assert_eq!(
+            decoded.unwrap(),
+            Decoded::Unknown("launchRocket".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(matches!(decode(None), Err(DecodeError::NoPayload)));
+        assert!(matches!(
+            decode(Some(&[0xff])),
+            Err(DecodeError::Codec(CodecError::NotUtf8(_)))
+        ));
+        assert!(matches!(
+            decode(Some(b"{")),
+            Err(DecodeError::Codec(CodecError::Json(_)))
+        ));
+        assert!(matches!(
+            decode(Some(br#"{"kind": "add"}"#)),
+            Err(DecodeError::MissingCommand)
+        ));
+        let error = decode(Some(br#"{"command": "createEvent", "kind": "mul"}"#)).unwrap_err();
+        assert_eq!(error.kind(), "schema-violation");
+        assert!(
+            matches!(error, DecodeError::SchemaViolation("createEvent", ref violations) if violations.len() == 2)
+        );
+        assert!(matches!(
+            decode(Some(
+                br#"{"command": "createEvent", "kind": "add", "amount": 1.5}"#
+            )),
+            Err(DecodeError::SchemaViolation("createEvent", _))
+        ));
+    }
+
+    #[test]
+    fn test_decode_with_codec() {
+        let codecs = Codecs::default().with_topic("events", Arc::new(MessagePackCodec));
+        let value = serde_json::json!({"command": "createEvent", "kind": "sub", "amount": 3});
+        let payload = codecs.encode("events", &value).unwrap();
+        assert_eq!(
+            decode_with(&codecs, "events", Some(&payload)).unwrap(),
+            Decoded::Command(Command::CreateEvent(CreateEvent {
+                kind: EventKind::Sub,
+                amount: 3
+            }))
+        );
+        assert_eq!(
+            decode_with(&codecs, "users", Some(&payload))
+                .unwrap_err()
+                .kind(),
+            "not-utf8"
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        for name in Command::NAMES {
+            assert!(matches!(
+                decode(Some(format!(r#"{{"command": "{}"}}"#, name).as_bytes())),
+                Err(DecodeError::SchemaViolation(known, _)) if known == name
+            ));
+        }
+    }
+}
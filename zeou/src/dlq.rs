@@ -0,0 +1,97 @@
+use rdkafka::error::KafkaError;
+use rdkafka::message::{BorrowedMessage, Headers, Message, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+
+use lib::async_std::{send, AsyncStdRuntime};
+use lib::context::CustomContext;
+use lib::headers::{MessageHeaders, Origin};
+
+use crate::retry;
+
+pub const HEADER_ERROR_KIND: &str = "dlq.error.kind";
+pub const HEADER_ERROR_MESSAGE: &str = "dlq.error.message";
+/// topic, partition and offset of the record which failed, e.g. on a retry topic
+pub const HEADER_ORIGINAL_TOPIC: &str = "dlq.original.topic";
+pub const HEADER_ORIGINAL_PARTITION: &str = "dlq.original.partition";
+pub const HEADER_ORIGINAL_OFFSET: &str = "dlq.original.offset";
+pub const HEADER_WORKER_ID: &str = "dlq.worker.id";
+/// the domain of the record, its topic when it is re-driven
+pub const HEADER_DOMAIN: &str = "dlq.domain";
+
+/// the dead-letter topic of a domain
+pub fn topic(domain: &str) -> String {
+    format!("{}.dlq", domain)
+}
+
+/// whether a header was added when the record was dead-lettered
+pub fn is_dlq_header(key: &str) -> bool {
+    key.starts_with("dlq.")
+}
+
+/// Forward `message` to the dead-letter topic of `domain`, keeping key, payload and
+/// headers and recording why and where it failed.
+pub async fn dead_letter(
+    producer: &FutureProducer<CustomContext, AsyncStdRuntime>,
+    domain: &str,
+    message: &BorrowedMessage<'_>,
+    kind: &str,
+    error: &str,
+    worker_id: &str,
+) -> Result<String, KafkaError> {
+    let topic = topic(domain);
+    forward(producer, &topic, domain, message, kind, error, worker_id).await?;
+    Ok(topic)
+}
+
+/// Forward a failed `message` of `domain` to `topic` like [`dead_letter`]
+pub async fn forward(
+    producer: &FutureProducer<CustomContext, AsyncStdRuntime>,
+    topic: &str,
+    domain: &str,
+    message: &BorrowedMessage<'_>,
+    kind: &str,
+    error: &str,
+    worker_id: &str,
+) -> Result<(), KafkaError> {
+    let mut headers = OwnedHeaders::new();
+    if let Some(original) = message.headers() {
+        for (key, value) in (0..original.count()).filter_map(|idx| original.get(idx)) {
+            if !is_dlq_header(key) && !retry::is_retry_header(key) {
+                headers = headers.add(key, value);
+            }
+        }
+    }
+    let headers = headers
+        .add(HEADER_ERROR_KIND, kind)
+        .add(HEADER_ERROR_MESSAGE, error)
+        .add(HEADER_ORIGINAL_TOPIC, message.topic())
+        .add(HEADER_ORIGINAL_PARTITION, &message.partition().to_string())
+        .add(HEADER_ORIGINAL_OFFSET, &message.offset().to_string())
+        .add(HEADER_WORKER_ID, worker_id)
+        .add(HEADER_DOMAIN, domain);
+    // the dead-lettered record keeps the identity and origin of the original
+    let headers = MessageHeaders::read(message).fill(Some(Origin::read(message).fill(headers)));
+
+    let mut record = FutureRecord::<[u8], [u8]>::to(topic).headers(headers);
+    if let Some(key) = message.key() {
+        record = record.key(key);
+    }
+    if let Some(payload) = message.payload() {
+        record = record.payload(payload);
+    }
+
+    send(producer, record).await.map_err(|(error, _)| error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic() {
+        assert_eq!(topic("events"), "events.dlq");
+        assert!(is_dlq_header(HEADER_ORIGINAL_OFFSET));
+        assert!(!is_dlq_header("traceparent"));
+    }
+}
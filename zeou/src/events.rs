@@ -1,68 +1,268 @@
-use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
-use rdkafka::message::BorrowedMessage;
+use futures::future::LocalBoxFuture;
+use rdkafka::consumer::Consumer;
 use rdkafka::message::Message;
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::producer::FutureRecord;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
 
-use lib::async_std::AsyncStdRuntime;
-use lib::context::CustomContext;
-use log::{error, info, warn};
+use lib::async_std::send;
+use lib::headers::{MessageHeaders, Origin};
+use lib::state::{create_changelog_topic, ChangelogStore, SledStore, StateResult};
+use log::info;
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Command<'a> {
-    command: &'a str,
+use crate::commands::Command;
+use crate::router::{CommandHandler, HandlerContext, HandlerError, Router};
+use crate::Config;
+
+/// compacted topic the [`Amount`] aggregates are mirrored to
+pub const CHANGELOG_TOPIC: &str = "events.changelog";
+
+/// topic the [`Amount`] aggregates are published to, in the output format of the worker
+pub const OUTPUT_TOPIC: &str = "events-processed";
+
+/// version of the [`Amount`] records published to `events-processed`
+pub const AMOUNT_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EventKind {
+    Add,
+    Sub,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateEvent {
+    pub kind: EventKind,
+    pub amount: i64,
+}
+
+/// The event-sourced aggregate of the events domain, one per message key
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Amount {
+    pub amount: i64,
+    /// number of events applied to the aggregate
+    pub version: u64,
+}
+
+impl Amount {
+    /// the state after applying `event`, `None` if the amount would overflow
+    pub fn apply(&self, event: &CreateEvent) -> Option<Amount> {
+        let amount = match event.kind {
+            EventKind::Add => self.amount.checked_add(event.amount)?,
+            EventKind::Sub => self.amount.checked_sub(event.amount)?,
+        };
+        Some(Amount {
+            amount,
+            version: self.version + 1,
+        })
+    }
+}
+
+/// What the state store keeps per aggregate
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+struct StoredAmount {
+    #[serde(flatten)]
+    amount: Amount,
+    /// the last record applied to the aggregate, absent in state stored before it was tracked
+    applied: Option<Origin>,
+}
+
+/// Handles `createEvent` commands and publishes the resulting aggregate to [`OUTPUT_TOPIC`]
+pub struct CreateEventHandler {
+    store: ChangelogStore<SledStore>,
+}
+
+impl CommandHandler for CreateEventHandler {
+    fn handle<'a>(
+        &'a self,
+        ctx: &'a HandlerContext<'a>,
+        command: &'a Command,
+    ) -> LocalBoxFuture<'a, Result<(), HandlerError>> {
+        Box::pin(async move {
+            match command {
+                Command::CreateEvent(event) => self.process_message(ctx, event).await,
+                _ => Err(HandlerError::Other(format!(
+                    "unexpected command: {}",
+                    command.name()
+                ))),
+            }
+        })
+    }
+
+    fn on_commit(&self) -> Result<(), HandlerError> {
+        Ok(self.store.commit()?)
+    }
+
+    fn on_abort(&self) {
+        self.store.abort();
+    }
 }
 
-pub async fn process_message(
-    message: BorrowedMessage<'_>,
-    consumer: &StreamConsumer<CustomContext, AsyncStdRuntime>,
-    producer: &FutureProducer<CustomContext, AsyncStdRuntime>,
-) {
-    match message.payload_view::<str>() {
-        Some(Ok(string)) => match serde_json::from_str::<Command>(string) {
-            Ok(cmd) => {
+impl CreateEventHandler {
+    pub fn new(store: ChangelogStore<SledStore>) -> Self {
+        CreateEventHandler { store }
+    }
+
+    pub async fn process_message(
+        &self,
+        ctx: &HandlerContext<'_>,
+        event: &CreateEvent,
+    ) -> Result<(), HandlerError> {
+        let message = ctx.message;
+        info!(
+            "Processing message {}, key: {:?}, topic: {}, partition: {}, {:?}: kind: {:?}",
+            message.offset(),
+            message.key(),
+            message.topic(),
+            message.partition(),
+            message.timestamp(),
+            event.kind
+        );
+
+        let id = match message.key_view::<str>() {
+            Some(Ok(id)) => id,
+            Some(Err(_)) => {
+                return Err(HandlerError::Other(
+                    "aggregate id is not utf-8 encoded".to_owned(),
+                ))
+            }
+            None => {
+                return Err(HandlerError::Other(
+                    "createEvent without aggregate id (message key)".to_owned(),
+                ))
+            }
+        };
+
+        self.store
+            .ensure_restored(message.partition(), ctx.consumer.context().generation())
+            .await?;
+
+        let current = match self.store.get(id.as_bytes())? {
+            Some(bytes) => serde_json::from_slice::<StoredAmount>(&bytes).map_err(|error| {
+                HandlerError::Other(format!("corrupt state of {}: {}", id, error))
+            })?,
+            None => StoredAmount::default(),
+        };
+        // a retried or re-driven record may have been applied before publishing its
+        // output failed, then only the output is published again. Earlier offsets of
+        // the partition are still applied, they may have failed before reaching the store.
+        let origin = Origin::read(message);
+        let state = match &current.applied {
+            Some(applied) if origin == *applied => {
                 info!(
-                    "Processing message {}, key: {:?}, topic: {}, partition: {}, {:?}: kind: {}",
-                    message.offset(),
-                    message.key(),
-                    message.topic(),
-                    message.partition(),
-                    message.timestamp(),
-                    cmd.command
+                    "Aggregate {} already applied offset {} of {}",
+                    id, origin.offset, origin.topic
                 );
-
-                // match event.kind {
-                //     "add" => amount.add(event.amount),
-                //     "sub" => amount.sub(event.amount),
-                //     _ => warn!("Unknown event kind: {}", event.kind),
-                // }
-
-                let delivery_status = producer
-                    .send::<Vec<u8>, _, _>(
-                        FutureRecord::to("events-processed").payload(
-                            &serde_json::json!({
-                                "amount": 0,
-                                "version": 0
-                            })
-                            .to_string(),
-                        ),
-                        Duration::from_secs(0),
+                current.amount
+            }
+            _ => {
+                let state = current
+                    .amount
+                    .apply(event)
+                    .ok_or_else(|| HandlerError::Other(format!("amount of {} overflows", id)))?;
+                let stored = StoredAmount {
+                    amount: state.clone(),
+                    applied: Some(origin),
+                };
+                let payload = serde_json::to_string(&stored).expect("Amount is serializable");
+                // the output is only published once the new state is persisted
+                self.store
+                    .put(
+                        ctx.producer,
+                        message.partition(),
+                        id.as_bytes(),
+                        payload.as_bytes(),
                     )
-                    .await;
+                    .await?;
+                state
+            }
+        };
+
+        let output = ctx.encode(OUTPUT_TOPIC, &state).await?;
+        let headers = MessageHeaders::outgoing().with_schema_version(AMOUNT_SCHEMA_VERSION);
+        send(
+            ctx.producer,
+            FutureRecord::to(OUTPUT_TOPIC)
+                .key(id)
+                .payload(&output)
+                .headers(headers.fill(None)),
+        )
+        .await
+        .map_err(|(error, _)| error)?;
+        info!("Aggregate {} is now at version {}", id, state.version);
+        Ok(())
+    }
+}
 
-                if let Err((error, _)) = delivery_status {
-                    error!("Unable to send message: {}", error);
-                }
+pub async fn register(router: &mut Router, config: &Config) -> StateResult<()> {
+    create_changelog_topic(&config.kafka, "events", CHANGELOG_TOPIC, config.replication).await?;
+    let store = SledStore::open(config.state_dir.join("events"))?;
+    let mut store =
+        ChangelogStore::new(store, &config.kafka, CHANGELOG_TOPIC).heartbeat(config.health.clone());
+    if config.exactly_once {
+        store = store.transactional();
+    }
+    router.register("events", "createEvent", CreateEventHandler::new(store));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let add = CreateEvent {
+            kind: EventKind::Add,
+            amount: 10,
+        };
+        let sub = CreateEvent {
+            kind: EventKind::Sub,
+            amount: 4,
+        };
+
+        let state = Amount::default().apply(&add).unwrap();
+        assert_eq!(
+            state,
+            Amount {
+                amount: 10,
+                version: 1
+            }
+        );
+
+        let state = state.apply(&sub).unwrap().apply(&sub).unwrap();
+        assert_eq!(
+            state,
+            Amount {
+                amount: 2,
+                version: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_apply_overflow() {
+        let state = Amount {
+            amount: i64::MAX,
+            version: 7,
+        };
+        let add = CreateEvent {
+            kind: EventKind::Add,
+            amount: 1,
+        };
+        assert_eq!(state.apply(&add), None);
+    }
 
-                consumer
-                    .commit_message(&message, CommitMode::Async)
-                    .unwrap();
-                info!("Committed offset: {}", message.offset());
+    #[test]
+    fn test_stored_amount() {
+        let stored: StoredAmount = serde_json::from_str(r#"{"amount": 3, "version": 2}"#).unwrap();
+        assert_eq!(
+            stored.amount,
+            Amount {
+                amount: 3,
+                version: 2
             }
-            Err(parse_error) => error!("Cannot parse message {:?}: {:?}", string, parse_error),
-        },
-        Some(Err(_)) => error!("Message is not utf-8 encoded"),
-        None => warn!("Got message without payload"),
+        );
+        assert_eq!(stored.applied, None);
     }
 }
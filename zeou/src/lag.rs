@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// How lag reports are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Table,
+    /// a JSON object per report and line
+    Json,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Output::Table),
+            "json" => Ok(Output::Json),
+            _ => Err(format!("unknown output: {}", s)),
+        }
+    }
+}
+
+/// Offsets of a partition at one point in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Offsets {
+    pub low: i64,
+    pub high: i64,
+    /// the offset committed by the group, `None` if it never committed one
+    pub committed: Option<i64>,
+}
+
+impl Offsets {
+    /// Records the group still has to consume. Without a committed offset the
+    /// group starts at the low watermark.
+    pub fn lag(&self) -> i64 {
+        (self.high - self.committed.unwrap_or(self.low).max(self.low)).max(0)
+    }
+}
+
+/// Offsets of all partitions of the inspected topics, keyed by topic and partition
+pub type Snapshot = BTreeMap<(String, i32), Offsets>;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PartitionLag {
+    pub topic: String,
+    pub partition: i32,
+    pub committed: Option<i64>,
+    pub high: i64,
+    pub lag: i64,
+    /// records per second the lag shrank by since the previous snapshot
+    pub drain_rate: Option<f64>,
+    /// estimated seconds until the lag is 0, `None` if it isn't shrinking
+    pub time_to_drain: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub group: String,
+    pub partitions: Vec<PartitionLag>,
+    pub lag: i64,
+    pub drain_rate: Option<f64>,
+    pub time_to_drain: Option<f64>,
+}
+
+/// Seconds until `lag` is consumed if it shrinks by `drain_rate` records per second
+fn time_to_drain(lag: i64, drain_rate: Option<f64>) -> Option<f64> {
+    match drain_rate {
+        _ if lag == 0 => Some(0.0),
+        Some(rate) if rate > 0.0 => Some(lag as f64 / rate),
+        _ => None,
+    }
+}
+
+impl Report {
+    /// Compare `current` to the snapshot taken `elapsed` before it, if any
+    pub fn new(group: &str, current: &Snapshot, previous: Option<(&Snapshot, Duration)>) -> Self {
+        let rate = |before: i64, now: i64, elapsed: Duration| {
+            (elapsed > Duration::ZERO).then(|| (before - now) as f64 / elapsed.as_secs_f64())
+        };
+
+        let partitions = current
+            .iter()
+            .map(|((topic, partition), offsets)| {
+                let lag = offsets.lag();
+                let drain_rate = previous.and_then(|(snapshot, elapsed)| {
+                    snapshot
+                        .get(&(topic.clone(), *partition))
+                        .and_then(|before| rate(before.lag(), lag, elapsed))
+                });
+                PartitionLag {
+                    topic: topic.clone(),
+                    partition: *partition,
+                    committed: offsets.committed,
+                    high: offsets.high,
+                    lag,
+                    drain_rate,
+                    time_to_drain: time_to_drain(lag, drain_rate),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let lag = partitions.iter().map(|partition| partition.lag).sum();
+        let drain_rate = previous.and_then(|(snapshot, elapsed)| {
+            rate(snapshot.values().map(Offsets::lag).sum(), lag, elapsed)
+        });
+        Report {
+            group: group.to_owned(),
+            partitions,
+            lag,
+            drain_rate,
+            time_to_drain: time_to_drain(lag, drain_rate),
+        }
+    }
+
+    pub fn table(&self) -> String {
+        let mut rows = vec![[
+            "TOPIC".to_owned(),
+            "PARTITION".to_owned(),
+            "COMMITTED".to_owned(),
+            "HIGH".to_owned(),
+            "LAG".to_owned(),
+            "RATE".to_owned(),
+            "DRAINED IN".to_owned(),
+        ]];
+        for partition in &self.partitions {
+            rows.push([
+                partition.topic.clone(),
+                partition.partition.to_string(),
+                partition
+                    .committed
+                    .map(|offset| offset.to_string())
+                    .unwrap_or_else(|| "-".to_owned()),
+                partition.high.to_string(),
+                partition.lag.to_string(),
+                format_rate(partition.drain_rate),
+                format_eta(partition.time_to_drain),
+            ]);
+        }
+        rows.push([
+            "TOTAL".to_owned(),
+            String::new(),
+            String::new(),
+            String::new(),
+            self.lag.to_string(),
+            format_rate(self.drain_rate),
+            format_eta(self.time_to_drain),
+        ]);
+
+        let widths = (0..7)
+            .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0))
+            .collect::<Vec<_>>();
+        let mut table = String::new();
+        for row in rows {
+            let line = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ");
+            let _ = writeln!(table, "{}", line.trim_end());
+        }
+        table
+    }
+}
+
+fn format_rate(rate: Option<f64>) -> String {
+    rate.map(|rate| format!("{:.1}/s", rate))
+        .unwrap_or_else(|| "-".to_owned())
+}
+
+fn format_eta(seconds: Option<f64>) -> String {
+    match seconds {
+        Some(seconds) => format_duration(Duration::from_secs_f64(seconds.ceil())),
+        None => "never".to_owned(),
+    }
+}
+
+/// Human readable duration with at most two units, e.g. `2h5m` or `40s`
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{}s", m, s),
+        (h, m, _) => format!("{}h{}m", h, m),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(partitions: &[(i32, i64, Option<i64>)]) -> Snapshot {
+        partitions
+            .iter()
+            .map(|(partition, high, committed)| {
+                (
+                    ("events".to_owned(), *partition),
+                    Offsets {
+                        low: 0,
+                        high: *high,
+                        committed: *committed,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_report() {
+        let before = snapshot(&[(0, 1000, Some(400)), (1, 500, None), (2, 10, Some(10))]);
+        let now = snapshot(&[(0, 1100, Some(700)), (1, 600, None), (2, 10, Some(10))]);
+
+        let report = Report::new("events-group", &now, None);
+        assert_eq!(report.lag, 400 + 600);
+        assert_eq!(report.drain_rate, None);
+        assert_eq!(report.time_to_drain, None);
+
+        let report = Report::new(
+            "events-group",
+            &now,
+            Some((&before, Duration::from_secs(10))),
+        );
+        // partition 0 drained 200 records in 10s, partition 1 only grew
+        assert_eq!(report.partitions[0].drain_rate, Some(20.0));
+        assert_eq!(report.partitions[0].time_to_drain, Some(20.0));
+        assert_eq!(report.partitions[1].drain_rate, Some(-10.0));
+        assert_eq!(report.partitions[1].time_to_drain, None);
+        assert_eq!(report.partitions[2].time_to_drain, Some(0.0));
+        assert_eq!(report.drain_rate, Some(10.0));
+        assert_eq!(report.time_to_drain, Some(100.0));
+
+        let table = report.table();
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "TOPIC   PARTITION  COMMITTED  HIGH  LAG   RATE     DRAINED IN"
+        );
+        assert_eq!(
+            lines[1],
+            "events  0          700        1100  400   20.0/s   20s"
+        );
+        assert_eq!(
+            lines[2],
+            "events  1          -          600   600   -10.0/s  never"
+        );
+        assert_eq!(
+            lines[4],
+            "TOTAL                               1000  10.0/s   1m40s"
+        );
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(40)), "40s");
+        assert_eq!(format_duration(Duration::from_secs(100)), "1m40s");
+        assert_eq!(format_duration(Duration::from_secs(7500)), "2h5m");
+        assert_eq!("json".parse(), Ok(Output::Json));
+    }
+}
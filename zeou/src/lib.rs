@@ -1,4 +1,42 @@
+pub mod articles;
+pub mod backpacks;
+pub mod circles;
+pub mod commands;
+pub mod dlq;
 pub mod events;
+pub mod lag;
+pub mod loadgen;
+pub mod retry;
+pub mod router;
+pub mod schema;
+pub mod users;
+
+use std::path::PathBuf;
+
+use lib::config::KafkaConfig;
+use lib::health::Health;
+use lib::state::StateResult;
+use router::Router;
+
+/// Settings shared by the handlers of all domains
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub kafka: KafkaConfig,
+    /// directory of the local state stores
+    pub state_dir: PathBuf,
+    /// replication factor of changelog topics
+    pub replication: i32,
+    /// stage state changes until the transaction of the message commits
+    pub exactly_once: bool,
+    /// kept live by the state stores while they restore
+    pub health: Health,
+}
+
+/// Register the handlers of all domains with the router
+pub async fn register_handlers(router: &mut Router, config: &Config) -> StateResult<()> {
+    events::register(router, config).await?;
+    Ok(())
+}
 
 // pub fn add(left: usize, right: usize) -> usize {
 //     left + right
@@ -0,0 +1,226 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use rand::Rng;
+use serde_json::Value;
+
+use crate::commands::Command;
+use crate::events::{CreateEvent, EventKind};
+use crate::{articles, backpacks, circles, users};
+
+/// How keys are picked from the key space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Skew {
+    Uniform,
+    /// key `k` (1-based) is picked with a probability proportional to `1 / k^s`
+    Zipf(f64),
+}
+
+impl FromStr for Skew {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(Skew::Uniform),
+            "zipf" => Ok(Skew::Zipf(1.0)),
+            _ => match s.strip_prefix("zipf:").map(str::parse::<f64>) {
+                Some(Ok(exponent)) if exponent > 0.0 => Ok(Skew::Zipf(exponent)),
+                _ => Err(format!("unknown skew: {}", s)),
+            },
+        }
+    }
+}
+
+/// Picks keys out of `0..keys` according to a [`Skew`]
+pub struct KeySampler {
+    keys: u64,
+    /// cumulative probabilities of the keys for skewed sampling
+    cdf: Option<Vec<f64>>,
+}
+
+impl KeySampler {
+    pub fn new(keys: u64, skew: Skew) -> Self {
+        let keys = keys.max(1);
+        let cdf = match skew {
+            Skew::Uniform => None,
+            Skew::Zipf(exponent) => {
+                let mut total = 0.0;
+                let mut cdf = (1..=keys)
+                    .map(|k| {
+                        total += 1.0 / (k as f64).powf(exponent);
+                        total
+                    })
+                    .collect::<Vec<_>>();
+                cdf.iter_mut().for_each(|p| *p /= total);
+                Some(cdf)
+            }
+        };
+        KeySampler { keys, cdf }
+    }
+
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        match &self.cdf {
+            None => rng.gen_range(0..self.keys),
+            Some(cdf) => {
+                let p = rng.gen::<f64>();
+                (cdf.partition_point(|q| *q < p) as u64).min(self.keys - 1)
+            }
+        }
+    }
+}
+
+fn name<R: Rng>(rng: &mut R) -> String {
+    format!("{:08x}", rng.gen::<u32>())
+}
+
+/// A random, valid command of `domain` for the aggregate `id`, `None` for unknown domains
+pub fn command<R: Rng>(domain: &str, id: &str, rng: &mut R) -> Option<Command> {
+    let id = id.to_owned();
+    let command = match (domain, rng.gen_range(0..3)) {
+        ("articles", 0) => Command::CreateArticle(articles::CreateArticle {
+            id,
+            title: name(rng),
+            body: Some(name(rng)),
+        }),
+        ("articles", 1) => Command::UpdateArticle(articles::UpdateArticle {
+            id,
+            title: Some(name(rng)),
+            body: None,
+        }),
+        ("articles", _) => Command::DeleteArticle(articles::DeleteArticle { id }),
+        ("backpacks", 0) => Command::CreateBackpack(backpacks::CreateBackpack {
+            id,
+            owner_id: name(rng),
+            name: name(rng),
+        }),
+        ("backpacks", 1) => Command::AddArticleToBackpack(backpacks::AddArticleToBackpack {
+            backpack_id: id,
+            article_id: name(rng),
+        }),
+        ("backpacks", _) => {
+            Command::RemoveArticleFromBackpack(backpacks::RemoveArticleFromBackpack {
+                backpack_id: id,
+                article_id: name(rng),
+            })
+        }
+        ("circles", 0) => Command::CreateCircle(circles::CreateCircle {
+            id,
+            name: name(rng),
+        }),
+        ("circles", 1) => Command::JoinCircle(circles::JoinCircle {
+            circle_id: id,
+            user_id: name(rng),
+        }),
+        ("circles", _) => Command::LeaveCircle(circles::LeaveCircle {
+            circle_id: id,
+            user_id: name(rng),
+        }),
+        ("events", _) => Command::CreateEvent(CreateEvent {
+            kind: if rng.gen() {
+                EventKind::Add
+            } else {
+                EventKind::Sub
+            },
+            amount: rng.gen_range(1..100),
+        }),
+        ("users", 0) => Command::CreateUser(users::CreateUser {
+            id,
+            email: format!("{}@example.com", name(rng)),
+            name: name(rng),
+        }),
+        ("users", 1) => Command::UpdateUser(users::UpdateUser {
+            id,
+            name: Some(name(rng)),
+            email: None,
+        }),
+        ("users", _) => Command::DeleteUser(users::DeleteUser { id }),
+        _ => return None,
+    };
+    Some(command)
+}
+
+/// Serialize `command`, padded with an ignored `padding` field to at least `size` bytes
+pub fn payload(command: &Command, size: usize) -> String {
+    let json = serde_json::to_string(command).expect("commands are serializable");
+    // `,"padding":""` adds 13 bytes
+    if json.len() + 13 > size {
+        return json;
+    }
+    let mut value = serde_json::to_value(command).expect("commands are serializable");
+    if let Value::Object(object) = &mut value {
+        object.insert(
+            "padding".to_owned(),
+            Value::String("x".repeat(size - json.len() - 13)),
+        );
+    }
+    value.to_string()
+}
+
+/// The `p`th percentile (0-100) of sorted `latencies`
+pub fn percentile(latencies: &[Duration], p: f64) -> Option<Duration> {
+    if latencies.is_empty() {
+        return None;
+    }
+    let rank = (p / 100.0 * latencies.len() as f64).ceil() as usize;
+    Some(latencies[rank.clamp(1, latencies.len()) - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{decode, Decoded};
+
+    #[test]
+    fn test_commands_decode() {
+        let mut rng = rand::thread_rng();
+        for domain in ["articles", "backpacks", "circles", "events", "users"] {
+            for _ in 0..20 {
+                let command = command(domain, "key-1", &mut rng).unwrap();
+                let payload = payload(&command, 256);
+                assert_eq!(payload.len(), 256);
+                assert_eq!(
+                    decode(Some(payload.as_bytes())).unwrap(),
+                    Decoded::Command(command)
+                );
+            }
+        }
+        assert!(command("rockets", "key-1", &mut rng).is_none());
+    }
+
+    #[test]
+    fn test_key_sampler() {
+        let mut rng = rand::thread_rng();
+        let uniform = KeySampler::new(10, Skew::Uniform);
+        let zipf = KeySampler::new(10, "zipf:1.5".parse().unwrap());
+        let mut hot = 0;
+        for _ in 0..1000 {
+            assert!(uniform.sample(&mut rng) < 10);
+            let key = zipf.sample(&mut rng);
+            assert!(key < 10);
+            if key == 0 {
+                hot += 1;
+            }
+        }
+        // p(0) is about 0.6 for s = 1.5 and 10 keys
+        assert!(hot > 400, "{}", hot);
+        assert!("zipf:0".parse::<Skew>().is_err());
+    }
+
+    #[test]
+    fn test_percentile() {
+        let latencies = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(
+            percentile(&latencies, 50.0),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(
+            percentile(&latencies, 99.0),
+            Some(Duration::from_millis(99))
+        );
+        assert_eq!(
+            percentile(&latencies, 100.0),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(percentile(&[], 50.0), None);
+    }
+}
@@ -0,0 +1,289 @@
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rdkafka::consumer::Consumer;
+use rdkafka::error::{KafkaError, KafkaResult};
+use rdkafka::message::{BorrowedMessage, Headers, Message, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+
+use lib::async_std::{send, AsyncStdRuntime};
+use lib::context::CustomContext;
+use lib::headers::{header, MessageHeaders, Origin};
+use lib::utils::parse_duration;
+use log::info;
+
+pub const HEADER_ATTEMPT: &str = "retry.attempt";
+/// milliseconds since the unix epoch after which the record is reprocessed
+pub const HEADER_DUE: &str = "retry.due";
+pub const HEADER_ERROR_KIND: &str = "retry.error.kind";
+pub const HEADER_ERROR_MESSAGE: &str = "retry.error.message";
+
+const RETRY_INFIX: &str = ".retry.";
+
+/// One retry topic and the delay of its records
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tier {
+    /// suffix of the retry topic, e.g. `30s`
+    pub label: String,
+    pub delay: Duration,
+}
+
+impl FromStr for Tier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Tier {
+            label: s.to_owned(),
+            delay: parse_duration(s)?,
+        })
+    }
+}
+
+/// Retry tiers with increasing delays, the last tier is used for all further attempts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryConfig {
+    pub tiers: Vec<Tier>,
+    /// attempts after the first failure before a record is moved to the failed topic
+    pub max_attempts: u32,
+    /// suffix of the topic `<domain>.<suffix>` receiving records whose retries are exhausted
+    pub failed_suffix: String,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            tiers: ["1s", "30s", "5m"]
+                .iter()
+                .map(|tier| tier.parse().unwrap())
+                .collect(),
+            max_attempts: 3,
+            failed_suffix: "retry.failed".to_owned(),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// the tier of the given (1-based) attempt, `None` without tiers
+    pub fn tier(&self, attempt: u32) -> Option<&Tier> {
+        let idx = attempt.max(1) as usize - 1;
+        self.tiers.get(idx).or_else(|| self.tiers.last())
+    }
+
+    /// all retry topics of `domain`
+    pub fn topics(&self, domain: &str) -> Vec<String> {
+        self.tiers.iter().map(|tier| topic(domain, tier)).collect()
+    }
+
+    /// the topic of `domain` receiving records whose retries are exhausted
+    pub fn failed_topic(&self, domain: &str) -> String {
+        format!("{}.{}", domain, self.failed_suffix)
+    }
+}
+
+pub fn topic(domain: &str, tier: &Tier) -> String {
+    format!("{}{}{}", domain, RETRY_INFIX, tier.label)
+}
+
+/// The domain of a domain or retry topic
+pub fn domain(topic: &str) -> &str {
+    match topic.find(RETRY_INFIX) {
+        Some(idx) => &topic[..idx],
+        None => topic,
+    }
+}
+
+pub fn is_retry_header(key: &str) -> bool {
+    key.starts_with("retry.")
+}
+
+/// Number of retries the message has gone through
+pub fn attempt<M: Message>(message: &M) -> u32 {
+    message
+        .headers()
+        .and_then(|headers| header(headers, HEADER_ATTEMPT))
+        .and_then(|attempt| attempt.parse().ok())
+        .unwrap_or(0)
+}
+
+/// When a retried message is due, `None` for messages which are not retries
+pub fn due<M: Message>(message: &M) -> Option<i64> {
+    message
+        .headers()
+        .and_then(|headers| header(headers, HEADER_DUE))
+        .and_then(|due| due.parse().ok())
+}
+
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// Republish `message` to the retry topic of its next attempt, keeping its partition.
+/// Returns the retry topic or `None` if all attempts are used up or there are no tiers.
+pub async fn schedule(
+    producer: &FutureProducer<CustomContext, AsyncStdRuntime>,
+    config: &RetryConfig,
+    domain: &str,
+    message: &BorrowedMessage<'_>,
+    kind: &str,
+    error: &str,
+) -> Result<Option<String>, KafkaError> {
+    let attempt = attempt(message) + 1;
+    let tier = match config.tier(attempt) {
+        Some(tier) if attempt <= config.max_attempts => tier,
+        _ => return Ok(None),
+    };
+    let topic = topic(domain, tier);
+    let due = now_millis() + tier.delay.as_millis() as i64;
+
+    let mut headers = OwnedHeaders::new();
+    if let Some(original) = message.headers() {
+        for (key, value) in (0..original.count()).filter_map(|idx| original.get(idx)) {
+            if !is_retry_header(key) {
+                headers = headers.add(key, value);
+            }
+        }
+    }
+    let headers = headers
+        .add(HEADER_ATTEMPT, &attempt.to_string())
+        .add(HEADER_DUE, &due.to_string())
+        .add(HEADER_ERROR_KIND, kind)
+        .add(HEADER_ERROR_MESSAGE, error);
+    // the retried record keeps the identity and origin of the original
+    let headers = MessageHeaders::read(message).fill(Some(Origin::read(message).fill(headers)));
+
+    let mut record = FutureRecord::<[u8], [u8]>::to(&topic)
+        .partition(message.partition())
+        .headers(headers);
+    if let Some(key) = message.key() {
+        record = record.key(key);
+    }
+    if let Some(payload) = message.payload() {
+        record = record.payload(payload);
+    }
+
+    send(producer, record).await.map_err(|(error, _)| error)?;
+    Ok(Some(topic))
+}
+
+/// Retry topic partitions paused until their next record is due
+#[derive(Default)]
+pub struct Delays {
+    paused: Vec<(String, i32, i64)>,
+}
+
+impl Delays {
+    /// Pause the partition of a message which is not due yet and rewind it to the message
+    pub fn defer<C: Consumer<CustomContext>>(
+        &mut self,
+        consumer: &C,
+        message: &BorrowedMessage<'_>,
+        due: i64,
+    ) -> KafkaResult<()> {
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition(message.topic(), message.partition());
+        consumer.pause(&partitions)?;
+        consumer.seek(
+            message.topic(),
+            message.partition(),
+            Offset::Offset(message.offset()),
+            Duration::from_secs(1),
+        )?;
+        info!(
+            "Pausing {} partition {} for {}ms",
+            message.topic(),
+            message.partition(),
+            due - now_millis()
+        );
+        self.paused
+            .push((message.topic().to_owned(), message.partition(), due));
+        Ok(())
+    }
+
+    /// Resume all partitions whose record is due now
+    pub fn resume_due<C: Consumer<CustomContext>>(&mut self, consumer: &C) -> KafkaResult<()> {
+        let now = now_millis();
+        let mut partitions = TopicPartitionList::new();
+        self.paused.retain(|(topic, partition, due)| {
+            if *due <= now {
+                partitions.add_partition(topic, *partition);
+                false
+            } else {
+                true
+            }
+        });
+        if partitions.count() > 0 {
+            consumer.resume(&partitions)?;
+        }
+        Ok(())
+    }
+
+    /// How long to wait for messages before checking for due partitions again
+    pub fn poll_timeout(&self, max: Duration) -> Duration {
+        let now = now_millis();
+        self.paused
+            .iter()
+            .map(|(_, _, due)| Duration::from_millis((due - now).max(0) as u64))
+            .min()
+            .map_or(max, |next| next.min(max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tier() {
+        assert_eq!(
+            "30s".parse::<Tier>(),
+            Ok(Tier {
+                label: "30s".to_owned(),
+                delay: Duration::from_secs(30)
+            })
+        );
+        assert_eq!(
+            "5m".parse::<Tier>().unwrap().delay,
+            Duration::from_secs(300)
+        );
+        assert_eq!(
+            "250ms".parse::<Tier>().unwrap().delay,
+            Duration::from_millis(250)
+        );
+        assert!("30".parse::<Tier>().is_err());
+        assert!("30d".parse::<Tier>().is_err());
+        assert!("s".parse::<Tier>().is_err());
+    }
+
+    #[test]
+    fn test_tiers() {
+        let config = RetryConfig {
+            max_attempts: 5,
+            ..RetryConfig::default()
+        };
+        assert_eq!(config.tier(1).unwrap().label, "1s");
+        assert_eq!(config.tier(3).unwrap().label, "5m");
+        assert_eq!(config.tier(5).unwrap().label, "5m");
+        assert_eq!(
+            config.topics("events"),
+            vec!["events.retry.1s", "events.retry.30s", "events.retry.5m"]
+        );
+        assert_eq!(config.failed_topic("events"), "events.retry.failed");
+
+        let config = RetryConfig {
+            tiers: Vec::new(),
+            ..RetryConfig::default()
+        };
+        assert_eq!(config.tier(1), None);
+        assert!(config.topics("events").is_empty());
+    }
+
+    #[test]
+    fn test_domain() {
+        assert_eq!(domain("events"), "events");
+        assert_eq!(domain("events.retry.30s"), "events");
+    }
+}
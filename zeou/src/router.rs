@@ -0,0 +1,862 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+use futures::future::LocalBoxFuture;
+use rdkafka::consumer::StreamConsumer;
+use rdkafka::error::KafkaError;
+use rdkafka::message::{BorrowedMessage, Message};
+use rdkafka::producer::FutureProducer;
+
+use lib::async_std::AsyncStdRuntime;
+use lib::codec::{CodecError, Codecs};
+use lib::context::CustomContext;
+use lib::error::ErrorKind;
+use lib::headers::MessageHeaders;
+use lib::metrics::{self, metrics};
+use lib::state::StateError;
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::commands::{Command, Decoded};
+use crate::dlq;
+use crate::retry::{self, RetryConfig};
+
+/// Everything a handler needs to process a single message
+pub struct HandlerContext<'a> {
+    pub message: &'a BorrowedMessage<'a>,
+    /// the domain of the message, which is not its topic for retried messages
+    pub domain: &'a str,
+    /// identifies the worker instance, e.g. in dead-lettered records
+    pub worker_id: &'a str,
+    pub consumer: &'a StreamConsumer<CustomContext, AsyncStdRuntime>,
+    pub producer: &'a FutureProducer<CustomContext, AsyncStdRuntime>,
+    /// encodes produced payloads
+    pub codecs: &'a Codecs,
+}
+
+impl HandlerContext<'_> {
+    /// The standard headers of the handled message
+    pub fn headers(&self) -> MessageHeaders {
+        MessageHeaders::read(self.message)
+    }
+
+    /// The payload of a record for `topic` encoded by the codec of the topic
+    pub async fn encode<T: Serialize>(
+        &self,
+        topic: &str,
+        value: &T,
+    ) -> Result<Vec<u8>, HandlerError> {
+        let value =
+            serde_json::to_value(value).map_err(|error| HandlerError::Other(error.to_string()))?;
+        self.codecs.prefetch_encode(topic).await?;
+        Ok(self.codecs.encode(topic, &value)?)
+    }
+}
+
+#[derive(Debug)]
+pub enum HandlerError {
+    Kafka(KafkaError),
+    State(StateError),
+    Codec(CodecError),
+    Other(String),
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::Kafka(error) => write!(f, "kafka error: {}", error),
+            HandlerError::State(error) => write!(f, "{}", error),
+            HandlerError::Codec(error) => write!(f, "{}", error),
+            HandlerError::Other(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+impl ErrorKind for HandlerError {
+    fn kind(&self) -> &'static str {
+        match self {
+            HandlerError::Kafka(_) => "handler-kafka",
+            HandlerError::State(_) => "handler-state",
+            HandlerError::Codec(error) => error.kind(),
+            HandlerError::Other(_) => "handler",
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            HandlerError::Codec(error) => error.is_transient(),
+            HandlerError::Other(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl From<KafkaError> for HandlerError {
+    fn from(error: KafkaError) -> Self {
+        HandlerError::Kafka(error)
+    }
+}
+
+impl From<CodecError> for HandlerError {
+    fn from(error: CodecError) -> Self {
+        HandlerError::Codec(error)
+    }
+}
+
+impl From<StateError> for HandlerError {
+    fn from(error: StateError) -> Self {
+        HandlerError::State(error)
+    }
+}
+
+/// Handles one (domain, command) combination registered with a [`Router`]
+pub trait CommandHandler {
+    fn handle<'a>(
+        &'a self,
+        ctx: &'a HandlerContext<'a>,
+        command: &'a Command,
+    ) -> LocalBoxFuture<'a, Result<(), HandlerError>>;
+
+    /// called after the transaction of the handled messages committed in exactly-once mode
+    fn on_commit(&self) -> Result<(), HandlerError> {
+        Ok(())
+    }
+
+    /// called after the transaction of the handled messages was aborted in exactly-once mode
+    fn on_abort(&self) {}
+}
+
+/// What to do with messages that cannot be handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// log and move on
+    Skip,
+    /// forward the message to the dead-letter topic `<domain>.dlq`
+    DeadLetter,
+    /// republish transient failures to the retry topics `<domain>.retry.<delay>` and,
+    /// once the retries are exhausted, to `<domain>.retry.failed`. Dead-letter everything else.
+    Retry,
+    /// stop processing
+    Fail,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(ErrorPolicy::Skip),
+            "dead-letter" => Ok(ErrorPolicy::DeadLetter),
+            "retry" => Ok(ErrorPolicy::Retry),
+            "fail" => Ok(ErrorPolicy::Fail),
+            _ => Err(format!("unknown policy: {}", s)),
+        }
+    }
+}
+
+/// How a message was dispatched by the [`Router`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatched {
+    Handled,
+    Skipped,
+    Retried,
+    DeadLettered,
+    /// moved to the failed topic of the retries
+    RetriesExhausted,
+}
+
+impl Dispatched {
+    /// the outcome label of the processed messages metric
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Dispatched::Handled => "handled",
+            Dispatched::Skipped => "skipped",
+            Dispatched::Retried => "retried",
+            Dispatched::DeadLettered => "dead_lettered",
+            Dispatched::RetriesExhausted => "retries_exhausted",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RouterError {
+    Unhandled { domain: String, command: String },
+    Failed { kind: &'static str, error: String },
+    Retry(KafkaError),
+    DeadLetter(KafkaError),
+}
+
+impl fmt::Display for RouterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouterError::Unhandled { domain, command } => {
+                write!(f, "no handler for {} in domain {}", command, domain)
+            }
+            RouterError::Failed { kind, error } => write!(f, "{}: {}", kind, error),
+            RouterError::Retry(error) => write!(f, "unable to schedule retry: {}", error),
+            RouterError::DeadLetter(error) => write!(f, "unable to dead-letter message: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for RouterError {}
+
+/// Dispatches decoded commands to the handler registered for (domain, command)
+pub struct Router {
+    handlers: HashMap<(String, String), Box<dyn CommandHandler>>,
+    /// applied to commands without a handler
+    unhandled: ErrorPolicy,
+    /// applied to undecodable messages and failing handlers
+    on_error: ErrorPolicy,
+    retry: RetryConfig,
+}
+
+impl Router {
+    pub fn new(unhandled: ErrorPolicy, on_error: ErrorPolicy) -> Self {
+        Router {
+            handlers: HashMap::new(),
+            unhandled,
+            on_error,
+            retry: RetryConfig::default(),
+        }
+    }
+
+    /// retry tiers used by [`ErrorPolicy::Retry`]
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn register<H>(&mut self, domain: &str, command: &str, handler: H) -> &mut Self
+    where
+        H: CommandHandler + 'static,
+    {
+        let key = (domain.to_owned(), command.to_owned());
+        if self.handlers.insert(key, Box::new(handler)).is_some() {
+            warn!("Replaced handler for {} in domain {}", command, domain);
+        }
+        self
+    }
+
+    pub fn is_registered(&self, domain: &str, command: &str) -> bool {
+        self.handlers
+            .contains_key(&(domain.to_owned(), command.to_owned()))
+    }
+
+    /// Notify all handlers that the current transaction committed, returning the
+    /// first error after every handler was notified
+    pub fn commit_handlers(&self) -> Result<(), HandlerError> {
+        self.handlers
+            .values()
+            .map(|handler| handler.on_commit())
+            .fold(Ok(()), Result::and)
+    }
+
+    /// Notify all handlers that the current transaction was aborted
+    pub fn abort_handlers(&self) {
+        self.handlers
+            .values()
+            .for_each(|handler| handler.on_abort());
+    }
+
+    pub async fn dispatch(
+        &self,
+        ctx: &HandlerContext<'_>,
+        decoded: &Decoded,
+    ) -> Result<Dispatched, RouterError> {
+        let command = match decoded {
+            Decoded::Command(command) => command.name(),
+            Decoded::Unknown(name) => name,
+        };
+        // records produced by the handler are caused by this message, logs carry its
+        // command and correlation id
+        let headers = ctx.headers().with_command(command);
+        let dispatched = headers.scope(self.route(ctx, decoded)).await;
+        record(ctx.domain, command, &dispatched);
+        dispatched
+    }
+
+    async fn route(
+        &self,
+        ctx: &HandlerContext<'_>,
+        decoded: &Decoded,
+    ) -> Result<Dispatched, RouterError> {
+        let domain = ctx.domain;
+        let command = match decoded {
+            Decoded::Command(command) => command,
+            Decoded::Unknown(name) => return self.unhandled(ctx, name).await,
+        };
+
+        match self
+            .handlers
+            .get(&(domain.to_owned(), command.name().to_owned()))
+        {
+            Some(handler) => {
+                let started = Instant::now();
+                let handled = handler.handle(ctx, command).await;
+                metrics::observe_handler(domain, command.name(), started.elapsed());
+                match handled {
+                    Ok(()) => Ok(Dispatched::Handled),
+                    Err(error) => {
+                        let failed = || RouterError::Failed {
+                            kind: error.kind(),
+                            error: error.to_string(),
+                        };
+                        self.apply_policy(
+                            ctx,
+                            self.on_error,
+                            error.is_transient(),
+                            error.kind(),
+                            &error.to_string(),
+                            failed,
+                        )
+                        .await
+                    }
+                }
+            }
+            None => self.unhandled(ctx, command.name()).await,
+        }
+    }
+
+    /// Apply the error policy to a message which could not be decoded.
+    /// Such messages are only retried if the error is `transient`.
+    pub async fn reject(
+        &self,
+        ctx: &HandlerContext<'_>,
+        kind: &'static str,
+        transient: bool,
+        error: &str,
+    ) -> Result<Dispatched, RouterError> {
+        let failed = || RouterError::Failed {
+            kind,
+            error: error.to_owned(),
+        };
+        let dispatched = self
+            .apply_policy(ctx, self.on_error, transient, kind, error, failed)
+            .await;
+        record(ctx.domain, "-", &dispatched);
+        dispatched
+    }
+
+    async fn unhandled(
+        &self,
+        ctx: &HandlerContext<'_>,
+        command: &str,
+    ) -> Result<Dispatched, RouterError> {
+        let domain = ctx.domain;
+        let error = format!("no handler for {} in domain {}", command, domain);
+        let unhandled = || RouterError::Unhandled {
+            domain: domain.to_owned(),
+            command: command.to_owned(),
+        };
+        self.apply_policy(
+            ctx,
+            self.unhandled,
+            false,
+            "unhandled-command",
+            &error,
+            unhandled,
+        )
+        .await
+    }
+
+    async fn apply_policy<F>(
+        &self,
+        ctx: &HandlerContext<'_>,
+        policy: ErrorPolicy,
+        transient: bool,
+        kind: &str,
+        error: &str,
+        fail: F,
+    ) -> Result<Dispatched, RouterError>
+    where
+        F: FnOnce() -> RouterError,
+    {
+        metrics()
+            .failed
+            .with_label_values(&[ctx.domain, kind])
+            .inc();
+        match policy {
+            ErrorPolicy::Skip => {
+                warn!(
+                    "Skipping message {} of {}: {}",
+                    ctx.message.offset(),
+                    ctx.message.topic(),
+                    error
+                );
+                Ok(Dispatched::Skipped)
+            }
+            ErrorPolicy::Retry if transient => {
+                let scheduled = retry::schedule(
+                    ctx.producer,
+                    &self.retry,
+                    ctx.domain,
+                    ctx.message,
+                    kind,
+                    error,
+                )
+                .await
+                .map_err(RouterError::Retry)?;
+                match scheduled {
+                    Some(topic) => {
+                        info!(
+                            "Retrying message {} of {} via {}: {}",
+                            ctx.message.offset(),
+                            ctx.message.topic(),
+                            topic,
+                            error
+                        );
+                        Ok(Dispatched::Retried)
+                    }
+                    None => {
+                        let error = format!(
+                            "giving up after {} retries: {}",
+                            self.retry.max_attempts, error
+                        );
+                        self.retries_exhausted(ctx, &error).await
+                    }
+                }
+            }
+            ErrorPolicy::DeadLetter | ErrorPolicy::Retry => {
+                self.dead_letter(ctx, kind, error).await
+            }
+            ErrorPolicy::Fail => Err(fail()),
+        }
+    }
+
+    async fn dead_letter(
+        &self,
+        ctx: &HandlerContext<'_>,
+        kind: &str,
+        error: &str,
+    ) -> Result<Dispatched, RouterError> {
+        let topic = dlq::dead_letter(
+            ctx.producer,
+            ctx.domain,
+            ctx.message,
+            kind,
+            error,
+            ctx.worker_id,
+        )
+        .await
+        .map_err(RouterError::DeadLetter)?;
+        info!(
+            "Dead-lettered message {} of {} to {}: {}",
+            ctx.message.offset(),
+            ctx.message.topic(),
+            topic,
+            error
+        );
+        Ok(Dispatched::DeadLettered)
+    }
+
+    async fn retries_exhausted(
+        &self,
+        ctx: &HandlerContext<'_>,
+        error: &str,
+    ) -> Result<Dispatched, RouterError> {
+        let topic = self.retry.failed_topic(ctx.domain);
+        dlq::forward(
+            ctx.producer,
+            &topic,
+            ctx.domain,
+            ctx.message,
+            "retries-exhausted",
+            error,
+            ctx.worker_id,
+        )
+        .await
+        .map_err(RouterError::DeadLetter)?;
+        warn!(
+            "Moved message {} of {} to {}: {}",
+            ctx.message.offset(),
+            ctx.message.topic(),
+            topic,
+            error
+        );
+        Ok(Dispatched::RetriesExhausted)
+    }
+}
+
+/// Count a dispatched message by its outcome, messages stopping the worker aren't processed
+fn record(domain: &str, command: &str, dispatched: &Result<Dispatched, RouterError>) {
+    if let Ok(dispatched) = dispatched {
+        metrics()
+            .processed
+            .with_label_values(&[domain, command, dispatched.as_str()])
+            .inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::executor::block_on;
+    use rdkafka::consumer::Consumer;
+    use rdkafka::message::OwnedHeaders;
+    use rdkafka::producer::FutureRecord;
+    use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+    use rdkafka::types::RDKafkaRespErr;
+
+    use lib::async_std::{create_consumer, send};
+    use lib::client::{create_mock_cluster, push_produce_errors};
+    use lib::headers::Origin;
+    use lib::state::{ChangelogStore, SledStore};
+
+    use super::*;
+    use crate::commands::decode;
+    use crate::events::{self, Amount, CreateEventHandler};
+
+    /// Fails with a transient or a permanent error
+    struct Failing {
+        transient: bool,
+    }
+
+    impl CommandHandler for Failing {
+        fn handle<'a>(
+            &'a self,
+            _ctx: &'a HandlerContext<'a>,
+            _command: &'a Command,
+        ) -> LocalBoxFuture<'a, Result<(), HandlerError>> {
+            Box::pin(async move {
+                if self.transient {
+                    Err(KafkaError::Canceled.into())
+                } else {
+                    Err(HandlerError::Other("invalid amount".to_owned()))
+                }
+            })
+        }
+    }
+
+    /// Dispatch a record of `events` with the given payload and headers on an
+    /// in-process mock cluster of librdkafka
+    fn dispatch(
+        router: &Router,
+        payload: &str,
+        headers: OwnedHeaders,
+    ) -> Result<Dispatched, RouterError> {
+        let (producer, kafka) = create_mock_cluster::<AsyncStdRuntime>();
+        let consumer = create_consumer(&kafka, "router-tests");
+        let codecs = Codecs::default();
+
+        block_on(async {
+            let record = FutureRecord::<[u8], str>::to("events")
+                .partition(0)
+                .key(b"key")
+                .payload(payload)
+                .headers(headers);
+            send(&producer, record)
+                .await
+                .map_err(|(error, _)| error)
+                .unwrap();
+
+            let mut assignment = TopicPartitionList::new();
+            assignment
+                .add_partition_offset("events", 0, Offset::Beginning)
+                .unwrap();
+            consumer.assign(&assignment).unwrap();
+            let message = consumer.recv().await.unwrap();
+
+            let ctx = HandlerContext {
+                message: &message,
+                domain: "events",
+                worker_id: "router-tests",
+                consumer: &consumer,
+                producer: &producer,
+                codecs: &codecs,
+            };
+            let decoded = decode(message.payload()).unwrap();
+            router.dispatch(&ctx, &decoded).await
+        })
+    }
+
+    fn command(router: &Router) -> Result<Dispatched, RouterError> {
+        let payload = r#"{"command": "createEvent", "kind": "add", "amount": 5}"#;
+        dispatch(router, payload, OwnedHeaders::new())
+    }
+
+    #[test]
+    fn test_policy_from_str() {
+        assert_eq!("skip".parse(), Ok(ErrorPolicy::Skip));
+        assert_eq!("dead-letter".parse(), Ok(ErrorPolicy::DeadLetter));
+        assert_eq!("retry".parse(), Ok(ErrorPolicy::Retry));
+        assert_eq!("fail".parse(), Ok(ErrorPolicy::Fail));
+        assert!("ignore".parse::<ErrorPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_register() {
+        struct Noop;
+
+        impl CommandHandler for Noop {
+            fn handle<'a>(
+                &'a self,
+                _ctx: &'a HandlerContext<'a>,
+                _command: &'a Command,
+            ) -> LocalBoxFuture<'a, Result<(), HandlerError>> {
+                Box::pin(async { Ok(()) })
+            }
+        }
+
+        let mut router = Router::new(ErrorPolicy::Skip, ErrorPolicy::DeadLetter);
+        router.register("events", "createEvent", Noop);
+        assert!(router.is_registered("events", "createEvent"));
+        assert!(!router.is_registered("users", "createEvent"));
+    }
+
+    #[test]
+    fn test_unhandled() {
+        let router = Router::new(ErrorPolicy::Skip, ErrorPolicy::Fail);
+        assert!(matches!(command(&router), Ok(Dispatched::Skipped)));
+
+        let router = Router::new(ErrorPolicy::DeadLetter, ErrorPolicy::Fail);
+        assert!(matches!(command(&router), Ok(Dispatched::DeadLettered)));
+
+        // unhandled commands are never retried
+        let router = Router::new(ErrorPolicy::Retry, ErrorPolicy::Fail);
+        assert!(matches!(command(&router), Ok(Dispatched::DeadLettered)));
+
+        let router = Router::new(ErrorPolicy::Fail, ErrorPolicy::Skip);
+        assert!(matches!(
+            command(&router),
+            Err(RouterError::Unhandled { command, .. }) if command == "createEvent"
+        ));
+    }
+
+    #[test]
+    fn test_handler_error() {
+        let cases = [
+            (ErrorPolicy::Skip, true, "skipped"),
+            (ErrorPolicy::Skip, false, "skipped"),
+            (ErrorPolicy::DeadLetter, true, "dead_lettered"),
+            (ErrorPolicy::DeadLetter, false, "dead_lettered"),
+            (ErrorPolicy::Retry, true, "retried"),
+            (ErrorPolicy::Retry, false, "dead_lettered"),
+        ];
+        for (policy, transient, expected) in cases {
+            let mut router = Router::new(ErrorPolicy::Fail, policy);
+            router.register("events", "createEvent", Failing { transient });
+            let dispatched = command(&router).unwrap();
+            assert_eq!(
+                dispatched.as_str(),
+                expected,
+                "{:?} transient={}",
+                policy,
+                transient
+            );
+        }
+
+        for transient in [true, false] {
+            let mut router = Router::new(ErrorPolicy::Skip, ErrorPolicy::Fail);
+            router.register("events", "createEvent", Failing { transient });
+            let kind = if transient {
+                "handler-kafka"
+            } else {
+                "handler"
+            };
+            assert!(matches!(
+                command(&router),
+                Err(RouterError::Failed { kind: failed, .. }) if failed == kind
+            ));
+        }
+    }
+
+    #[test]
+    fn test_retries_exhausted() {
+        let mut router = Router::new(ErrorPolicy::Fail, ErrorPolicy::Retry);
+        router.register("events", "createEvent", Failing { transient: true });
+        let payload = r#"{"command": "createEvent", "kind": "add", "amount": 5}"#;
+        let headers = OwnedHeaders::new().add(retry::HEADER_ATTEMPT, "3");
+        assert!(matches!(
+            dispatch(&router, payload, headers),
+            Ok(Dispatched::RetriesExhausted)
+        ));
+
+        // without tiers there are no retries
+        let mut router =
+            Router::new(ErrorPolicy::Fail, ErrorPolicy::Retry).with_retry(RetryConfig {
+                tiers: Vec::new(),
+                ..RetryConfig::default()
+            });
+        router.register("events", "createEvent", Failing { transient: true });
+        assert!(matches!(command(&router), Ok(Dispatched::RetriesExhausted)));
+    }
+
+    /// Consume `topic` from the beginning of all its partitions
+    fn assign_all(consumer: &StreamConsumer<CustomContext, AsyncStdRuntime>, topic: &str) -> i64 {
+        let metadata = consumer
+            .fetch_metadata(Some(topic), Duration::from_secs(5))
+            .unwrap();
+        let mut assignment = TopicPartitionList::new();
+        let mut records = 0;
+        for partition in metadata.topics()[0].partitions() {
+            assignment
+                .add_partition_offset(topic, partition.id(), Offset::Beginning)
+                .unwrap();
+            let (low, high) = consumer
+                .fetch_watermarks(topic, partition.id(), Duration::from_secs(5))
+                .unwrap();
+            records += high - low;
+        }
+        consumer.assign(&assignment).unwrap();
+        records
+    }
+
+    #[test]
+    fn test_retry_after_failed_publish() {
+        let (producer, kafka) = create_mock_cluster::<AsyncStdRuntime>();
+        let consumer = create_consumer(&kafka, "router-tests");
+        let codecs = Codecs::default();
+        let store = ChangelogStore::new(
+            SledStore::temporary().unwrap(),
+            &kafka,
+            events::CHANGELOG_TOPIC,
+        );
+        let mut router = Router::new(ErrorPolicy::Fail, ErrorPolicy::Retry);
+        router.register("events", "createEvent", CreateEventHandler::new(store));
+        let retry_topic = retry::topic("events", RetryConfig::default().tier(1).unwrap());
+
+        block_on(async {
+            let payload = r#"{"command": "createEvent", "kind": "add", "amount": 5}"#;
+            let record = FutureRecord::<[u8], str>::to("events")
+                .partition(0)
+                .key(b"key")
+                .payload(payload);
+            send(&producer, record)
+                .await
+                .map_err(|(error, _)| error)
+                .unwrap();
+            // the changelog write passes, publishing the aggregate fails
+            push_produce_errors(
+                &producer,
+                &[
+                    RDKafkaRespErr::RD_KAFKA_RESP_ERR_NO_ERROR,
+                    RDKafkaRespErr::RD_KAFKA_RESP_ERR_MSG_SIZE_TOO_LARGE,
+                ],
+            );
+
+            for topic in ["events", retry_topic.as_str()] {
+                let mut assignment = TopicPartitionList::new();
+                assignment
+                    .add_partition_offset(topic, 0, Offset::Beginning)
+                    .unwrap();
+                consumer.assign(&assignment).unwrap();
+                let message = consumer.recv().await.unwrap();
+
+                let ctx = HandlerContext {
+                    message: &message,
+                    domain: "events",
+                    worker_id: "router-tests",
+                    consumer: &consumer,
+                    producer: &producer,
+                    codecs: &codecs,
+                };
+                let decoded = decode(message.payload()).unwrap();
+                let expected = if topic == "events" {
+                    Dispatched::Retried
+                } else {
+                    Dispatched::Handled
+                };
+                assert_eq!(router.dispatch(&ctx, &decoded).await.unwrap(), expected);
+            }
+
+            // the retry publishes the aggregate without applying the event again
+            assert_eq!(assign_all(&consumer, events::OUTPUT_TOPIC), 1);
+            let published = consumer.recv().await.unwrap();
+            let amount: Amount = serde_json::from_slice(published.payload().unwrap()).unwrap();
+            assert_eq!(
+                amount,
+                Amount {
+                    amount: 5,
+                    version: 1
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn test_retry_after_later_offset() {
+        let (producer, kafka) = create_mock_cluster::<AsyncStdRuntime>();
+        let consumer = create_consumer(&kafka, "router-tests");
+        let codecs = Codecs::default();
+        let store = ChangelogStore::new(
+            SledStore::temporary().unwrap(),
+            &kafka,
+            events::CHANGELOG_TOPIC,
+        );
+        let mut router = Router::new(ErrorPolicy::Fail, ErrorPolicy::Retry);
+        router.register("events", "createEvent", CreateEventHandler::new(store));
+        let retry_topic = retry::topic("events", RetryConfig::default().tier(1).unwrap());
+
+        block_on(async {
+            let failed = r#"{"command": "createEvent", "kind": "add", "amount": 5}"#;
+            let later = r#"{"command": "createEvent", "kind": "add", "amount": 7}"#;
+            for payload in [failed, later] {
+                let record = FutureRecord::<[u8], str>::to("events")
+                    .partition(0)
+                    .key(b"key")
+                    .payload(payload);
+                send(&producer, record)
+                    .await
+                    .map_err(|(error, _)| error)
+                    .unwrap();
+            }
+            // offset 0 failed before it reached the store and waits on the retry topic
+            let origin = Origin {
+                topic: "events".to_owned(),
+                partition: 0,
+                offset: 0,
+            };
+            let record = FutureRecord::<[u8], str>::to(&retry_topic)
+                .partition(0)
+                .key(b"key")
+                .payload(failed)
+                .headers(origin.fill(OwnedHeaders::new()));
+            send(&producer, record)
+                .await
+                .map_err(|(error, _)| error)
+                .unwrap();
+
+            for (topic, offset) in [("events", 1), (retry_topic.as_str(), 0)] {
+                let mut assignment = TopicPartitionList::new();
+                assignment
+                    .add_partition_offset(topic, 0, Offset::Offset(offset))
+                    .unwrap();
+                consumer.assign(&assignment).unwrap();
+                let message = consumer.recv().await.unwrap();
+
+                let ctx = HandlerContext {
+                    message: &message,
+                    domain: "events",
+                    worker_id: "router-tests",
+                    consumer: &consumer,
+                    producer: &producer,
+                    codecs: &codecs,
+                };
+                let decoded = decode(message.payload()).unwrap();
+                assert_eq!(
+                    router.dispatch(&ctx, &decoded).await.unwrap(),
+                    Dispatched::Handled
+                );
+            }
+
+            // the retried event is applied after the later one
+            assert_eq!(assign_all(&consumer, events::OUTPUT_TOPIC), 2);
+            consumer.recv().await.unwrap();
+            let published = consumer.recv().await.unwrap();
+            let amount: Amount = serde_json::from_slice(published.payload().unwrap()).unwrap();
+            assert_eq!(
+                amount,
+                Amount {
+                    amount: 12,
+                    version: 2
+                }
+            );
+        });
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use jsonschema::Validator;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+/// The JSON Schema of every command, keyed by command name
+pub const SCHEMAS: [(&str, &str); 13] = [
+    (
+        "createArticle",
+        include_str!("../schemas/createArticle.json"),
+    ),
+    (
+        "updateArticle",
+        include_str!("../schemas/updateArticle.json"),
+    ),
+    (
+        "deleteArticle",
+        include_str!("../schemas/deleteArticle.json"),
+    ),
+    (
+        "createBackpack",
+        include_str!("../schemas/createBackpack.json"),
+    ),
+    (
+        "addArticleToBackpack",
+        include_str!("../schemas/addArticleToBackpack.json"),
+    ),
+    (
+        "removeArticleFromBackpack",
+        include_str!("../schemas/removeArticleFromBackpack.json"),
+    ),
+    ("createCircle", include_str!("../schemas/createCircle.json")),
+    ("joinCircle", include_str!("../schemas/joinCircle.json")),
+    ("leaveCircle", include_str!("../schemas/leaveCircle.json")),
+    ("createEvent", include_str!("../schemas/createEvent.json")),
+    ("createUser", include_str!("../schemas/createUser.json")),
+    ("updateUser", include_str!("../schemas/updateUser.json")),
+    ("deleteUser", include_str!("../schemas/deleteUser.json")),
+];
+
+static VALIDATORS: Lazy<HashMap<&'static str, Validator>> = Lazy::new(|| {
+    SCHEMAS
+        .iter()
+        .map(|(command, schema)| {
+            let schema = serde_json::from_str::<Value>(schema)
+                .unwrap_or_else(|e| panic!("schema of {} is not JSON: {}", command, e));
+            let validator = jsonschema::validator_for(&schema)
+                .unwrap_or_else(|e| panic!("schema of {} is invalid: {}", command, e));
+            (*command, validator)
+        })
+        .collect()
+});
+
+/// A part of a payload which doesn't match the schema of its command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// JSON pointer to the offending value, empty for the payload itself
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// The JSON Schema of `command`, `None` for unknown commands
+pub fn schema(command: &str) -> Option<&'static str> {
+    SCHEMAS
+        .iter()
+        .find(|(name, _)| *name == command)
+        .map(|(_, schema)| *schema)
+}
+
+/// Validate `payload` against the schema of `command`, returning all violations.
+/// Payloads of commands without a schema are valid.
+pub fn validate(command: &str, payload: &Value) -> Result<(), Vec<Violation>> {
+    let validator = match VALIDATORS.get(command) {
+        Some(validator) => validator,
+        None => return Ok(()),
+    };
+    let violations = validator
+        .iter_errors(payload)
+        .map(|error| Violation {
+            path: error.instance_path.to_string(),
+            message: error.to_string(),
+        })
+        .collect::<Vec<_>>();
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::commands::Command;
+    use crate::loadgen;
+
+    #[test]
+    fn test_schemas() {
+        for name in Command::NAMES {
+            assert!(schema(name).is_some(), "{} has no schema", name);
+        }
+        assert_eq!(VALIDATORS.len(), Command::NAMES.len());
+
+        // generated commands are valid
+        let mut rng = StdRng::seed_from_u64(7);
+        for domain in ["articles", "backpacks", "circles", "events", "users"] {
+            for _ in 0..20 {
+                let command = loadgen::command(domain, "key-1", &mut rng).unwrap();
+                let payload = serde_json::from_str(&loadgen::payload(&command, 100)).unwrap();
+                assert_eq!(validate(command.name(), &payload), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_violations() {
+        let payload = serde_json::json!({"command": "createUser", "id": "", "email": "nobody"});
+        let violations = validate("createUser", &payload).unwrap_err();
+        let mut report = violations
+            .iter()
+            .map(|violation| violation.to_string())
+            .collect::<Vec<_>>();
+        report.sort();
+        assert_eq!(
+            report,
+            vec![
+                r#""name" is a required property"#,
+                r#"/email: "nobody" does not match "^[^@ ]+@[^@ ]+$""#,
+                r#"/id: "" is shorter than 1 character"#,
+            ]
+        );
+
+        assert_eq!(validate("launchRocket", &payload), Ok(()));
+    }
+}